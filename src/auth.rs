@@ -0,0 +1,184 @@
+use imap_next::imap_types::{
+    auth::{AuthMechanism, AuthenticateData},
+    command::CommandBody,
+    core::AString,
+    secret::Secret,
+};
+
+/// Credentials presented by a client.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    /// Authorization identity (SASL PLAIN only).
+    pub authzid: Option<String>,
+    /// Authentication identity.
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+/// How credentials are presented (by a client or to a server).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Method {
+    /// LOGIN command.
+    Login,
+    /// AUTHENTICATE PLAIN, with or without initial response (SASL-IR).
+    AuthenticatePlain { initial_response: bool },
+    /// AUTHENTICATE LOGIN (without initial response).
+    AuthenticateLogin,
+    /// AUTHENTICATE XOAUTH2 with an access token as password (server only).
+    AuthenticateXOAuth2,
+    /// AUTHENTICATE OAUTHBEARER with an access token as password (server only).
+    AuthenticateOAuthBearer,
+    /// AUTHENTICATE SCRAM-* (client only, terminated by the proxy).
+    AuthenticateScram,
+}
+
+impl Method {
+    /// Command that presents the credentials to the server, `None` when they can't be encoded.
+    pub fn command_body(self, credentials: &Credentials) -> Option<CommandBody<'static>> {
+        let body = match self {
+            Self::Login => CommandBody::login(
+                credentials.username.clone(),
+                credentials.password.declassify().clone(),
+            )
+            .ok()?,
+            Self::AuthenticatePlain {
+                initial_response: true,
+            } => CommandBody::authenticate_with_ir(AuthMechanism::Plain, credentials.to_plain()),
+            Self::AuthenticatePlain {
+                initial_response: false,
+            } => CommandBody::authenticate(AuthMechanism::Plain),
+            Self::AuthenticateLogin => CommandBody::authenticate(AuthMechanism::Login),
+            Self::AuthenticateXOAuth2 => CommandBody::authenticate(AuthMechanism::XOAuth2),
+            Self::AuthenticateOAuthBearer => CommandBody::authenticate(AuthMechanism::OAuthBearer),
+            Self::AuthenticateScram => return None,
+        };
+
+        Some(body)
+    }
+
+    /// Answer to the server's continuation request (counted from 0).
+    ///
+    /// The exchange is cancelled when the server asks more often than the mechanism requires.
+    pub fn response(self, credentials: &Credentials, index: usize) -> AuthenticateData<'static> {
+        let message = match (self, index) {
+            (
+                Self::AuthenticatePlain {
+                    initial_response: false,
+                },
+                0,
+            ) => credentials.to_plain(),
+            (Self::AuthenticateLogin, 0) => credentials.username.clone().into_bytes(),
+            (Self::AuthenticateLogin, 1) => credentials.password.declassify().clone().into_bytes(),
+            (Self::AuthenticateXOAuth2, 0) => credentials.to_xoauth2(),
+            // XOAUTH2 and OAUTHBEARER send an error, which must be acknowledged to receive the
+            // failure status (see RFC 7628, section 3.2.3).
+            (Self::AuthenticateXOAuth2, 1) => vec![],
+            (Self::AuthenticateOAuthBearer, 0) => credentials.to_oauthbearer(),
+            (Self::AuthenticateOAuthBearer, 1) => b"\x01".to_vec(),
+            _ => return AuthenticateData::Cancel,
+        };
+
+        AuthenticateData::r#continue(message)
+    }
+}
+
+impl Credentials {
+    /// Credentials from a LOGIN command, `None` when they aren't UTF-8.
+    pub fn from_login(username: &AString, password: &AString) -> Option<Self> {
+        let username = String::from_utf8(username.as_ref().to_vec()).ok()?;
+        let password = String::from_utf8(password.as_ref().to_vec()).ok()?;
+
+        Some(Self {
+            authzid: None,
+            username,
+            password: Secret::new(password),
+        })
+    }
+
+    /// Credentials from a SASL PLAIN message, i.e., "[authzid] NUL authcid NUL passwd".
+    ///
+    /// See <https://datatracker.ietf.org/doc/html/rfc4616#section-2>.
+    pub fn from_plain(message: &[u8]) -> Option<Self> {
+        let mut fields = message.split(|byte| *byte == 0);
+
+        let (Some(authzid), Some(authcid), Some(passwd), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return None;
+        };
+
+        let authzid = String::from_utf8(authzid.to_vec()).ok()?;
+        let username = String::from_utf8(authcid.to_vec()).ok()?;
+        let password = String::from_utf8(passwd.to_vec()).ok()?;
+
+        if username.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            authzid: (!authzid.is_empty()).then_some(authzid),
+            username,
+            password: Secret::new(password),
+        })
+    }
+
+    /// Encode as SASL PLAIN message.
+    pub fn to_plain(&self) -> Vec<u8> {
+        let authzid = self.authzid.as_deref().unwrap_or_default();
+
+        [
+            authzid.as_bytes(),
+            self.username.as_bytes(),
+            self.password.declassify().as_bytes(),
+        ]
+        .join(&0)
+    }
+
+    /// Encode as SASL XOAUTH2 message (with the password as access token).
+    ///
+    /// See <https://developers.google.com/gmail/imap/xoauth2-protocol>.
+    pub fn to_xoauth2(&self) -> Vec<u8> {
+        format!(
+            "user={}\x01auth=Bearer {}\x01\x01",
+            self.username,
+            self.password.declassify()
+        )
+        .into_bytes()
+    }
+
+    /// Encode as SASL OAUTHBEARER message (with the password as access token).
+    ///
+    /// See <https://datatracker.ietf.org/doc/html/rfc7628#section-3.1>.
+    pub fn to_oauthbearer(&self) -> Vec<u8> {
+        let username = self.username.replace('=', "=3D").replace(',', "=2C");
+
+        format!(
+            "n,a={username},\x01auth=Bearer {}\x01\x01",
+            self.password.declassify()
+        )
+        .into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oauth2_messages() {
+        let credentials = Credentials {
+            authzid: None,
+            username: "a,b=c@example.org".into(),
+            password: Secret::new("token".into()),
+        };
+
+        assert_eq!(
+            b"user=a,b=c@example.org\x01auth=Bearer token\x01\x01".as_slice(),
+            credentials.to_xoauth2()
+        );
+        assert_eq!(
+            b"n,a=a=2Cb=3Dc@example.org,\x01auth=Bearer token\x01\x01".as_slice(),
+            credentials.to_oauthbearer()
+        );
+    }
+}
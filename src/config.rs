@@ -1,10 +1,13 @@
 use std::{
+    collections::BTreeMap,
     fmt::{Display, Formatter},
     path::Path,
 };
 
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tracing::warn;
 
 const fn default_imap_port() -> u16 {
     143
@@ -14,14 +17,149 @@ const fn default_imaps_port() -> u16 {
     993
 }
 
+const fn default_socks5_port() -> u16 {
+    1080
+}
+
+const fn default_http_proxy_port() -> u16 {
+    3128
+}
+
+const fn default_accept_tls_timeout() -> u64 {
+    30
+}
+
+const fn default_connect_timeout() -> u64 {
+    10
+}
+
+const fn default_connect_tls_timeout() -> u64 {
+    30
+}
+
+const fn default_greeting_timeout() -> u64 {
+    30
+}
+
+const fn default_unauthenticated_timeout() -> u64 {
+    60
+}
+
+const fn default_authenticated_timeout() -> u64 {
+    // See RFC 3501, section 5.4.
+    30 * 60
+}
+
+const fn default_idle_timeout() -> u64 {
+    30 * 60
+}
+
+const fn default_max_failures() -> u32 {
+    3
+}
+
+const fn default_max_login_failures() -> u32 {
+    3
+}
+
+const fn default_cool_down() -> u64 {
+    30
+}
+
+const fn default_failure_window() -> u64 {
+    15 * 60
+}
+
+const fn default_max_failures_per_ip() -> usize {
+    20
+}
+
+const fn default_max_failures_per_user() -> usize {
+    10
+}
+
+const fn default_failure_delay() -> u64 {
+    1
+}
+
+const fn default_max_failure_delay() -> u64 {
+    15
+}
+
+const fn default_drain_timeout() -> u64 {
+    30
+}
+
+const fn default_max_password_length() -> usize {
+    1024
+}
+
+const fn default_token_timeout() -> u64 {
+    30
+}
+
+fn default_shutdown_text() -> String {
+    String::from("proxy: Shutting down")
+}
+
+const fn default_true() -> bool {
+    true
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct Config {
     pub services: Vec<Service>,
+    /// What to do on SIGTERM or Ctrl-C?
+    #[serde(default)]
+    pub shutdown: Shutdown,
 }
 
 impl Config {
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+        let mut config: Self = toml::from_str(&std::fs::read_to_string(path)?)?;
+        config.migrate();
+        Ok(config)
+    }
+
+    /// Move deprecated settings to their replacements.
+    fn migrate(&mut self) {
+        for service in &mut self.services {
+            let connect_timeout = service
+                .upstreams
+                .as_mut()
+                .and_then(|upstreams| upstreams.connect_timeout.take());
+            if let Some(connect_timeout) = connect_timeout {
+                warn!(
+                    service = service.name,
+                    "`upstreams.connect_timeout` is deprecated, use `timeouts.connect`"
+                );
+                service.timeouts.connect = connect_timeout;
+            }
+        }
+    }
+}
+
+/// Graceful shutdown.
+///
+/// The proxy stops accepting clients and sends `BYE` to every client once its commands
+/// completed (or right away when it is in IDLE).
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Shutdown {
+    /// Text of the `BYE` sent to clients.
+    #[serde(default = "default_shutdown_text")]
+    pub text: String,
+    /// Close remaining connections after this many seconds (and exit with an error).
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            text: default_shutdown_text(),
+            drain_timeout: default_drain_timeout(),
+        }
     }
 }
 
@@ -33,6 +171,374 @@ pub struct Service {
     pub bind: Bind,
     /// How to establish server connections?
     pub connect: Connect,
+    /// Routes selected by the server name (SNI) a TLS client requested.
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// What to do with clients that requested an unknown (or no) server name?
+    #[serde(default)]
+    pub sni_fallback: SniFallback,
+    /// Route clients by username (director mode).
+    ///
+    /// The proxy handles the not authenticated state itself and connects to a server only
+    /// after the client sent its credentials. Unknown users are routed to `connect`.
+    pub director: Option<Director>,
+    /// Authenticate clients at the proxy (authentication termination).
+    ///
+    /// The proxy verifies the client's password itself and authenticates with the server
+    /// using mapped credentials, which are never sent to the client.
+    pub auth_termination: Option<AuthTermination>,
+    /// Balance clients between `connect` and more servers.
+    pub upstreams: Option<Upstreams>,
+    /// How long to wait in each phase of a session?
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// What to do with messages that can't be parsed?
+    #[serde(default)]
+    pub malformed: Malformed,
+    /// What to tell clients when the server rejected a command (before its literal was sent)?
+    #[serde(default)]
+    pub command_rejected: CommandRejected,
+    /// Which capabilities to announce to clients?
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    /// Translate between `LOGIN`, `AUTHENTICATE LOGIN`, and `AUTHENTICATE PLAIN` (with or
+    /// without SASL-IR) when the server doesn't accept what the client uses.
+    ///
+    /// Clients are offered all of them (when the server accepts any).
+    #[serde(default)]
+    pub auth_translation: bool,
+    /// Slow down and block clients that fail to authenticate repeatedly.
+    pub brute_force: Option<BruteForce>,
+}
+
+/// Changes to the built-in set of capabilities that are forwarded to clients.
+///
+/// Capabilities are written as announced by servers, e.g., "UIDPLUS" or "AUTH=PLAIN".
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Capabilities {
+    /// Forward these capabilities of the server, too.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Never forward these capabilities of the server (takes precedence).
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Announce these capabilities (emulated by the proxy), even when the server doesn't.
+    #[serde(default)]
+    pub inject: Vec<String>,
+}
+
+/// Certificate and server used for clients that requested one of the server names.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Route {
+    /// Server names, e.g., "imap.customer-a.example" (case-insensitive).
+    pub server_names: Vec<String>,
+    /// Which identity to use?
+    pub identity: Identity,
+    /// How to establish server connections?
+    pub connect: Connect,
+}
+
+/// Routing table of the director mode.
+///
+/// Lookups are done in order: `subjects`, `users_path`, `users`, `domains` (longest suffix
+/// wins).
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Director {
+    /// Servers that clients can be routed to, by name.
+    pub backends: BTreeMap<String, Connect>,
+    /// Backend by subject of the client certificate, e.g., `"CN=webmail" = "backend-1"`.
+    ///
+    /// Requires client certificate authentication (see [`ClientAuth`]).
+    #[serde(default)]
+    pub subjects: BTreeMap<String, String>,
+    /// Backend by username, e.g., `"alice@example.org" = "backend-1"` (case-insensitive).
+    #[serde(default)]
+    pub users: BTreeMap<String, String>,
+    /// Backend by domain of username, e.g., `"example.org" = "backend-2"` (case-insensitive).
+    ///
+    /// Also matches subdomains, e.g., "alice@mail.example.org".
+    #[serde(default)]
+    pub domains: BTreeMap<String, String>,
+    /// Path to a file with one "<username> <backend>" pair per line.
+    ///
+    /// The file is read for every lookup. Empty lines and lines starting with "#" are ignored.
+    pub users_path: Option<String>,
+    /// Close the connection after this many failed authentications.
+    #[serde(default = "default_max_login_failures")]
+    pub max_login_failures: u32,
+}
+
+/// Local credential store and mapping to server credentials (authentication termination).
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthTermination {
+    /// Path to a file with one "<username> <hash>" pair per line.
+    ///
+    /// Hashes are argon2 (PHC string format, e.g., "$argon2id$..."), bcrypt ("$2b$..."), or
+    /// SCRAM verifiers (RFC 5803, e.g., "SCRAM-SHA-256$4096:..."). A user can have multiple
+    /// lines, e.g., one verifier per SCRAM mechanism. Passwords are verified with the first one.
+    /// The file is read for every login. Empty lines and lines starting with "#" are ignored.
+    pub users_path: String,
+    /// Which passwords to accept (before they are verified)?
+    #[serde(default)]
+    pub password_policy: PasswordPolicy,
+    /// Server credentials of users that are not in `users`, e.g., a shared service account.
+    pub upstream: Option<UpstreamCredentials>,
+    /// Server credentials by username, e.g., an app password (case-insensitive).
+    #[serde(default)]
+    pub users: BTreeMap<String, UpstreamCredentials>,
+    /// Where to obtain access tokens for server credentials with a `refresh_token`.
+    pub oauth2: Option<OAuth2>,
+    /// SCRAM mechanisms to offer clients (verified with the SCRAM verifiers in `users_path`).
+    ///
+    /// "-PLUS" mechanisms are only offered to clients using TLS.
+    #[serde(default)]
+    pub scram: Vec<ScramMechanism>,
+}
+
+/// Credentials the proxy authenticates with at the server.
+///
+/// Either `password` or `refresh_token` is required.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamCredentials {
+    pub username: String,
+    pub password: Option<Secret>,
+    /// OAuth 2.0 refresh token, used to obtain access tokens from `oauth2.token_endpoint`.
+    ///
+    /// The proxy then authenticates via `oauth2.mechanism` instead of a password.
+    pub refresh_token: Option<Secret>,
+    /// Send the client's username as authorization identity (via `AUTHENTICATE PLAIN`).
+    ///
+    /// Allows a single account (e.g., a master user) to act on behalf of all users.
+    #[serde(default)]
+    pub impersonate: bool,
+}
+
+/// OAuth 2.0 client of the proxy.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OAuth2 {
+    /// URL of the token endpoint, e.g., "https://oauth2.googleapis.com/token".
+    ///
+    /// Plain "http://" URLs are supported for local endpoints (insecure).
+    pub token_endpoint: String,
+    pub client_id: String,
+    /// Secret of confidential clients.
+    pub client_secret: Option<Secret>,
+    /// Which SASL mechanism to authenticate with at the server?
+    #[serde(default)]
+    pub mechanism: OAuth2Mechanism,
+    /// How to verify the token endpoint (for "https://" URLs)?
+    #[serde(default)]
+    pub tls: ConnectTls,
+    /// Give up on the token endpoint after this many seconds.
+    #[serde(default = "default_token_timeout")]
+    pub timeout: u64,
+}
+
+/// SASL mechanism that carries an OAuth 2.0 access token.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub enum OAuth2Mechanism {
+    /// `AUTHENTICATE XOAUTH2` (Google, Microsoft).
+    #[default]
+    XOAuth2,
+    /// `AUTHENTICATE OAUTHBEARER` (RFC 7628).
+    OAuthBearer,
+}
+
+/// SCRAM mechanism the proxy terminates.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum ScramMechanism {
+    #[serde(rename = "SCRAM-SHA-1")]
+    ScramSha1,
+    #[serde(rename = "SCRAM-SHA-1-PLUS")]
+    ScramSha1Plus,
+    #[serde(rename = "SCRAM-SHA-256")]
+    ScramSha256,
+    #[serde(rename = "SCRAM-SHA-256-PLUS")]
+    ScramSha256Plus,
+}
+
+impl ScramMechanism {
+    pub fn binds_channel(&self) -> bool {
+        matches!(self, Self::ScramSha1Plus | Self::ScramSha256Plus)
+    }
+}
+
+/// Password policy of the proxy (in characters).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PasswordPolicy {
+    #[serde(default)]
+    pub min_length: usize,
+    /// Longer passwords are rejected without being hashed.
+    #[serde(default = "default_max_password_length")]
+    pub max_length: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 0,
+            max_length: default_max_password_length(),
+        }
+    }
+}
+
+/// Servers that clients are balanced between.
+///
+/// Applies to `connect` only (not to routes or director backends).
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Upstreams {
+    /// Servers in addition to `connect` (which is the first server).
+    pub servers: Vec<Connect>,
+    /// How to select a server?
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// Mark a server as down after this many consecutive failures to connect (or greet).
+    #[serde(default = "default_max_failures")]
+    pub max_failures: u32,
+    /// Try a server that is marked as down again after this many seconds.
+    #[serde(default = "default_cool_down")]
+    pub cool_down: u64,
+    /// Deprecated: Use `timeouts.connect` (which it is moved to when the config is loaded).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u64>,
+}
+
+/// Protection against password guessing.
+///
+/// Failed `LOGIN`s and `AUTHENTICATE`s are counted per client IP address and per username
+/// in a sliding window. Every failure is logged as "Authentication failure; rhost=<IP>
+/// user=<username>" (for fail2ban).
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BruteForce {
+    /// Count failures of the last this many seconds.
+    #[serde(default = "default_failure_window")]
+    pub window: u64,
+    /// Block an IP address after this many failures.
+    #[serde(default = "default_max_failures_per_ip")]
+    pub max_failures_per_ip: usize,
+    /// Block a username after this many failures.
+    #[serde(default = "default_max_failures_per_user")]
+    pub max_failures_per_user: usize,
+    /// Delay the answer to a failed attempt by this many seconds (doubled with every
+    /// further failure in the window).
+    #[serde(default = "default_failure_delay")]
+    pub delay: u64,
+    /// Never delay the answer by more than this many seconds.
+    #[serde(default = "default_max_failure_delay")]
+    pub max_delay: u64,
+    /// What to do with attempts of blocked IP addresses or usernames?
+    #[serde(default)]
+    pub blocked: Blocked,
+    /// Keep the failures in this file, so they survive restarts.
+    pub state_path: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub enum Blocked {
+    /// Answer with `NO [UNAVAILABLE]`.
+    #[default]
+    Reject,
+    /// Close the session with `BYE`.
+    Close,
+}
+
+/// Timeouts (in seconds).
+///
+/// Clients that are inactive for too long are logged out with a `BYE`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Timeouts {
+    /// TLS handshake with a client (including the PROXY protocol header and STARTTLS).
+    #[serde(default = "default_accept_tls_timeout")]
+    pub accept_tls: u64,
+    /// Connection to a server (including SOCKS5 or HTTP proxies).
+    #[serde(default = "default_connect_timeout")]
+    pub connect: u64,
+    /// TLS handshake with a server (including STARTTLS).
+    #[serde(default = "default_connect_tls_timeout")]
+    pub connect_tls: u64,
+    /// Greeting of a server.
+    #[serde(default = "default_greeting_timeout")]
+    pub greeting: u64,
+    /// Inactivity of a client that is not authenticated.
+    #[serde(default = "default_unauthenticated_timeout")]
+    pub unauthenticated: u64,
+    /// Inactivity of an authenticated client.
+    #[serde(default = "default_authenticated_timeout")]
+    pub authenticated: u64,
+    /// Inactivity of a client in IDLE.
+    #[serde(default = "default_idle_timeout")]
+    pub idle: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            accept_tls: default_accept_tls_timeout(),
+            connect: default_connect_timeout(),
+            connect_tls: default_connect_tls_timeout(),
+            greeting: default_greeting_timeout(),
+            unauthenticated: default_unauthenticated_timeout(),
+            authenticated: default_authenticated_timeout(),
+            idle: default_idle_timeout(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub enum Strategy {
+    /// Use the first server that is up (in order).
+    #[default]
+    Failover,
+    /// Use the servers in turn.
+    RoundRobin,
+    /// Use the server with the least connections.
+    LeastConnections,
+    /// Use the same server for the same client IP (if it is up).
+    IpHash,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub enum Malformed {
+    /// Answer with `BAD` (tagged, if the message's tag can be recovered).
+    #[default]
+    Reject,
+    /// Send the message to the other side unmodified (encrypted, if the connection uses TLS).
+    ///
+    /// Messages that weren't received completely are rejected, and so are commands the
+    /// director receives before the server connection exists.
+    Forward,
+    /// Close the session with `BYE`.
+    Close,
+}
+
+/// Text of a rejected command's status (the kind and code are always kept).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub enum CommandRejected {
+    /// Keep the server's text.
+    PassThrough,
+    /// Use a generic text (unless the code is `ALERT`).
+    #[default]
+    Generic,
+    /// Prefix the server's text with "proxy: ".
+    Prefix,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub enum SniFallback {
+    /// Use the service's `bind.identity` and `connect`.
+    #[default]
+    Default,
+    /// Abort the TLS handshake.
+    Reject,
 }
 
 /// How to accept client connections?
@@ -46,6 +552,9 @@ pub enum Bind {
         /// Port.
         #[serde(default = "default_imap_port")]
         port: u16,
+        /// Accept PROXY protocol headers from trusted networks.
+        #[serde(default)]
+        proxy_protocol: Option<AcceptProxyProtocol>,
     },
     /// Accept TLS-encrypted connections from client.
     Tls {
@@ -56,16 +565,70 @@ pub enum Bind {
         port: u16,
         /// Cryptographic objects required to accept a TLS connection.
         identity: Identity,
+        /// How to verify clients?
+        #[serde(default)]
+        tls: BindTls,
+        /// Accept PROXY protocol headers from trusted networks.
+        #[serde(default)]
+        proxy_protocol: Option<AcceptProxyProtocol>,
+    },
+    /// Accept non-encrypted connections from client and offer to upgrade them via STARTTLS.
+    StartTls {
+        /// Host.
+        host: String,
+        /// Port.
+        #[serde(default = "default_imap_port")]
+        port: u16,
+        /// Cryptographic objects required to accept a TLS connection.
+        identity: Identity,
+        /// How to verify clients?
+        #[serde(default)]
+        tls: BindTls,
+        /// Advertise `LOGINDISABLED` and reject authentication until STARTTLS was completed.
+        #[serde(default = "default_true")]
+        login_disabled: bool,
+        /// Accept PROXY protocol headers from trusted networks.
+        #[serde(default)]
+        proxy_protocol: Option<AcceptProxyProtocol>,
+    },
+    /// Accept connections from client on a Unix domain socket.
+    ///
+    /// A stale socket file (from a previous run) is removed.
+    Unix {
+        /// Path of the socket file.
+        path: String,
+        /// Permissions of the socket file, e.g., `0o660`.
+        mode: Option<u32>,
+        /// Owner of the socket file, i.e., "user", "user:group", or ":group".
+        owner: Option<String>,
+        /// Accept TLS-encrypted connections using this identity (non-encrypted by default).
+        identity: Option<Identity>,
+        /// How to verify clients (requires `identity`)?
+        #[serde(default)]
+        tls: BindTls,
     },
 }
 
 impl Bind {
-    /// Creates a `host:port` `String`.
+    /// Creates a `host:port` `String` (or the path of a Unix socket).
     pub fn addr_port(&self) -> String {
         match self {
-            Self::Tls { host, port, .. } | Self::Insecure { host, port } => {
+            Self::Tls { host, port, .. }
+            | Self::StartTls { host, port, .. }
+            | Self::Insecure { host, port, .. } => {
                 format!("{host}:{port}")
             }
+            Self::Unix { path, .. } => path.clone(),
+        }
+    }
+
+    /// PROXY protocol settings.
+    pub fn proxy_protocol(&self) -> Option<&AcceptProxyProtocol> {
+        match self {
+            Self::Tls { proxy_protocol, .. }
+            | Self::StartTls { proxy_protocol, .. }
+            | Self::Insecure { proxy_protocol, .. } => proxy_protocol.as_ref(),
+            Self::Unix { .. } => None,
         }
     }
 }
@@ -76,13 +639,106 @@ impl Display for Bind {
             Bind::Tls { host, port, .. } => {
                 write!(f, "imaps://{}:{} (TLS)", host, port)
             }
-            Bind::Insecure { host, port } => {
+            Bind::StartTls { host, port, .. } => {
+                write!(f, "imap://{}:{} (STARTTLS)", host, port)
+            }
+            Bind::Insecure { host, port, .. } => {
                 write!(f, "imap://{}:{} (insecure)", host, port)
             }
+            Bind::Unix {
+                path,
+                identity: Some(_),
+                ..
+            } => {
+                write!(f, "unix:{} (TLS)", path)
+            }
+            Bind::Unix { path, .. } => {
+                write!(f, "unix:{} (insecure)", path)
+            }
+        }
+    }
+}
+
+/// Accept PROXY protocol headers, e.g., from HAProxy.
+///
+/// Connections from trusted networks must start with a PROXY protocol header (v1 or v2).
+/// The client address from the header then replaces the peer address.
+/// Connections from other networks are handled as usual.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AcceptProxyProtocol {
+    /// Networks of load balancers, e.g., `["10.0.0.0/8", "::1/128"]`.
+    pub trusted_networks: Vec<IpNet>,
+}
+
+/// Version of the PROXY protocol header sent to the server.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum ProxyProtocolVersion {
+    /// Human-readable header.
+    V1,
+    /// Binary header.
+    V2,
+}
+
+/// How to establish TLS with clients?
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BindTls {
+    /// Request (or require) a client certificate.
+    pub client_auth: Option<ClientAuth>,
+    /// Minimum TLS version (rustls' default by default).
+    pub min_version: Option<TlsVersion>,
+    /// Maximum TLS version (rustls' default by default).
+    pub max_version: Option<TlsVersion>,
+    /// Allowed cipher suites, e.g., "TLS13_AES_256_GCM_SHA384" (rustls' defaults by default).
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    /// ALPN protocols (`["imap"]` by default). An empty list disables ALPN.
+    pub alpn_protocols: Option<Vec<String>>,
+    /// Allow session resumption.
+    #[serde(default = "default_true")]
+    pub session_resumption: bool,
+}
+
+impl Default for BindTls {
+    fn default() -> Self {
+        Self {
+            client_auth: None,
+            min_version: None,
+            max_version: None,
+            cipher_suites: Vec::new(),
+            alpn_protocols: None,
+            session_resumption: true,
         }
     }
 }
 
+/// Client certificate authentication (mutual TLS).
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuth {
+    /// Path to CA certificates (in PEM format) that client certificates must chain to.
+    pub ca_bundle_path: String,
+    /// Whether clients without certificate are rejected.
+    #[serde(default)]
+    pub mode: ClientAuthMode,
+    /// Accept only clients with a certificate with one of these subjects, e.g.,
+    /// "CN=alice, O=Example" (any certificate by default).
+    ///
+    /// Clients without certificate are rejected, too, even with `mode = "Optional"`.
+    #[serde(default)]
+    pub subjects: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub enum ClientAuthMode {
+    /// Reject clients without a valid certificate.
+    #[default]
+    Required,
+    /// Accept clients without certificate, but reject invalid certificates.
+    Optional,
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 #[serde(tag = "type")]
 pub enum Identity {
@@ -93,6 +749,54 @@ pub enum Identity {
         /// Path to leaf key (in PEM format).
         leaf_key_path: String,
     },
+    /// Certificate chain and leaf key in a single file.
+    CombinedPem {
+        /// Path to certificate chain and leaf key (in PEM format).
+        path: String,
+    },
+    /// PKCS#12 (PFX) bundle.
+    Pkcs12 {
+        /// Path to bundle.
+        path: String,
+        /// Password of bundle (empty by default).
+        #[serde(default)]
+        password: Option<Secret>,
+    },
+}
+
+impl Identity {
+    /// Paths of all files the identity is loaded from.
+    pub fn paths(&self) -> Vec<&str> {
+        match self {
+            Self::CertificateChainAndLeafKey {
+                certificate_chain_path,
+                leaf_key_path,
+            } => vec![certificate_chain_path, leaf_key_path],
+            Self::CombinedPem { path } => vec![path],
+            Self::Pkcs12 { path, password } => match password {
+                Some(Secret::File {
+                    path: password_path,
+                }) => vec![path, password_path],
+                _ => vec![path],
+            },
+        }
+    }
+}
+
+/// Where to read a secret from?
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "source")]
+pub enum Secret {
+    /// Read secret from file (without trailing newline).
+    File {
+        /// Path to file.
+        path: String,
+    },
+    /// Read secret from environment variable.
+    Env {
+        /// Name of variable.
+        name: String,
+    },
 }
 
 /// How to establish server connections?
@@ -106,6 +810,11 @@ pub enum Connect {
         /// Port.
         #[serde(default = "default_imap_port")]
         port: u16,
+        /// Send a PROXY protocol header with the client's address.
+        #[serde(default)]
+        proxy_protocol: Option<ProxyProtocolVersion>,
+        /// Connect through a SOCKS5 or HTTP proxy.
+        via: Option<Via>,
     },
     /// Establish TLS-encrypted connection to server.
     Tls {
@@ -114,16 +823,65 @@ pub enum Connect {
         /// Port.
         #[serde(default = "default_imaps_port")]
         port: u16,
+        /// How to verify the server?
+        #[serde(default)]
+        tls: ConnectTls,
+        /// Send a PROXY protocol header with the client's address.
+        #[serde(default)]
+        proxy_protocol: Option<ProxyProtocolVersion>,
+        /// Connect through a SOCKS5 or HTTP proxy.
+        via: Option<Via>,
+    },
+    /// Establish non-encrypted connection to server and upgrade it via STARTTLS.
+    StartTls {
+        /// Host.
+        host: String,
+        /// Port.
+        #[serde(default = "default_imap_port")]
+        port: u16,
+        /// How to verify the server?
+        #[serde(default)]
+        tls: ConnectTls,
+        /// Send a PROXY protocol header with the client's address.
+        #[serde(default)]
+        proxy_protocol: Option<ProxyProtocolVersion>,
+        /// Connect through a SOCKS5 or HTTP proxy.
+        via: Option<Via>,
+    },
+    /// Establish connection to server via a Unix domain socket.
+    Unix {
+        /// Path of the socket file.
+        path: String,
+        /// Use TLS and verify the server like this (non-encrypted by default).
+        ///
+        /// Note: `server_name` defaults to "localhost".
+        tls: Option<ConnectTls>,
+        /// Send a PROXY protocol header with the client's address.
+        #[serde(default)]
+        proxy_protocol: Option<ProxyProtocolVersion>,
     },
 }
 
 impl Connect {
-    /// Creates a `host:port` `String`.
+    /// Creates a `host:port` `String` (or the path of a Unix socket).
     pub fn addr_port(&self) -> String {
         match self {
-            Self::Tls { host, port, .. } | Self::Insecure { host, port } => {
+            Self::Tls { host, port, .. }
+            | Self::StartTls { host, port, .. }
+            | Self::Insecure { host, port, .. } => {
                 format!("{host}:{port}")
             }
+            Self::Unix { path, .. } => path.clone(),
+        }
+    }
+
+    /// PROXY protocol settings.
+    pub fn proxy_protocol(&self) -> Option<ProxyProtocolVersion> {
+        match self {
+            Self::Tls { proxy_protocol, .. }
+            | Self::StartTls { proxy_protocol, .. }
+            | Self::Insecure { proxy_protocol, .. }
+            | Self::Unix { proxy_protocol, .. } => *proxy_protocol,
         }
     }
 }
@@ -134,13 +892,168 @@ impl Display for Connect {
             Connect::Tls { host, port, .. } => {
                 write!(f, "imaps://{}:{} (TLS)", host, port)
             }
-            Connect::Insecure { host, port } => {
+            Connect::StartTls { host, port, .. } => {
+                write!(f, "imap://{}:{} (STARTTLS)", host, port)
+            }
+            Connect::Insecure { host, port, .. } => {
                 write!(f, "imap://{}:{} (insecure)", host, port)
             }
+            Connect::Unix {
+                path, tls: Some(_), ..
+            } => {
+                write!(f, "unix:{} (TLS)", path)
+            }
+            Connect::Unix { path, .. } => {
+                write!(f, "unix:{} (insecure)", path)
+            }
         }
     }
 }
 
+/// Proxy that server connections are established through.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", deny_unknown_fields)]
+pub enum Via {
+    /// SOCKS5 proxy, e.g., Tor.
+    Socks5 {
+        /// Host.
+        host: String,
+        /// Port.
+        #[serde(default = "default_socks5_port")]
+        port: u16,
+        /// Username for username/password authentication.
+        username: Option<String>,
+        /// Password for username/password authentication.
+        password: Option<Secret>,
+        /// Let the proxy resolve the server's `host` (required for Tor's onion services).
+        #[serde(default = "default_true")]
+        remote_dns: bool,
+    },
+    /// HTTP proxy supporting the CONNECT method.
+    HttpConnect {
+        /// Host.
+        host: String,
+        /// Port.
+        #[serde(default = "default_http_proxy_port")]
+        port: u16,
+        /// Username for basic authentication.
+        username: Option<String>,
+        /// Password for basic authentication.
+        password: Option<Secret>,
+    },
+}
+
+impl Display for Via {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Via::Socks5 { host, port, .. } => write!(f, "socks5://{}:{}", host, port),
+            Via::HttpConnect { host, port, .. } => write!(f, "http://{}:{}", host, port),
+        }
+    }
+}
+
+/// How to establish TLS with the server?
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectTls {
+    /// Name used for SNI and certificate verification (`host` by default).
+    ///
+    /// Useful when connecting by IP address.
+    pub server_name: Option<String>,
+    /// Path to CA certificates (in PEM format) that replace the system's trust anchors.
+    pub ca_bundle_path: Option<String>,
+    /// SHA-256 fingerprints of accepted server certificates.
+    ///
+    /// When pins are configured, the server certificate must match at least one of them.
+    #[serde(default)]
+    pub pinned_certificate_sha256: Vec<Fingerprint>,
+    /// SHA-256 fingerprints of accepted server public keys (SubjectPublicKeyInfo).
+    #[serde(default)]
+    pub pinned_spki_sha256: Vec<Fingerprint>,
+    /// Accept any server certificate, i.e., disable verification (dangerous).
+    ///
+    /// Pins are still enforced. Only use this for lab servers with self-signed certificates.
+    #[serde(default)]
+    pub danger_accept_invalid_certs: bool,
+    /// Client certificate to authenticate the proxy to the server (mutual TLS).
+    pub client_identity: Option<Identity>,
+    /// Minimum TLS version (rustls' default by default).
+    pub min_version: Option<TlsVersion>,
+    /// Maximum TLS version (rustls' default by default).
+    pub max_version: Option<TlsVersion>,
+    /// Allowed cipher suites, e.g., "TLS13_AES_256_GCM_SHA384" (rustls' defaults by default).
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    /// ALPN protocols (`["imap"]` by default). An empty list disables ALPN.
+    pub alpn_protocols: Option<Vec<String>>,
+    /// Allow session resumption.
+    #[serde(default = "default_true")]
+    pub session_resumption: bool,
+}
+
+impl Default for ConnectTls {
+    fn default() -> Self {
+        Self {
+            server_name: None,
+            ca_bundle_path: None,
+            pinned_certificate_sha256: Vec::new(),
+            pinned_spki_sha256: Vec::new(),
+            danger_accept_invalid_certs: false,
+            client_identity: None,
+            min_version: None,
+            max_version: None,
+            cipher_suites: Vec::new(),
+            alpn_protocols: None,
+            session_resumption: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Deserialize, Serialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// SHA-256 fingerprint, written in hex with optional colons, e.g., "AB:CD:...".
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Fingerprint(pub [u8; 32]);
+
+impl TryFrom<String> for Fingerprint {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let hex: String = value.chars().filter(|c| *c != ':').collect();
+
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(format!("Expected SHA-256 fingerprint, got \"{value}\""));
+        }
+
+        let mut fingerprint = [0; 32];
+        for (byte, chunk) in fingerprint.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            // Unwrap: We checked that `hex` is ASCII.
+            *byte = u8::from_str_radix(std::str::from_utf8(chunk).unwrap(), 16)
+                .map_err(|_| format!("Expected SHA-256 fingerprint, got \"{value}\""))?;
+        }
+
+        Ok(Self(fingerprint))
+    }
+}
+
+impl From<Fingerprint> for String {
+    fn from(value: Fingerprint) -> Self {
+        value
+            .0
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error(transparent)]
@@ -153,7 +1066,14 @@ pub enum Error {
 
 #[cfg(test)]
 mod tests {
-    use crate::config::{Bind, Config, Connect, Identity, Service};
+    use std::collections::BTreeMap;
+
+    use crate::config::{
+        AuthTermination, Bind, BindTls, Blocked, BruteForce, ClientAuth, ClientAuthMode, Config,
+        Connect, ConnectTls, Director, Fingerprint, Identity, OAuth2, OAuth2Mechanism,
+        PasswordPolicy, Route, ScramMechanism, Secret, Service, SniFallback, Timeouts, TlsVersion,
+        UpstreamCredentials, Via,
+    };
 
     #[test]
     fn test_config() {
@@ -170,11 +1090,26 @@ mod tests {
                     bind: Bind::Insecure {
                         host: "127.0.0.1".into(),
                         port: 1143,
+                        proxy_protocol: None,
                     },
                     connect: Connect::Tls {
                         host: "127.0.0.1".into(),
                         port: 993,
+                        tls: ConnectTls::default(),
+                        proxy_protocol: None,
+                        via: None,
                     },
+                    routes: vec![],
+                    sni_fallback: SniFallback::Default,
+                    director: None,
+                    auth_termination: None,
+                    upstreams: None,
+                    timeouts: Default::default(),
+                    malformed: Default::default(),
+                    command_rejected: Default::default(),
+                    capabilities: Default::default(),
+                    auth_translation: false,
+                    brute_force: None,
                 },
                 Service {
                     name: "TLS to TLS".into(),
@@ -185,22 +1120,52 @@ mod tests {
                             certificate_chain_path: "localhost.pem".into(),
                             leaf_key_path: "localhost-key.pem".into(),
                         },
+                        tls: BindTls::default(),
+                        proxy_protocol: None,
                     },
                     connect: Connect::Tls {
                         host: "127.0.0.1".into(),
                         port: 993,
+                        tls: ConnectTls::default(),
+                        proxy_protocol: None,
+                        via: None,
                     },
+                    routes: vec![],
+                    sni_fallback: SniFallback::Default,
+                    director: None,
+                    auth_termination: None,
+                    upstreams: None,
+                    timeouts: Default::default(),
+                    malformed: Default::default(),
+                    command_rejected: Default::default(),
+                    capabilities: Default::default(),
+                    auth_translation: false,
+                    brute_force: None,
                 },
                 Service {
                     name: "Insecure to Insecure".into(),
                     bind: Bind::Insecure {
                         host: "127.0.0.1".into(),
                         port: 3143,
+                        proxy_protocol: None,
                     },
                     connect: Connect::Insecure {
                         host: "127.0.0.1".into(),
                         port: 143,
+                        proxy_protocol: None,
+                        via: None,
                     },
+                    routes: vec![],
+                    sni_fallback: SniFallback::Default,
+                    director: None,
+                    auth_termination: None,
+                    upstreams: None,
+                    timeouts: Default::default(),
+                    malformed: Default::default(),
+                    command_rejected: Default::default(),
+                    capabilities: Default::default(),
+                    auth_translation: false,
+                    brute_force: None,
                 },
                 Service {
                     name: "TLS to Insecure".into(),
@@ -211,17 +1176,502 @@ mod tests {
                             certificate_chain_path: "localhost.pem".into(),
                             leaf_key_path: "localhost-key.pem".into(),
                         },
+                        tls: BindTls::default(),
+                        proxy_protocol: None,
                     },
                     connect: Connect::Insecure {
                         host: "127.0.0.1".into(),
                         port: 143,
+                        proxy_protocol: None,
+                        via: None,
+                    },
+                    routes: vec![],
+                    sni_fallback: SniFallback::Default,
+                    director: None,
+                    auth_termination: None,
+                    upstreams: None,
+                    timeouts: Default::default(),
+                    malformed: Default::default(),
+                    command_rejected: Default::default(),
+                    capabilities: Default::default(),
+                    auth_translation: false,
+                    brute_force: None,
+                },
+                Service {
+                    name: "STARTTLS to TLS".into(),
+                    bind: Bind::StartTls {
+                        host: "127.0.0.1".into(),
+                        port: 5143,
+                        identity: Identity::CertificateChainAndLeafKey {
+                            certificate_chain_path: "localhost.pem".into(),
+                            leaf_key_path: "localhost-key.pem".into(),
+                        },
+                        tls: BindTls::default(),
+                        login_disabled: true,
+                        proxy_protocol: None,
+                    },
+                    connect: Connect::Tls {
+                        host: "127.0.0.1".into(),
+                        port: 993,
+                        tls: ConnectTls::default(),
+                        proxy_protocol: None,
+                        via: None,
                     },
+                    routes: vec![],
+                    sni_fallback: SniFallback::Default,
+                    director: None,
+                    auth_termination: None,
+                    upstreams: None,
+                    timeouts: Default::default(),
+                    malformed: Default::default(),
+                    command_rejected: Default::default(),
+                    capabilities: Default::default(),
+                    auth_translation: false,
+                    brute_force: None,
+                },
+                Service {
+                    name: "TLS to TLS (by server name)".into(),
+                    bind: Bind::Tls {
+                        host: "127.0.0.1".into(),
+                        port: 6993,
+                        identity: Identity::CertificateChainAndLeafKey {
+                            certificate_chain_path: "localhost.pem".into(),
+                            leaf_key_path: "localhost-key.pem".into(),
+                        },
+                        tls: BindTls::default(),
+                        proxy_protocol: None,
+                    },
+                    connect: Connect::Tls {
+                        host: "127.0.0.1".into(),
+                        port: 993,
+                        tls: ConnectTls::default(),
+                        proxy_protocol: None,
+                        via: None,
+                    },
+                    routes: vec![Route {
+                        server_names: vec!["imap.customer-a.example".into()],
+                        identity: Identity::CertificateChainAndLeafKey {
+                            certificate_chain_path: "customer-a.pem".into(),
+                            leaf_key_path: "customer-a-key.pem".into(),
+                        },
+                        connect: Connect::Tls {
+                            host: "backend-a.example".into(),
+                            port: 993,
+                            tls: ConnectTls::default(),
+                            proxy_protocol: None,
+                            via: None,
+                        },
+                    }],
+                    sni_fallback: SniFallback::Default,
+                    director: None,
+                    auth_termination: None,
+                    upstreams: None,
+                    timeouts: Default::default(),
+                    malformed: Default::default(),
+                    command_rejected: Default::default(),
+                    capabilities: Default::default(),
+                    auth_translation: false,
+                    brute_force: None,
                 },
             ],
+            shutdown: Default::default(),
         };
 
         let got = toml::from_str(&file).unwrap();
 
         assert_eq!(expected, got);
     }
+
+    #[test]
+    fn test_connect_tls() {
+        let file = r#"
+            encryption = "Tls"
+            host = "192.0.2.1"
+
+            [tls]
+            server_name = "imap.example.org"
+            ca_bundle_path = "private/ca.pem"
+            pinned_spki_sha256 = [
+                "6d:f6:bb:0b:3a:7e:31:a4:24:d3:93:57:fd:2c:a6:ef:8a:bc:2b:41:f0:a6:95:8f:9c:65:27:f1:15:93:1d:46",
+            ]
+            max_version = "1.2"
+            alpn_protocols = []
+            session_resumption = false
+        "#;
+
+        let expected = Connect::Tls {
+            host: "192.0.2.1".into(),
+            port: 993,
+            tls: ConnectTls {
+                server_name: Some("imap.example.org".into()),
+                ca_bundle_path: Some("private/ca.pem".into()),
+                pinned_certificate_sha256: vec![],
+                pinned_spki_sha256: vec![Fingerprint([
+                    0x6d, 0xf6, 0xbb, 0x0b, 0x3a, 0x7e, 0x31, 0xa4, 0x24, 0xd3, 0x93, 0x57, 0xfd,
+                    0x2c, 0xa6, 0xef, 0x8a, 0xbc, 0x2b, 0x41, 0xf0, 0xa6, 0x95, 0x8f, 0x9c, 0x65,
+                    0x27, 0xf1, 0x15, 0x93, 0x1d, 0x46,
+                ])],
+                danger_accept_invalid_certs: false,
+                client_identity: None,
+                min_version: None,
+                max_version: Some(TlsVersion::Tls12),
+                cipher_suites: vec![],
+                alpn_protocols: Some(vec![]),
+                session_resumption: false,
+            },
+            proxy_protocol: None,
+            via: None,
+        };
+
+        let got: Connect = toml::from_str(file).unwrap();
+        assert_eq!(expected, got);
+
+        let fingerprint = toml::from_str::<Connect>(&file.replace("46\"", "4\""));
+        assert!(fingerprint.is_err());
+    }
+
+    #[test]
+    fn test_bind_tls() {
+        let file = r#"
+            encryption = "Tls"
+            host = "0.0.0.0"
+
+            [identity]
+            type = "CertificateChainAndLeafKey"
+            certificate_chain_path = "localhost.pem"
+            leaf_key_path = "localhost-key.pem"
+
+            [tls]
+            min_version = "1.3"
+            cipher_suites = ["TLS13_AES_256_GCM_SHA384"]
+
+            [tls.client_auth]
+            ca_bundle_path = "clients.pem"
+            mode = "Optional"
+            subjects = ["CN=alice, O=Example"]
+        "#;
+
+        let expected = Bind::Tls {
+            host: "0.0.0.0".into(),
+            port: 993,
+            identity: Identity::CertificateChainAndLeafKey {
+                certificate_chain_path: "localhost.pem".into(),
+                leaf_key_path: "localhost-key.pem".into(),
+            },
+            tls: BindTls {
+                client_auth: Some(ClientAuth {
+                    ca_bundle_path: "clients.pem".into(),
+                    mode: ClientAuthMode::Optional,
+                    subjects: vec!["CN=alice, O=Example".into()],
+                }),
+                min_version: Some(TlsVersion::Tls13),
+                cipher_suites: vec!["TLS13_AES_256_GCM_SHA384".into()],
+                ..BindTls::default()
+            },
+            proxy_protocol: None,
+        };
+
+        let got: Bind = toml::from_str(file).unwrap();
+        assert_eq!(expected, got);
+    }
+
+    #[test]
+    fn test_director() {
+        let file = r#"
+            name = "Director"
+
+            [bind]
+            encryption = "Insecure"
+            host = "127.0.0.1"
+
+            [connect]
+            encryption = "Tls"
+            host = "imap.example.org"
+
+            [director]
+            users_path = "users.txt"
+            subjects = { "CN=webmail" = "backend-2" }
+            users = { "alice@example.org" = "backend-1" }
+            domains = { "example.com" = "backend-2" }
+
+            [director.backends]
+            backend-1 = { encryption = "Tls", host = "backend-1.example.org" }
+            backend-2 = { encryption = "Insecure", host = "192.0.2.2" }
+        "#;
+
+        let expected = Director {
+            backends: BTreeMap::from([
+                (
+                    "backend-1".into(),
+                    Connect::Tls {
+                        host: "backend-1.example.org".into(),
+                        port: 993,
+                        tls: ConnectTls::default(),
+                        proxy_protocol: None,
+                        via: None,
+                    },
+                ),
+                (
+                    "backend-2".into(),
+                    Connect::Insecure {
+                        host: "192.0.2.2".into(),
+                        port: 143,
+                        proxy_protocol: None,
+                        via: None,
+                    },
+                ),
+            ]),
+            subjects: BTreeMap::from([("CN=webmail".into(), "backend-2".into())]),
+            users: BTreeMap::from([("alice@example.org".into(), "backend-1".into())]),
+            domains: BTreeMap::from([("example.com".into(), "backend-2".into())]),
+            users_path: Some("users.txt".into()),
+            max_login_failures: 3,
+        };
+
+        let got: Service = toml::from_str(file).unwrap();
+        assert_eq!(Some(expected), got.director);
+    }
+
+    #[test]
+    fn test_auth_termination() {
+        let file = r#"
+            users_path = "users.txt"
+            password_policy = { min_length = 12 }
+            scram = ["SCRAM-SHA-256", "SCRAM-SHA-256-PLUS"]
+            upstream = { username = "master", password = { source = "Env", name = "MASTER" }, impersonate = true }
+
+            [users."alice@example.org"]
+            username = "alice"
+            refresh_token = { source = "File", path = "alice.txt" }
+
+            [oauth2]
+            token_endpoint = "http://127.0.0.1:8080/token"
+            client_id = "proxy"
+            mechanism = "OAuthBearer"
+        "#;
+
+        let expected = AuthTermination {
+            users_path: "users.txt".into(),
+            password_policy: PasswordPolicy {
+                min_length: 12,
+                max_length: 1024,
+            },
+            upstream: Some(UpstreamCredentials {
+                username: "master".into(),
+                password: Some(Secret::Env {
+                    name: "MASTER".into(),
+                }),
+                refresh_token: None,
+                impersonate: true,
+            }),
+            users: BTreeMap::from([(
+                "alice@example.org".into(),
+                UpstreamCredentials {
+                    username: "alice".into(),
+                    password: None,
+                    refresh_token: Some(Secret::File {
+                        path: "alice.txt".into(),
+                    }),
+                    impersonate: false,
+                },
+            )]),
+            oauth2: Some(OAuth2 {
+                token_endpoint: "http://127.0.0.1:8080/token".into(),
+                client_id: "proxy".into(),
+                client_secret: None,
+                mechanism: OAuth2Mechanism::OAuthBearer,
+                tls: ConnectTls::default(),
+                timeout: 30,
+            }),
+            scram: vec![ScramMechanism::ScramSha256, ScramMechanism::ScramSha256Plus],
+        };
+
+        let got: AuthTermination = toml::from_str(file).unwrap();
+        assert_eq!(expected, got);
+    }
+
+    #[test]
+    fn test_via() {
+        let file = r#"
+            encryption = "Tls"
+            host = "example.onion"
+            via = { type = "Socks5", host = "127.0.0.1", port = 9050, username = "isolation" }
+        "#;
+
+        let expected = Connect::Tls {
+            host: "example.onion".into(),
+            port: 993,
+            tls: ConnectTls::default(),
+            proxy_protocol: None,
+            via: Some(Via::Socks5 {
+                host: "127.0.0.1".into(),
+                port: 9050,
+                username: Some("isolation".into()),
+                password: None,
+                remote_dns: true,
+            }),
+        };
+
+        let got: Connect = toml::from_str(file).unwrap();
+        assert_eq!(expected, got);
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let file = r#"
+            unauthenticated = 30
+            idle = 1740
+        "#;
+
+        let expected = Timeouts {
+            unauthenticated: 30,
+            idle: 1740,
+            ..Timeouts::default()
+        };
+
+        let got: Timeouts = toml::from_str(file).unwrap();
+        assert_eq!(expected, got);
+        assert_eq!(1800, got.authenticated);
+    }
+
+    #[test]
+    fn test_migrate_connect_timeout() {
+        let file = r#"
+            [[services]]
+            name = "Balanced"
+            bind = { encryption = "Insecure", host = "127.0.0.1", port = 1143 }
+            connect = { encryption = "Insecure", host = "127.0.0.1", port = 143 }
+
+            [services.upstreams]
+            servers = []
+            connect_timeout = 5
+        "#;
+
+        let mut got: Config = toml::from_str(file).unwrap();
+        got.migrate();
+        assert_eq!(5, got.services[0].timeouts.connect);
+        assert_eq!(
+            None,
+            got.services[0].upstreams.as_ref().unwrap().connect_timeout
+        );
+    }
+
+    #[test]
+    fn test_brute_force() {
+        let file = r#"
+            max_failures_per_user = 5
+            blocked = "Close"
+            state_path = "/var/lib/imap-proxy/failures.json"
+        "#;
+
+        let expected = BruteForce {
+            window: 900,
+            max_failures_per_ip: 20,
+            max_failures_per_user: 5,
+            delay: 1,
+            max_delay: 15,
+            blocked: Blocked::Close,
+            state_path: Some("/var/lib/imap-proxy/failures.json".into()),
+        };
+
+        let got: BruteForce = toml::from_str(file).unwrap();
+        assert_eq!(expected, got);
+    }
+
+    #[test]
+    fn test_unix() {
+        let file = r#"
+            name = "Unix to Unix"
+
+            [bind]
+            encryption = "Unix"
+            path = "/run/imap-proxy/imap.sock"
+            mode = 0o660
+            owner = "imap-proxy:www-data"
+
+            [connect]
+            encryption = "Unix"
+            path = "/run/dovecot/imap.sock"
+            tls = { ca_bundle_path = "ca.pem" }
+        "#;
+
+        let got: Service = toml::from_str(file).unwrap();
+        assert_eq!(
+            Bind::Unix {
+                path: "/run/imap-proxy/imap.sock".into(),
+                mode: Some(0o660),
+                owner: Some("imap-proxy:www-data".into()),
+                identity: None,
+                tls: BindTls::default(),
+            },
+            got.bind
+        );
+        assert_eq!(
+            Connect::Unix {
+                path: "/run/dovecot/imap.sock".into(),
+                tls: Some(ConnectTls {
+                    ca_bundle_path: Some("ca.pem".into()),
+                    ..Default::default()
+                }),
+                proxy_protocol: None,
+            },
+            got.connect
+        );
+    }
+
+    #[test]
+    fn test_identity() {
+        let tests = [
+            (
+                r#"
+                    type = "CombinedPem"
+                    path = "localhost.pem"
+                "#,
+                Identity::CombinedPem {
+                    path: "localhost.pem".into(),
+                },
+            ),
+            (
+                r#"
+                    type = "Pkcs12"
+                    path = "localhost.p12"
+                "#,
+                Identity::Pkcs12 {
+                    path: "localhost.p12".into(),
+                    password: None,
+                },
+            ),
+            (
+                r#"
+                    type = "Pkcs12"
+                    path = "localhost.p12"
+                    password = { source = "File", path = "localhost.pass" }
+                "#,
+                Identity::Pkcs12 {
+                    path: "localhost.p12".into(),
+                    password: Some(Secret::File {
+                        path: "localhost.pass".into(),
+                    }),
+                },
+            ),
+            (
+                r#"
+                    type = "Pkcs12"
+                    path = "localhost.p12"
+                    password = { source = "Env", name = "IMAP_PROXY_PKCS12_PASSWORD" }
+                "#,
+                Identity::Pkcs12 {
+                    path: "localhost.p12".into(),
+                    password: Some(Secret::Env {
+                        name: "IMAP_PROXY_PKCS12_PASSWORD".into(),
+                    }),
+                },
+            ),
+        ];
+
+        for (file, expected) in tests {
+            let got: Identity = toml::from_str(file).unwrap();
+            assert_eq!(expected, got);
+        }
+    }
 }
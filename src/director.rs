@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+
+use tracing::{error, warn};
+
+use crate::{config::Director, proxy::ProxyError};
+
+/// Looks up the backend of a user (director mode).
+pub struct RoutingTable {
+    subjects: HashMap<String, String>,
+    users: HashMap<String, String>,
+    /// Sorted by length (longest first).
+    domains: Vec<(String, String)>,
+    users_path: Option<String>,
+    backends: Vec<String>,
+}
+
+impl RoutingTable {
+    pub fn new(director: &Director) -> Result<Self, ProxyError> {
+        let backends: Vec<String> = director.backends.keys().cloned().collect();
+
+        let routes = director
+            .subjects
+            .values()
+            .chain(director.users.values())
+            .chain(director.domains.values());
+        for backend in routes {
+            if !backends.contains(backend) {
+                return Err(ProxyError::UnknownBackend(backend.clone()));
+            }
+        }
+
+        let users = director
+            .users
+            .iter()
+            .map(|(user, backend)| (user.to_lowercase(), backend.clone()))
+            .collect();
+
+        let mut domains: Vec<_> = director
+            .domains
+            .iter()
+            .map(|(domain, backend)| (domain.to_lowercase(), backend.clone()))
+            .collect();
+        domains.sort_by_key(|(domain, _)| std::cmp::Reverse(domain.len()));
+
+        Ok(Self {
+            subjects: director.subjects.clone().into_iter().collect(),
+            users,
+            domains,
+            users_path: director.users_path.clone(),
+            backends,
+        })
+    }
+
+    /// Name of the backend for clients with a certificate of this subject (if any).
+    pub fn lookup_subject(&self, subject: &str) -> Option<String> {
+        self.subjects.get(subject).cloned()
+    }
+
+    /// Name of the backend for `username`, `None` when the user is unknown.
+    pub async fn lookup(&self, username: &str) -> Option<String> {
+        let username = username.to_lowercase();
+
+        if let Some(backend) = self.lookup_file(&username).await {
+            return Some(backend);
+        }
+
+        if let Some(backend) = self.users.get(&username) {
+            return Some(backend.clone());
+        }
+
+        let (_, domain) = username.rsplit_once('@')?;
+        self.domains
+            .iter()
+            .find(|(suffix, _)| {
+                domain == suffix
+                    || domain
+                        .strip_suffix(suffix.as_str())
+                        .is_some_and(|subdomain| subdomain.ends_with('.'))
+            })
+            .map(|(_, backend)| backend.clone())
+    }
+
+    async fn lookup_file(&self, username: &str) -> Option<String> {
+        let path = self.users_path.as_ref()?;
+
+        let content = match tokio::fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(error) => {
+                error!(path, %error, "Failed to read users file");
+                return None;
+            }
+        };
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let (Some(user), Some(backend), None) = (fields.next(), fields.next(), fields.next())
+            else {
+                warn!(path, line, "Ignored malformed line in users file");
+                continue;
+            };
+
+            if user.to_lowercase() != username {
+                continue;
+            }
+
+            if !self.backends.iter().any(|known| known == backend) {
+                warn!(path, backend, "Ignored unknown backend in users file");
+                continue;
+            }
+
+            return Some(backend.to_owned());
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::config::Connect;
+
+    fn director() -> Director {
+        let backend = |host: &str| Connect::Insecure {
+            host: host.into(),
+            port: 143,
+            proxy_protocol: None,
+            via: None,
+        };
+
+        Director {
+            backends: BTreeMap::from([
+                ("backend-1".into(), backend("192.0.2.1")),
+                ("backend-2".into(), backend("192.0.2.2")),
+                ("backend-3".into(), backend("192.0.2.3")),
+            ]),
+            subjects: BTreeMap::from([("CN=webmail, O=Example".into(), "backend-3".into())]),
+            users: BTreeMap::from([("Alice@Example.org".into(), "backend-1".into())]),
+            domains: BTreeMap::from([
+                ("example.org".into(), "backend-2".into()),
+                ("mail.example.org".into(), "backend-3".into()),
+            ]),
+            users_path: None,
+            max_login_failures: 3,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let table = RoutingTable::new(&director()).unwrap();
+
+        assert_eq!(
+            Some("backend-1".into()),
+            table.lookup("alice@example.org").await
+        );
+        assert_eq!(
+            Some("backend-1".into()),
+            table.lookup("ALICE@example.ORG").await
+        );
+        assert_eq!(
+            Some("backend-2".into()),
+            table.lookup("bob@example.org").await
+        );
+        assert_eq!(
+            Some("backend-2".into()),
+            table.lookup("bob@www.example.org").await
+        );
+        // The longest suffix wins.
+        assert_eq!(
+            Some("backend-3".into()),
+            table.lookup("bob@mail.example.org").await
+        );
+        assert_eq!(
+            Some("backend-3".into()),
+            table.lookup("bob@a.mail.example.org").await
+        );
+        // Only whole labels match.
+        assert_eq!(None, table.lookup("bob@badexample.org").await);
+        assert_eq!(None, table.lookup("bob@example.com").await);
+        assert_eq!(None, table.lookup("bob").await);
+
+        // Subjects are compared exactly.
+        assert_eq!(
+            Some("backend-3".into()),
+            table.lookup_subject("CN=webmail, O=Example")
+        );
+        assert_eq!(None, table.lookup_subject("CN=webmail"));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_file() {
+        let dir = std::env::temp_dir().join(format!("imap-proxy-director-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("users.txt");
+        std::fs::write(
+            &path,
+            "# Moved to backend-3\n\
+             \n\
+             ALICE@example.org backend-3\n\
+             bob@example.org backend-9\n\
+             carol@example.org\n\
+             dave@example.org backend-1\n",
+        )
+        .unwrap();
+
+        let mut director = director();
+        director.users_path = Some(path.to_str().unwrap().into());
+        let table = RoutingTable::new(&director).unwrap();
+
+        // The file takes precedence.
+        assert_eq!(
+            Some("backend-3".into()),
+            table.lookup("alice@example.org").await
+        );
+        assert_eq!(
+            Some("backend-1".into()),
+            table.lookup("Dave@Example.org").await
+        );
+        // Unknown backends and malformed lines are ignored.
+        assert_eq!(
+            Some("backend-2".into()),
+            table.lookup("bob@example.org").await
+        );
+        assert_eq!(
+            Some("backend-2".into()),
+            table.lookup("carol@example.org").await
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Without the file, the other maps are used.
+        assert_eq!(
+            Some("backend-1".into()),
+            table.lookup("alice@example.org").await
+        );
+        assert_eq!(
+            Some("backend-2".into()),
+            table.lookup("dave@example.org").await
+        );
+    }
+
+    #[test]
+    fn test_unknown_backend() {
+        let mut director = director();
+        director
+            .domains
+            .insert("example.com".into(), "backend-9".into());
+
+        assert!(matches!(
+            RoutingTable::new(&director),
+            Err(ProxyError::UnknownBackend(backend)) if backend == "backend-9"
+        ));
+
+        let mut director = self::director();
+        director
+            .subjects
+            .insert("CN=mallory".into(), "backend-8".into());
+
+        assert!(matches!(
+            RoutingTable::new(&director),
+            Err(ProxyError::UnknownBackend(backend)) if backend == "backend-8"
+        ));
+    }
+}
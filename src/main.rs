@@ -1,13 +1,35 @@
+mod auth;
 mod config;
+mod director;
+mod malformed;
+mod oauth2;
+mod pool;
 mod proxy;
+mod proxy_protocol;
+mod sasl;
+mod scram;
+mod shutdown;
+mod stream;
+mod termination;
+mod throttle;
+mod tls;
+mod unix;
 mod util;
+mod via;
 
-use anyhow::{Context, Result};
+use std::{future::Future, time::Duration};
+
+use anyhow::{bail, Context, Result};
 use argh::FromArgs;
 use config::{Config, Service};
-use proxy::{ClientAcceptedState, Proxy};
-use tokio::task::JoinSet;
-use tracing::{error, instrument, Instrument};
+use proxy::{IncomingState, Proxy};
+use shutdown::Shutdown;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    task::JoinSet,
+};
+use tracing::{error, info, instrument, Instrument, Span};
 use tracing_subscriber::EnvFilter;
 
 /// IMAP proxy.
@@ -36,28 +58,89 @@ async fn main() -> Result<()> {
     let config = Config::load(&args.config)
         .with_context(|| format!("Failed to load config from path '{}'", args.config))?;
 
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    let shutdown =
+        Shutdown::new(shutdown_receiver, &config.shutdown.text).context("Invalid shutdown text")?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
     // Start proxy services
     let mut set = JoinSet::new();
     for service in config.services {
         println!("# {}", service.name);
-        println!("{} -> {}\n", service.bind, service.connect);
+        println!("{} -> {}", service.bind, service.connect);
+        for route in &service.routes {
+            println!(
+                "{} ({}) -> {}",
+                service.bind,
+                route.server_names.join(", "),
+                route.connect
+            );
+        }
+        println!();
 
-        set.spawn(handle_service(service));
+        set.spawn(handle_service(service, shutdown.clone()));
     }
+    drop(shutdown);
 
-    // Terminate once all services has stopped
-    while let Some(res) = set.join_next().await {
-        if let Err(error) = res {
-            error!(?error, "Failed to join with service task");
+    // Terminate once all services has stopped (or on SIGTERM/Ctrl-C)
+    let services = async {
+        while let Some(res) = set.join_next().await {
+            if let Err(error) = res {
+                error!(?error, "Failed to join with service task");
+            }
         }
+    };
+    tokio::select! {
+        _ = services => return Ok(()),
+        _ = terminate.recv() => info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("Received Ctrl-C"),
     }
+
+    // Exit right away on a second SIGTERM/Ctrl-C
+    let second_signal = async {
+        tokio::select! {
+            _ = terminate.recv() => info!("Received SIGTERM again"),
+            _ = tokio::signal::ctrl_c() => info!("Received Ctrl-C again"),
+        }
+    };
+
+    info!("Shutting down");
+    let drain_timeout = Duration::from_secs(config.shutdown.drain_timeout);
+    drain(&shutdown_sender, drain_timeout, second_signal).await
+}
+
+/// Stop accepting clients and wait until all clients are gone.
+///
+/// Fails when clients remain after `drain_timeout` or when `force` completes first.
+async fn drain(
+    shutdown_sender: &watch::Sender<bool>,
+    drain_timeout: Duration,
+    force: impl Future<Output = ()>,
+) -> Result<()> {
+    shutdown_sender.send_replace(true);
+
+    tokio::select! {
+        result = tokio::time::timeout(drain_timeout, shutdown_sender.closed()) => {
+            if result.is_err() {
+                // Services hold one receiver, too, but stop right away.
+                let remaining = shutdown_sender.receiver_count();
+                bail!("Failed to drain {remaining} connection(s) in time");
+            }
+        }
+        _ = force => {
+            let remaining = shutdown_sender.receiver_count();
+            bail!("Forced exit with {remaining} connection(s) left");
+        }
+    }
+
+    info!("Drained all connections");
     Ok(())
 }
 
 #[instrument(name = "service", skip_all, fields(name = service.name))]
-async fn handle_service(service: Service) {
+async fn handle_service(service: Service, mut shutdown: Shutdown) {
     // Bind to port
-    let proxy = match Proxy::bind(service.clone()).await {
+    let proxy = match Proxy::bind(service.clone(), shutdown.clone()).await {
         Ok(proxy) => proxy,
         Err(error) => {
             error!(?error, "Failed to start service");
@@ -67,7 +150,15 @@ async fn handle_service(service: Service) {
 
     loop {
         // Wait for client
-        let proxy = match proxy.accept_client().await {
+        let result = tokio::select! {
+            result = proxy.accept_client() => result,
+            _ = shutdown.started() => {
+                info!(malformed = %proxy.malformed_counters(), "Stopped accepting clients");
+                proxy.save_failures().await;
+                return;
+            }
+        };
+        let proxy = match result {
             Ok(result) => result,
             Err(error) => {
                 error!(?error, "Failed to accept client");
@@ -87,9 +178,75 @@ async fn handle_service(service: Service) {
     }
 }
 
-#[instrument(name = "client", skip_all, fields(addr = %proxy.client_addr()))]
-async fn handle_client(proxy: Proxy<ClientAcceptedState>) -> Result<()> {
-    let proxy = proxy.connect_to_server().await?;
+#[instrument(
+    name = "client",
+    skip_all,
+    fields(
+        addr = tracing::field::Empty,
+        subject = tracing::field::Empty,
+        server = tracing::field::Empty,
+    )
+)]
+async fn handle_client(proxy: Proxy<IncomingState>) -> Result<()> {
+    // The PROXY header may change the client's address.
+    let peer_addr = proxy.client_addr().clone();
+    let proxy = proxy
+        .handshake()
+        .await
+        .with_context(|| format!("Failed to set up connection from {peer_addr}"))?;
+    let span = Span::current();
+    span.record("addr", tracing::field::display(proxy.client_addr()));
+    if let Some(subject) = proxy.client_subject() {
+        span.record("subject", subject);
+    }
+
+    let Some(proxy) = proxy.connect_to_server().await? else {
+        return Ok(());
+    };
     proxy.start_conversation().await;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let (sender, receiver) = watch::channel(false);
+        let mut shutdown = Shutdown::new(receiver, "Bye").unwrap();
+        let client = tokio::spawn(async move {
+            shutdown.started().await;
+            // The client leaves once it got its BYE.
+            drop(shutdown);
+        });
+
+        drain(&sender, Duration::from_secs(5), pending())
+            .await
+            .unwrap();
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout() {
+        let (sender, receiver) = watch::channel(false);
+
+        let error = drain(&sender, Duration::from_millis(10), pending())
+            .await
+            .unwrap_err();
+        assert_eq!("Failed to drain 1 connection(s) in time", error.to_string());
+        assert!(*receiver.borrow());
+    }
+
+    #[tokio::test]
+    async fn test_drain_forced() {
+        let (sender, _receiver) = watch::channel(false);
+
+        let error = drain(&sender, Duration::from_secs(5), async {})
+            .await
+            .unwrap_err();
+        assert_eq!("Forced exit with 1 connection(s) left", error.to_string());
+    }
+}
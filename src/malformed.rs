@@ -0,0 +1,126 @@
+//! Messages that imap-next fails to parse.
+
+use std::{
+    fmt::{Display, Formatter},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use imap_next::imap_types::core::Tag;
+
+/// Where a malformed message came from.
+#[derive(Clone, Copy, Debug)]
+pub enum Origin {
+    Client,
+    Server,
+}
+
+/// What happened to a malformed message.
+#[derive(Clone, Copy, Debug)]
+pub enum Outcome {
+    /// Answered with `BAD`.
+    Rejected,
+    /// Sent to the other side unmodified.
+    Forwarded,
+    /// Closed the session.
+    Closed,
+}
+
+/// How often each outcome occurred (per service).
+#[derive(Default)]
+pub struct Counters {
+    client: OutcomeCounters,
+    server: OutcomeCounters,
+}
+
+#[derive(Default)]
+struct OutcomeCounters {
+    rejected: AtomicU64,
+    forwarded: AtomicU64,
+    closed: AtomicU64,
+}
+
+impl Counters {
+    /// Returns the updated count.
+    pub fn count(&self, origin: Origin, outcome: Outcome) -> u64 {
+        let counters = match origin {
+            Origin::Client => &self.client,
+            Origin::Server => &self.server,
+        };
+        let counter = match outcome {
+            Outcome::Rejected => &counters.rejected,
+            Outcome::Forwarded => &counters.forwarded,
+            Outcome::Closed => &counters.closed,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+impl Display for Counters {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "commands: {}, responses: {}", self.client, self.server)
+    }
+}
+
+impl Display for OutcomeCounters {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} rejected, {} forwarded, {} closed",
+            self.rejected.load(Ordering::Relaxed),
+            self.forwarded.load(Ordering::Relaxed),
+            self.closed.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Tag of a malformed message (if it starts with a valid one).
+pub fn recover_tag(bytes: &[u8]) -> Option<Tag<'static>> {
+    let end = bytes
+        .iter()
+        .position(|byte| matches!(byte, b' ' | b'\r' | b'\n'))
+        .unwrap_or(bytes.len());
+    let tag = std::str::from_utf8(&bytes[..end]).ok()?;
+
+    Tag::try_from(tag.to_owned()).ok()
+}
+
+/// The malformed message is a tagged `OK`.
+pub fn is_tagged_ok(bytes: &[u8]) -> bool {
+    let mut words = bytes.split(|byte| matches!(byte, b' ' | b'\r' | b'\n'));
+
+    words.next().and_then(recover_tag).is_some()
+        && words
+            .next()
+            .is_some_and(|kind| kind.eq_ignore_ascii_case(b"OK"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recover_tag() {
+        let tests: [(&[u8], Option<&str>); 6] = [
+            (b"A1 FETCH (\r\n", Some("A1")),
+            (b"A1\r\n", Some("A1")),
+            (b"a.b-c BAD\n", Some("a.b-c")),
+            (b"* 1 FETCH (\r\n", None),
+            (b"+ \r\n", None),
+            (b"A{1 NOOP\r\n", None),
+        ];
+
+        for (bytes, expected) in tests {
+            let expected = expected.map(|tag| Tag::try_from(tag).unwrap());
+            assert_eq!(expected, recover_tag(bytes));
+        }
+    }
+
+    #[test]
+    fn test_is_tagged_ok() {
+        assert!(is_tagged_ok(b"A1 OK [ALERT\r\n"));
+        assert!(is_tagged_ok(b"A1 ok\r\n"));
+        assert!(!is_tagged_ok(b"A1 NO [ALERT\r\n"));
+        assert!(!is_tagged_ok(b"* OK [ALERT\r\n"));
+    }
+}
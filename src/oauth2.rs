@@ -0,0 +1,325 @@
+//! Access tokens from an OAuth 2.0 token endpoint (refresh token grant, RFC 6749).
+
+use std::time::Duration;
+
+use imap_next::imap_types::secret::Secret;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::Instant,
+};
+
+use crate::{config::OAuth2, proxy::ProxyError, tls::ServerConnector, util};
+
+/// Maximum length of the token endpoint's response.
+const MAX_RESPONSE_LENGTH: usize = 65536;
+
+#[derive(Debug, Error)]
+pub enum OAuth2Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Timed out waiting for token endpoint")]
+    Timeout,
+    #[error("Unexpected HTTP response from token endpoint")]
+    HttpProtocol,
+    #[error("Token endpoint failed: \"{0}\"")]
+    HttpFailed(String),
+}
+
+/// Access token obtained with a refresh token.
+pub struct Token {
+    pub access_token: Secret<String>,
+    /// `None` when the token endpoint didn't tell.
+    pub expires_at: Option<Instant>,
+    /// Replaces the refresh token (if the token endpoint rotates them).
+    pub refresh_token: Option<Secret<String>>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+    refresh_token: Option<String>,
+}
+
+/// Token endpoint of an authorization server.
+pub struct TokenEndpoint {
+    /// `None` for "http://" URLs.
+    connector: Option<ServerConnector>,
+    host: String,
+    port: u16,
+    /// Value of the `Host` header (with the port, unless it is the scheme's default).
+    authority: String,
+    path: String,
+    client_id: String,
+    client_secret: Option<Secret<String>>,
+    timeout: Duration,
+}
+
+impl TokenEndpoint {
+    pub fn new(oauth2: &OAuth2) -> Result<Self, ProxyError> {
+        let invalid = || ProxyError::InvalidTokenEndpoint(oauth2.token_endpoint.clone());
+
+        let (tls, rest) = match oauth2.token_endpoint.split_once("://") {
+            Some(("https", rest)) => (true, rest),
+            Some(("http", rest)) => (false, rest),
+            _ => return Err(invalid()),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let default_port = if tls { 443 } else { 80 };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| invalid())?)
+            }
+            _ => (authority, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let authority = match (host.contains(':'), port == default_port) {
+            (true, true) => format!("[{host}]"),
+            (true, false) => format!("[{host}]:{port}"),
+            (false, true) => host.to_owned(),
+            (false, false) => format!("{host}:{port}"),
+        };
+
+        let connector = match tls {
+            true => Some(ServerConnector::new(host, &oauth2.tls)?),
+            false => None,
+        };
+        let client_secret = match &oauth2.client_secret {
+            Some(secret) => Some(Secret::new(util::load_secret(secret)?)),
+            None => None,
+        };
+
+        Ok(Self {
+            connector,
+            host: host.to_owned(),
+            port,
+            authority,
+            path: path.to_owned(),
+            client_id: oauth2.client_id.clone(),
+            client_secret,
+            timeout: Duration::from_secs(oauth2.timeout),
+        })
+    }
+
+    /// Obtain a new access token.
+    pub async fn refresh(&self, refresh_token: &str) -> Result<Token, OAuth2Error> {
+        let mut body = format!(
+            "grant_type=refresh_token&refresh_token={}&client_id={}",
+            form_encode(refresh_token),
+            form_encode(&self.client_id),
+        );
+        if let Some(client_secret) = &self.client_secret {
+            body.push_str("&client_secret=");
+            body.push_str(&form_encode(client_secret.declassify()));
+        }
+
+        let start = Instant::now();
+        let response = tokio::time::timeout(self.timeout, self.post(&body))
+            .await
+            .map_err(|_| OAuth2Error::Timeout)??;
+        let response: TokenResponse = serde_json::from_slice(&response)?;
+
+        Ok(Token {
+            access_token: Secret::new(response.access_token),
+            expires_at: response
+                .expires_in
+                .map(|expires_in| start + Duration::from_secs(expires_in)),
+            refresh_token: response.refresh_token.map(Secret::new),
+        })
+    }
+
+    /// Send a form to the token endpoint and return the body of the response.
+    async fn post(&self, body: &str) -> Result<Vec<u8>, OAuth2Error> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+
+        match &self.connector {
+            Some(connector) => {
+                post(
+                    connector.connect(stream).await?,
+                    &self.authority,
+                    &self.path,
+                    body,
+                )
+                .await
+            }
+            None => post(stream, &self.authority, &self.path, body).await,
+        }
+    }
+}
+
+/// HTTP/1.0 keeps the response simple (no chunked encoding, closed after the body).
+async fn post<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    host: &str,
+    path: &str,
+    body: &str,
+) -> Result<Vec<u8>, OAuth2Error> {
+    let request = format!(
+        "POST {path} HTTP/1.0\r\n\
+         Host: {host}\r\n\
+         Accept: application/json\r\n\
+         Content-Type: application/x-www-form-urlencoded\r\n\
+         Content-Length: {}\r\n\
+         \r\n\
+         {body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    let mut response = Vec::new();
+    (&mut stream)
+        .take(MAX_RESPONSE_LENGTH as u64 + 1)
+        .read_to_end(&mut response)
+        .await?;
+    if response.len() > MAX_RESPONSE_LENGTH {
+        return Err(OAuth2Error::HttpProtocol);
+    }
+
+    let Some(end) = response.windows(4).position(|window| window == b"\r\n\r\n") else {
+        return Err(OAuth2Error::HttpProtocol);
+    };
+    let header = String::from_utf8_lossy(&response[..end]);
+    // Unwrap: There is at least one line.
+    let status_line = header.lines().next().unwrap();
+    let mut fields = status_line.splitn(3, ' ');
+    match (fields.next(), fields.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/1.") => {
+            if code.starts_with('2') {
+                Ok(response[end + 4..].to_vec())
+            } else {
+                Err(OAuth2Error::HttpFailed(status_line.to_owned()))
+            }
+        }
+        _ => Err(OAuth2Error::HttpProtocol),
+    }
+}
+
+/// Encode a value of an "application/x-www-form-urlencoded" body.
+fn form_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(char::from(byte));
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_form_encode() {
+        assert_eq!("1%2F0a-b_c.d~", form_encode("1/0a-b_c.d~"));
+        assert_eq!("a%20b%3D%26%C3%A4", form_encode("a b=&ä"));
+    }
+
+    /// POST to a stand-in endpoint that sends `response`.
+    async fn post_to(response: &'static [u8]) -> Result<Vec<u8>, OAuth2Error> {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let mut request = [0; 4096];
+            let _ = server.read(&mut request).await.unwrap();
+            server.write_all(response).await.unwrap();
+        });
+
+        post(&mut client, "example.org", "/token", "a=b").await
+    }
+
+    #[tokio::test]
+    async fn test_post() {
+        let body = post_to(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}").await;
+        assert_eq!(b"{}".as_slice(), body.unwrap());
+
+        let error = post_to(b"HTTP/1.1 400 Bad Request\r\n\r\n{}").await;
+        assert!(
+            matches!(error, Err(OAuth2Error::HttpFailed(line)) if line == "HTTP/1.1 400 Bad Request")
+        );
+
+        let error = post_to(b"{}").await;
+        assert!(matches!(error, Err(OAuth2Error::HttpProtocol)));
+    }
+
+    fn oauth2(token_endpoint: &str) -> OAuth2 {
+        OAuth2 {
+            token_endpoint: token_endpoint.into(),
+            client_id: "proxy".into(),
+            client_secret: None,
+            mechanism: Default::default(),
+            tls: Default::default(),
+            timeout: 5,
+        }
+    }
+
+    #[test]
+    fn test_authority() {
+        let tests = [
+            ("https://example.org/token", "example.org", 443),
+            ("http://127.0.0.1:8080/token", "127.0.0.1:8080", 8080),
+            ("https://[::1]/token", "[::1]", 443),
+            ("http://[::1]:8080/token", "[::1]:8080", 8080),
+        ];
+
+        for (url, authority, port) in tests {
+            let endpoint = TokenEndpoint::new(&oauth2(url)).unwrap();
+            assert_eq!(authority, endpoint.authority);
+            assert_eq!(port, endpoint.port);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"client_id=proxy") {
+                let mut buffer = [0; 4096];
+                let count = stream.read(&mut buffer).await.unwrap();
+                assert_ne!(0, count);
+                request.extend_from_slice(&buffer[..count]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\n\r\n\
+                      {\"access_token\": \"access\", \"expires_in\": 3600, \"refresh_token\": \"new\"}",
+                )
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let endpoint =
+            TokenEndpoint::new(&oauth2(&format!("http://127.0.0.1:{port}/token"))).unwrap();
+        let token = endpoint.refresh("old/token").await.unwrap();
+        assert_eq!("access", token.access_token.declassify());
+        assert!(token.expires_at.is_some());
+        assert_eq!("new", token.refresh_token.unwrap().declassify());
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /token HTTP/1.0\r\n"));
+        assert!(request.contains(&format!("\r\nHost: 127.0.0.1:{port}\r\n")));
+        assert!(request.ends_with(
+            "\r\n\r\ngrant_type=refresh_token&refresh_token=old%2Ftoken&client_id=proxy"
+        ));
+    }
+}
@@ -0,0 +1,219 @@
+//! Selection of servers and passive health tracking.
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::config::Strategy;
+
+pub struct Pool<T> {
+    endpoints: Vec<Endpoint<T>>,
+    strategy: Strategy,
+    /// Mark an endpoint as down after this many consecutive failures.
+    max_failures: u32,
+    /// Try an endpoint that is down again after this duration.
+    cool_down: Duration,
+    /// Next endpoint (round-robin).
+    next: AtomicUsize,
+}
+
+struct Endpoint<T> {
+    value: T,
+    connections: Arc<AtomicUsize>,
+    health: Mutex<Health>,
+}
+
+#[derive(Default)]
+struct Health {
+    /// Consecutive failures.
+    failures: u32,
+    down_until: Option<Instant>,
+}
+
+impl<T> Pool<T> {
+    pub fn new(values: Vec<T>, strategy: Strategy, max_failures: u32, cool_down: Duration) -> Self {
+        let endpoints = values
+            .into_iter()
+            .map(|value| Endpoint {
+                value,
+                connections: Default::default(),
+                health: Default::default(),
+            })
+            .collect();
+
+        Self {
+            endpoints,
+            strategy,
+            max_failures,
+            cool_down,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pool with a single endpoint (that is never marked as down).
+    ///
+    /// Without another endpoint to try instead, its health doesn't matter, e.g., for an SNI
+    /// route or a director backend.
+    pub fn single(value: T) -> Self {
+        Self::new(vec![value], Strategy::Failover, u32::MAX, Duration::ZERO)
+    }
+
+    pub fn get(&self, index: usize) -> &T {
+        &self.endpoints[index].value
+    }
+
+    /// Indices of endpoints in the order they should be tried.
+    ///
+    /// Endpoints that are down come last, so they are tried when all endpoints are down.
+    pub fn candidates(&self, client_ip: Option<IpAddr>) -> Vec<usize> {
+        let count = self.endpoints.len();
+        let rotate = |start: usize| (0..count).map(|offset| (start + offset) % count).collect();
+
+        let mut candidates: Vec<usize> = match self.strategy {
+            Strategy::Failover => (0..count).collect(),
+            Strategy::RoundRobin => rotate(self.next.fetch_add(1, Ordering::Relaxed)),
+            Strategy::LeastConnections => {
+                let mut candidates: Vec<usize> = (0..count).collect();
+                candidates.sort_by_key(|index| {
+                    self.endpoints[*index].connections.load(Ordering::Relaxed)
+                });
+                candidates
+            }
+            Strategy::IpHash => {
+                let mut hasher = DefaultHasher::new();
+                client_ip.hash(&mut hasher);
+                rotate(hasher.finish() as usize)
+            }
+        };
+
+        let now = Instant::now();
+        candidates.sort_by_key(|index| self.is_down(*index, now));
+        candidates
+    }
+
+    fn is_down(&self, index: usize, now: Instant) -> bool {
+        // Unwrap: The lock is never poisoned.
+        let health = self.endpoints[index].health.lock().unwrap();
+        health.down_until.is_some_and(|down_until| now < down_until)
+    }
+
+    /// Count a connection to the endpoint until the lease is dropped.
+    pub fn lease(&self, index: usize) -> Lease {
+        let connections = self.endpoints[index].connections.clone();
+        connections.fetch_add(1, Ordering::Relaxed);
+
+        Lease(connections)
+    }
+
+    pub fn report_success(&self, index: usize) {
+        // Unwrap: The lock is never poisoned.
+        let mut health = self.endpoints[index].health.lock().unwrap();
+        *health = Health::default();
+    }
+
+    /// Returns `true` when the endpoint is (still) marked as down.
+    pub fn report_failure(&self, index: usize) -> bool {
+        // Unwrap: The lock is never poisoned.
+        let mut health = self.endpoints[index].health.lock().unwrap();
+        health.failures = health.failures.saturating_add(1);
+
+        if health.failures >= self.max_failures {
+            health.down_until = Some(Instant::now() + self.cool_down);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Connection to an endpoint (for least-connections).
+pub struct Lease(Arc<AtomicUsize>);
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: Strategy) -> Pool<&'static str> {
+        Pool::new(vec!["a", "b", "c"], strategy, 2, Duration::from_secs(60))
+    }
+
+    #[test]
+    fn test_failover() {
+        let pool = pool(Strategy::Failover);
+        assert_eq!(vec![0, 1, 2], pool.candidates(None));
+
+        // The first endpoint is marked as down after two consecutive failures ...
+        assert!(!pool.report_failure(0));
+        assert_eq!(vec![0, 1, 2], pool.candidates(None));
+        assert!(pool.report_failure(0));
+        assert_eq!(vec![1, 2, 0], pool.candidates(None));
+
+        // ... and is up again after a success.
+        pool.report_success(0);
+        assert!(!pool.report_failure(0));
+        assert_eq!(vec![0, 1, 2], pool.candidates(None));
+    }
+
+    #[test]
+    fn test_cool_down() {
+        let pool = Pool::new(vec!["a", "b"], Strategy::Failover, 1, Duration::ZERO);
+        assert!(pool.report_failure(0));
+        // The cool-down already elapsed.
+        assert_eq!(vec![0, 1], pool.candidates(None));
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let pool = pool(Strategy::RoundRobin);
+        assert_eq!(vec![0, 1, 2], pool.candidates(None));
+        assert_eq!(vec![1, 2, 0], pool.candidates(None));
+        assert_eq!(vec![2, 0, 1], pool.candidates(None));
+        assert_eq!(vec![0, 1, 2], pool.candidates(None));
+
+        pool.report_failure(1);
+        pool.report_failure(1);
+        assert_eq!(vec![2, 0, 1], pool.candidates(None));
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let pool = pool(Strategy::LeastConnections);
+        let first = pool.lease(0);
+        let _second = pool.lease(0);
+        let _third = pool.lease(1);
+        assert_eq!(vec![2, 1, 0], pool.candidates(None));
+
+        drop(first);
+        let _fourth = pool.lease(2);
+        let _fifth = pool.lease(2);
+        assert_eq!(vec![0, 1, 2], pool.candidates(None));
+    }
+
+    #[test]
+    fn test_ip_hash() {
+        let pool = pool(Strategy::IpHash);
+        let ip = Some("192.0.2.1".parse().unwrap());
+        let candidates = pool.candidates(ip);
+        assert_eq!(candidates, pool.candidates(ip));
+
+        // The client moves to the next endpoint while its endpoint is down.
+        let first = candidates[0];
+        pool.report_failure(first);
+        pool.report_failure(first);
+        let mut expected = candidates[1..].to_vec();
+        expected.push(first);
+        assert_eq!(expected, pool.candidates(ip));
+    }
+}
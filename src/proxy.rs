@@ -1,44 +1,88 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
 use colored::Colorize;
 use imap_next::{
-    client::{self, Client},
+    client::{self, Client, CommandHandle},
     imap_types::{
+        auth::{AuthMechanism, AuthenticateData},
         command::{Command, CommandBody},
+        core::{AString, Tag, Text, Vec1},
         extensions::idle::IdleDone,
-        response::{Code, Greeting, Status},
+        response::{
+            Bye, Capability, Code, CodeOther, CommandContinuationRequest, Data, Greeting,
+            GreetingKind, Status, StatusBody, StatusKind, Tagged,
+        },
         ToStatic,
     },
-    server::{self, Server},
-    stream::{self, Stream},
+    server::{self, ResponseHandle, Server},
+    types::CommandAuthenticate,
+    Interrupt, Io, State as _,
 };
-use once_cell::sync::Lazy;
 use thiserror::Error;
-use tokio::net::{TcpListener, TcpStream};
-use tokio_rustls::{
-    rustls::{pki_types::ServerName, ClientConfig, RootCertStore, ServerConfig},
-    TlsAcceptor, TlsConnector,
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    signal::unix::{signal, SignalKind},
+    time::Instant,
 };
-use tracing::{error, info, info_span, trace, Instrument};
+use tokio_rustls::rustls::{client::VerifierBuilderError, pki_types::InvalidDnsNameError};
+use tracing::{error, info, info_span, trace, warn, Instrument, Span};
 
 use crate::{
-    config::{Bind, Connect, Identity, Service},
-    util::{self, IdentityError},
+    auth::{Credentials, Method},
+    config::{
+        self, Bind, BindTls, Blocked, CommandRejected, Connect, Malformed, Service, Timeouts,
+    },
+    director::RoutingTable,
+    malformed::{self, Origin, Outcome},
+    pool::{Lease, Pool},
+    proxy_protocol::{self, Addresses, ProxyProtocolError},
+    sasl::{Step, Translation},
+    scram::{ClientFirst, Exchange, ScramError},
+    shutdown::Shutdown,
+    stream::{self, Socket, Stream},
+    termination::{Rejection, Terminator},
+    throttle::{self, Throttle},
+    tls::{self, Acceptor, ChannelBindings, ReloadableAcceptor, ServerConnector},
+    unix::{self, UnixError},
+    util::{self, CapabilityError, CapabilityFilter, IdentityError},
+    via::{Hop, ViaError},
 };
 
-static ROOT_CERT_STORE: Lazy<RootCertStore> = Lazy::new(|| {
-    let mut root_store = RootCertStore::empty();
-
-    for cert in rustls_native_certs::load_native_certs().unwrap() {
-        root_store.add(cert).unwrap();
-    }
-
-    root_store
-});
+const PROXY_TAG: &str = "proxy";
 
 const LITERAL_ACCEPT_TEXT: &str = "proxy: Literal accepted by proxy";
 const LITERAL_REJECT_TEXT: &str = "proxy: Literal rejected by proxy";
 const COMMAND_REJECTED_TEXT: &str = "proxy: Command rejected by server";
+const STARTTLS_ACCEPT_TEXT: &str = "proxy: Begin TLS negotiation now";
+const STARTTLS_REJECT_TEXT: &str = "proxy: STARTTLS not available";
+const LOGIN_DISABLED_TEXT: &str = "proxy: Use STARTTLS before authentication";
+const DIRECTOR_GREETING_TEXT: &str = "proxy: Ready";
+const DIRECTOR_OK_TEXT: &str = "proxy: Completed";
+const DIRECTOR_LOGOUT_TEXT: &str = "proxy: Logging out";
+const DIRECTOR_UNAUTHENTICATED_TEXT: &str = "proxy: Authenticate first";
+const DIRECTOR_MECHANISM_TEXT: &str = "proxy: Unsupported authentication mechanism";
+const DIRECTOR_CREDENTIALS_TEXT: &str = "proxy: Invalid credentials";
+const DIRECTOR_CANCEL_TEXT: &str = "proxy: Authentication cancelled";
+const INACTIVITY_TEXT: &str = "proxy: Disconnected for inactivity";
+const UNAVAILABLE_TEXT: &str = "proxy: Server unavailable";
+const AUTHENTICATION_PENDING_TEXT: &str = "proxy: Wait for the pending authentication";
+const BLOCKED_TEXT: &str = "proxy: Too many failed authentications, try again later";
+const LOGIN_FAILURES_TEXT: &str = "proxy: Too many failed authentications";
+const SERVER_LOST_TEXT: &str = "proxy: Connection to server lost";
+const OUT_OF_SYNC_TEXT: &str = "proxy: Session out of sync";
+const MALFORMED_COMMAND_TEXT: &str = "proxy: Malformed command";
+const MALFORMED_RESPONSE_TEXT: &str = "proxy: Malformed response from server";
+
+/// How long to wait for a client to receive a `BYE`.
+const BYE_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Error)]
 pub enum ProxyError {
@@ -47,7 +91,59 @@ pub enum ProxyError {
     #[error(transparent)]
     Identity(#[from] IdentityError),
     #[error(transparent)]
+    Capability(#[from] CapabilityError),
+    #[error(transparent)]
     Tls(#[from] tokio_rustls::rustls::Error),
+    #[error(transparent)]
+    InvalidServerName(#[from] InvalidDnsNameError),
+    #[error(transparent)]
+    Verifier(#[from] VerifierBuilderError),
+    #[error(transparent)]
+    Unix(#[from] UnixError),
+    #[error(transparent)]
+    Via(#[from] ViaError),
+    #[error(transparent)]
+    ProxyProtocol(#[from] ProxyProtocolError),
+    #[error(transparent)]
+    ServerStream(#[from] stream::Error<client::Error>),
+    #[error("Unexpected greeting from server: {0:?}")]
+    UnexpectedGreeting(Box<Greeting<'static>>),
+    #[error("Unexpected event from server: {0:?}")]
+    UnexpectedServerEvent(Box<client::Event>),
+    #[error("Unexpected status from server: {0:?}")]
+    UnexpectedStatus(Box<StatusBody<'static>>),
+    #[error("Server rejected STARTTLS: {0:?}")]
+    StartTlsRejected(Box<StatusBody<'static>>),
+    #[error("Failed to resume session after STARTTLS: {0}")]
+    StartTlsResume(String),
+    #[error("Unknown cipher suite \"{0}\"")]
+    UnknownCipherSuite(String),
+    #[error("No TLS version between `min_version` and `max_version`")]
+    NoTlsVersion,
+    #[error("Routes require `encryption = \"Tls\"` in `bind`")]
+    RoutesRequireTls,
+    #[error("`tls` requires `identity` in `bind`")]
+    TlsRequiresIdentity,
+    #[error("Timed out waiting for {0}")]
+    Timeout(&'static str),
+    #[error("Unknown backend \"{0}\"")]
+    UnknownBackend(String),
+    #[error("Invalid server credentials for \"{0}\": {1}")]
+    InvalidUpstreamCredentials(String, &'static str),
+    #[error("Invalid token endpoint \"{0}\"")]
+    InvalidTokenEndpoint(String),
+    #[error("Credentials can't be sent to server")]
+    InvalidCredentials,
+    #[error("Unexpected authentication data from client (tag {0:?})")]
+    UnexpectedAuthenticateData(Option<Tag<'static>>),
+    #[error("Unexpected authentication continuation request from server (tag {0:?})")]
+    UnexpectedAuthenticateContinuation(Option<Tag<'static>>),
+    #[error("Unexpected authentication status from server (tag {0:?})")]
+    UnexpectedAuthenticateStatus(Option<Tag<'static>>),
+    #[error("Unexpected IDLE acceptance from server (tag {0:?})")]
+    UnexpectedIdleAccept(Option<Tag<'static>>),
+    #[error("Unexpected IDLE rejection from server (tag {0:?})")]
+    UnexpectedIdleReject(Option<Tag<'static>>),
 }
 
 pub trait State: Send + 'static {}
@@ -57,260 +153,2684 @@ pub struct Proxy<S: State> {
     state: S,
 }
 
+/// Server names (SNI) and the upstream they select.
+type Route = (Vec<String>, Arc<Pool<Upstream>>);
+
 pub struct BoundState {
-    listener: TcpListener,
+    listener: Listener,
+    acceptor: Option<Arc<ReloadableAcceptor>>,
+    upstream: Arc<Pool<Upstream>>,
+    /// Upstreams selected by server name (SNI).
+    routes: Arc<Vec<Route>>,
+    director: Option<Arc<Director>>,
+    terminator: Option<Arc<Terminator>>,
+    throttle: Option<Arc<Throttle>>,
+    shutdown: Shutdown,
+    malformed: Arc<malformed::Counters>,
+    capabilities: Arc<CapabilityFilter>,
 }
 
 impl State for BoundState {}
 
+enum Listener {
+    Tcp(TcpListener),
+    /// Listener and path of a Unix socket.
+    Unix(UnixListener, String),
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // Clients must not connect to a socket nobody listens on.
+        if let Listener::Unix(_, path) = self {
+            unix::unbind(path);
+        }
+    }
+}
+
+/// Address of a client.
+#[derive(Clone, Debug)]
+pub enum ClientAddr {
+    Tcp(SocketAddr),
+    /// Unix socket clients don't have a (named) address, so we use the socket's path.
+    Unix(String),
+}
+
+impl Display for ClientAddr {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            ClientAddr::Tcp(addr) => write!(f, "{addr}"),
+            ClientAddr::Unix(path) => write!(f, "unix:{path}"),
+        }
+    }
+}
+
+/// Server that clients are proxied to.
+#[derive(Clone)]
+struct Upstream {
+    connect: Connect,
+    connector: Option<ServerConnector>,
+    /// SOCKS5 or HTTP proxy to connect through.
+    hop: Option<Hop>,
+    timeouts: Timeouts,
+}
+
+impl Upstream {
+    fn new(connect: &Connect, timeouts: &Timeouts) -> Result<Self, ProxyError> {
+        let connector = match connect {
+            Connect::Tls { host, tls, .. } | Connect::StartTls { host, tls, .. } => {
+                Some(ServerConnector::new(host, tls)?)
+            }
+            Connect::Unix { tls: Some(tls), .. } => Some(ServerConnector::new("localhost", tls)?),
+            Connect::Insecure { .. } | Connect::Unix { tls: None, .. } => None,
+        };
+
+        let hop = match connect {
+            Connect::Tls { via: Some(via), .. }
+            | Connect::StartTls { via: Some(via), .. }
+            | Connect::Insecure { via: Some(via), .. } => Some(Hop::new(via)?),
+            _ => None,
+        };
+
+        Ok(Self {
+            connect: connect.clone(),
+            connector,
+            hop,
+            timeouts: *timeouts,
+        })
+    }
+
+    /// Connect to the server (and start TLS, if configured) and receive its greeting.
+    async fn connect(
+        &self,
+        addresses: Option<&Addresses>,
+    ) -> Result<(Stream, Greeting<'static>), ProxyError> {
+        let server_addr_port = self.connect.addr_port();
+        let timeouts = &self.timeouts;
+
+        let stream_to_server = with_timeout(timeouts.connect, "connection to server", async {
+            info!(?server_addr_port, "Connecting to server");
+            let mut stream_to_server = match (&self.connect, &self.hop) {
+                (Connect::Unix { path, .. }, _) => Socket::Unix(UnixStream::connect(path).await?),
+                (
+                    Connect::Tls { host, port, .. }
+                    | Connect::StartTls { host, port, .. }
+                    | Connect::Insecure { host, port, .. },
+                    Some(hop),
+                ) => {
+                    info!(via = %hop, "Connecting through proxy");
+                    Socket::Tcp(hop.connect(host, *port).await?)
+                }
+                _ => Socket::Tcp(TcpStream::connect(&server_addr_port).await?),
+            };
+            info!(?server_addr_port, "Connected to server");
+
+            if let Some(version) = self.connect.proxy_protocol() {
+                let header = proxy_protocol::encode_header(version, addresses);
+                stream_to_server.write_all(&header).await?;
+                trace!(role = "p2s", ?version, ?addresses, "Sent PROXY header");
+            }
+
+            Ok::<_, ProxyError>(stream_to_server)
+        })
+        .await?;
+
+        let mut proxy_to_server = match (&self.connect, &self.connector) {
+            (Connect::Tls { .. } | Connect::Unix { .. }, Some(connector)) => {
+                with_timeout(timeouts.connect_tls, "TLS handshake with server", async {
+                    info!(?server_addr_port, "Starting TLS with server");
+                    let stream = connector.connect(stream_to_server).await?;
+
+                    Ok::<_, ProxyError>(Stream::tls(stream.into()))
+                })
+                .await?
+            }
+            _ => Stream::insecure(stream_to_server),
+        };
+
+        let greeting = with_timeout(
+            timeouts.greeting,
+            "greeting from server",
+            receive_greeting(&mut proxy_to_server),
+        )
+        .await?;
+
+        match (&self.connect, &self.connector) {
+            (Connect::StartTls { .. }, Some(connector)) => {
+                with_timeout(
+                    timeouts.connect_tls,
+                    "TLS handshake with server",
+                    start_tls_with_server(proxy_to_server, greeting, connector, &server_addr_port),
+                )
+                .await
+            }
+            _ => Ok((proxy_to_server, greeting)),
+        }
+    }
+}
+
+/// Connect to a server of the pool, trying the next one when a server fails.
+///
+/// Records the selected server in the current span.
+async fn connect_to_pool(
+    pool: &Pool<Upstream>,
+    addresses: Option<&Addresses>,
+) -> Result<(Stream, Greeting<'static>, Lease), ProxyError> {
+    let client_ip = addresses.map(|addresses| addresses.source.ip());
+
+    let mut bye = None;
+    let mut last_error = None;
+    for index in pool.candidates(client_ip) {
+        let upstream = pool.get(index);
+        let server = upstream.connect.addr_port();
+
+        match upstream.connect(addresses).await {
+            Ok((stream, greeting)) if greeting.kind != GreetingKind::Bye => {
+                pool.report_success(index);
+                Span::current().record("server", &server);
+
+                return Ok((stream, greeting, pool.lease(index)));
+            }
+            Ok((stream, greeting)) => {
+                warn!(server, ?greeting, "Server refused connection");
+                bye = Some((index, stream, greeting));
+            }
+            Err(error) => {
+                warn!(server, ?error, "Failed to connect to server");
+                last_error = Some(error);
+            }
+        }
+
+        if pool.report_failure(index) {
+            warn!(server, "Marked server as down");
+        }
+    }
+
+    // No server accepted the connection, so we forward the refusal (if any).
+    if let Some((index, stream, greeting)) = bye {
+        let upstream = pool.get(index);
+        Span::current().record("server", upstream.connect.addr_port());
+
+        return Ok((stream, greeting, pool.lease(index)));
+    }
+
+    // Unwrap: A pool has at least one server.
+    Err(last_error.unwrap())
+}
+
+/// Fail with [`ProxyError::Timeout`] when `future` takes longer than `seconds`.
+async fn with_timeout<T>(
+    seconds: u64,
+    waiting_for: &'static str,
+    future: impl Future<Output = Result<T, ProxyError>>,
+) -> Result<T, ProxyError> {
+    match tokio::time::timeout(Duration::from_secs(seconds), future).await {
+        Ok(result) => result,
+        Err(_) => Err(ProxyError::Timeout(waiting_for)),
+    }
+}
+
+/// Build the pool of servers clients are balanced between.
+fn pool(service: &Service) -> Result<Pool<Upstream>, ProxyError> {
+    let upstream = Upstream::new(&service.connect, &service.timeouts)?;
+
+    let Some(upstreams) = &service.upstreams else {
+        return Ok(Pool::single(upstream));
+    };
+
+    let mut servers = vec![upstream];
+    for connect in &upstreams.servers {
+        servers.push(Upstream::new(connect, &service.timeouts)?);
+    }
+
+    Ok(Pool::new(
+        servers,
+        upstreams.strategy,
+        upstreams.max_failures,
+        Duration::from_secs(upstreams.cool_down),
+    ))
+}
+
+/// Routes clients by username (director mode).
+struct Director {
+    table: RoutingTable,
+    backends: HashMap<String, Pool<Upstream>>,
+    max_login_failures: u32,
+}
+
+impl Director {
+    fn new(director: &config::Director, timeouts: &Timeouts) -> Result<Self, ProxyError> {
+        let table = RoutingTable::new(director)?;
+        let backends = director
+            .backends
+            .iter()
+            .map(|(name, connect)| {
+                let upstream = Upstream::new(connect, timeouts)?;
+                Ok((name.clone(), Pool::single(upstream)))
+            })
+            .collect::<Result<_, ProxyError>>()?;
+
+        Ok(Self {
+            table,
+            backends,
+            max_login_failures: director.max_login_failures,
+        })
+    }
+
+    /// Upstream of a client (by certificate subject) or user, `None` when both are unknown.
+    async fn lookup(&self, subject: Option<&str>, username: &str) -> Option<&Pool<Upstream>> {
+        if let Some(subject) = subject {
+            if let Some(backend) = self.table.lookup_subject(subject) {
+                info!(subject, username, backend, "Routing by client certificate");
+                return self.backends.get(&backend);
+            }
+        }
+
+        let backend = self.table.lookup(username).await?;
+        info!(username, backend, "Routing by username");
+        self.backends.get(&backend)
+    }
+}
+
 impl Proxy<BoundState> {
-    pub async fn bind(service: Service) -> Result<Self, ProxyError> {
+    pub async fn bind(service: Service, shutdown: Shutdown) -> Result<Self, ProxyError> {
+        if !service.routes.is_empty() && !matches!(service.bind, Bind::Tls { .. }) {
+            return Err(ProxyError::RoutesRequireTls);
+        }
+        if let Bind::Unix {
+            identity: None,
+            tls,
+            ..
+        } = &service.bind
+        {
+            if *tls != BindTls::default() {
+                return Err(ProxyError::TlsRequiresIdentity);
+            }
+        }
+
+        let acceptor = match &service.bind {
+            Bind::Tls { identity, tls, .. }
+            | Bind::StartTls { identity, tls, .. }
+            | Bind::Unix {
+                identity: Some(identity),
+                tls,
+                ..
+            } => {
+                let acceptor = Arc::new(ReloadableAcceptor::new(
+                    identity.clone(),
+                    tls.clone(),
+                    service.routes.clone(),
+                    service.sni_fallback,
+                )?);
+                let hangup = signal(SignalKind::hangup())?;
+                tokio::spawn(
+                    tls::watch_identity(Arc::downgrade(&acceptor), hangup).in_current_span(),
+                );
+
+                Some(acceptor)
+            }
+            Bind::Insecure { .. } | Bind::Unix { identity: None, .. } => None,
+        };
+
+        let capabilities = Arc::new(CapabilityFilter::new(&service.capabilities)?);
+        let upstream = Arc::new(pool(&service)?);
+        let routes = service
+            .routes
+            .iter()
+            .map(|route| {
+                let upstream = Pool::single(Upstream::new(&route.connect, &service.timeouts)?);
+                Ok((route.server_names.clone(), Arc::new(upstream)))
+            })
+            .collect::<Result<_, ProxyError>>()?;
+        let director = match &service.director {
+            Some(director) => Some(Arc::new(Director::new(director, &service.timeouts)?)),
+            None => None,
+        };
+        let terminator = match &service.auth_termination {
+            Some(termination) => Some(Arc::new(Terminator::new(termination)?)),
+            None => None,
+        };
+        let throttle = service.brute_force.as_ref().map(|brute_force| {
+            let throttle = Arc::new(Throttle::new(brute_force));
+            if brute_force.state_path.is_some() {
+                tokio::spawn(
+                    throttle::save_periodically(Arc::downgrade(&throttle)).in_current_span(),
+                );
+            }
+
+            throttle
+        });
+
         // Accept arbitrary number of connections.
-        let bind_addr_port = service.bind.addr_port();
-        let listener = TcpListener::bind(&bind_addr_port).await?;
-        info!(?bind_addr_port, "Bound to");
+        let listener = match &service.bind {
+            Bind::Unix {
+                path, mode, owner, ..
+            } => {
+                let listener = unix::bind(path, *mode, owner.as_deref()).await?;
+                info!(path, "Bound to");
+
+                Listener::Unix(listener, path.clone())
+            }
+            _ => {
+                let bind_addr_port = service.bind.addr_port();
+                let listener = TcpListener::bind(&bind_addr_port).await?;
+                info!(?bind_addr_port, "Bound to");
+
+                Listener::Tcp(listener)
+            }
+        };
 
         Ok(Self {
             service,
-            state: BoundState { listener },
+            state: BoundState {
+                listener,
+                acceptor,
+                upstream,
+                routes: Arc::new(routes),
+                director,
+                terminator,
+                throttle,
+                shutdown,
+                malformed: Default::default(),
+                capabilities,
+            },
         })
     }
 
-    pub async fn accept_client(&self) -> Result<Proxy<ClientAcceptedState>, ProxyError> {
-        let (client_to_proxy, client_addr) = self.state.listener.accept().await?;
-        info!(?client_addr, "Accepted client");
+    /// How often the policy for malformed messages was applied.
+    pub fn malformed_counters(&self) -> &malformed::Counters {
+        &self.state.malformed
+    }
 
-        let client_to_proxy = match &self.service.bind {
-            Bind::Tls { identity, .. } => {
-                let config = {
-                    let (certificate_chain, leaf_key) = match identity {
-                        Identity::CertificateChainAndLeafKey {
-                            certificate_chain_path,
-                            leaf_key_path,
-                        } => {
-                            let certificate_chain =
-                                util::load_certificate_chain_pem(certificate_chain_path)?;
-                            let leaf_key = util::load_leaf_key_pem(leaf_key_path)?;
+    /// Save the failed authentications (if they are kept across restarts).
+    pub async fn save_failures(&self) {
+        if let Some(throttle) = &self.state.throttle {
+            throttle.save().await;
+        }
+    }
 
-                            (certificate_chain, leaf_key)
-                        }
-                    };
+    /// Accept a connection (the handshake is done by [`Proxy::handshake`]).
+    ///
+    /// Only waits for the listener, so slow or silent clients never block others.
+    pub async fn accept_client(&self) -> Result<Proxy<IncomingState>, ProxyError> {
+        let (client_to_proxy, client_addr, local_addr) = match &self.state.listener {
+            Listener::Tcp(listener) => {
+                let (client_to_proxy, client_addr) = listener.accept().await?;
+                let local_addr = client_to_proxy.local_addr()?;
 
-                    let mut config = ServerConfig::builder()
-                        .with_no_client_auth()
-                        // Note: The name is misleading. We provide the full chain here.
-                        .with_single_cert(certificate_chain, leaf_key)?;
+                (
+                    Socket::Tcp(client_to_proxy),
+                    ClientAddr::Tcp(client_addr),
+                    Some(local_addr),
+                )
+            }
+            Listener::Unix(listener, path) => {
+                let (client_to_proxy, _) = listener.accept().await?;
 
-                    config.alpn_protocols = vec![b"imap".to_vec()];
+                (
+                    Socket::Unix(client_to_proxy),
+                    ClientAddr::Unix(path.clone()),
+                    None,
+                )
+            }
+        };
+        info!(%client_addr, "Accepted client");
 
-                    config
-                };
+        Ok(Proxy {
+            service: self.service.clone(),
+            state: IncomingState {
+                client_to_proxy,
+                client_addr,
+                local_addr,
+                acceptor: self.state.acceptor.clone(),
+                upstream: self.state.upstream.clone(),
+                routes: self.state.routes.clone(),
+                director: self.state.director.clone(),
+                terminator: self.state.terminator.clone(),
+                throttle: self.state.throttle.clone(),
+                shutdown: self.state.shutdown.clone(),
+                malformed: self.state.malformed.clone(),
+                capabilities: self.state.capabilities.clone(),
+            },
+        })
+    }
+}
+
+/// Connection that was accepted, but not set up yet (PROXY header, TLS).
+pub struct IncomingState {
+    client_to_proxy: Socket,
+    /// Address of the peer (before the PROXY header was read).
+    client_addr: ClientAddr,
+    local_addr: Option<SocketAddr>,
+    acceptor: Option<Arc<ReloadableAcceptor>>,
+    upstream: Arc<Pool<Upstream>>,
+    routes: Arc<Vec<Route>>,
+    director: Option<Arc<Director>>,
+    terminator: Option<Arc<Terminator>>,
+    throttle: Option<Arc<Throttle>>,
+    shutdown: Shutdown,
+    malformed: Arc<malformed::Counters>,
+    capabilities: Arc<CapabilityFilter>,
+}
+
+impl State for IncomingState {}
+
+impl Proxy<IncomingState> {
+    pub fn client_addr(&self) -> &ClientAddr {
+        &self.state.client_addr
+    }
+
+    /// Read the PROXY header and do the TLS handshake (run per client, not by the listener).
+    pub async fn handshake(self) -> Result<Proxy<ClientAcceptedState>, ProxyError> {
+        let mut client_addr = self.state.client_addr;
+        let mut local_addr = self.state.local_addr;
+        let mut client_to_proxy = self.state.client_to_proxy;
+
+        if let (Some(proxy_protocol), ClientAddr::Tcp(peer_addr)) =
+            (self.service.bind.proxy_protocol(), &client_addr)
+        {
+            let peer_ip = peer_addr.ip().to_canonical();
+            let trusted = proxy_protocol
+                .trusted_networks
+                .iter()
+                .any(|network| network.contains(&peer_ip));
+
+            if trusted {
+                let header =
+                    with_timeout(self.service.timeouts.accept_tls, "PROXY header", async {
+                        Ok(proxy_protocol::read_header(&mut client_to_proxy).await?)
+                    })
+                    .await?;
+
+                if let Some(addresses) = header {
+                    info!(proxy_addr = %peer_addr, client_addr = %addresses.source, "Received PROXY header");
+                    client_addr = ClientAddr::Tcp(addresses.source);
+                    local_addr = Some(addresses.destination);
+                }
+            }
+        }
+
+        let mut client_subject = None;
+        let mut channel_bindings = None;
+        let mut upstream = &self.state.upstream;
+        let (client_to_proxy, client_starttls) = match (&self.service.bind, &self.state.acceptor) {
+            (Bind::Tls { .. } | Bind::Unix { .. }, Some(acceptor)) => {
+                let acceptor = acceptor.current();
 
-                // TODO(#146): The acceptor should really be part of the proxy initialization.
-                //             However, for testing purposes, it's nice to create it on-the-fly.
-                let acceptor = TlsAcceptor::from(Arc::new(config));
+                info!(%client_addr, "Starting TLS with client");
+                let client_to_proxy = with_timeout(
+                    self.service.timeouts.accept_tls,
+                    "TLS handshake with client",
+                    async { Ok(acceptor.accept(client_to_proxy).await?) },
+                )
+                .await?;
+                tls::log_negotiated("c2p", client_to_proxy.get_ref().1);
+                client_subject = tls::peer_subject(client_to_proxy.get_ref().1);
+                channel_bindings = Some(acceptor.channel_bindings(client_to_proxy.get_ref().1));
+                if let Some(subject) = &client_subject {
+                    info!(%client_addr, subject, "Verified client certificate");
+                }
+
+                if let Some(server_name) = client_to_proxy.get_ref().1.server_name() {
+                    let route = self.state.routes.iter().find(|(server_names, _)| {
+                        server_names
+                            .iter()
+                            .any(|name| name.eq_ignore_ascii_case(server_name))
+                    });
+
+                    if let Some((_, route_upstream)) = route {
+                        info!(%client_addr, server_name, "Routing by server name");
+                        upstream = route_upstream;
+                    }
+                }
 
-                info!(?client_addr, "Starting TLS with client");
-                Stream::tls(acceptor.accept(client_to_proxy).await?.into())
+                (Stream::tls(client_to_proxy.into()), None)
             }
-            Bind::Insecure { .. } => Stream::insecure(client_to_proxy),
+            (Bind::StartTls { login_disabled, .. }, Some(acceptor)) => {
+                let starttls = StartTls {
+                    acceptor: acceptor.current(),
+                    login_disabled: *login_disabled,
+                };
+
+                (Stream::insecure(client_to_proxy), Some(starttls))
+            }
+            _ => (Stream::insecure(client_to_proxy), None),
         };
 
         Ok(Proxy {
             service: self.service.clone(),
             state: ClientAcceptedState {
                 client_addr,
+                local_addr,
+                client_subject,
+                channel_bindings,
                 client_to_proxy,
+                client_starttls,
+                upstream: upstream.clone(),
+                director: self.state.director.clone(),
+                terminator: self.state.terminator.clone(),
+                throttle: self.state.throttle.clone(),
+                shutdown: self.state.shutdown.clone(),
+                malformed: self.state.malformed.clone(),
+                capabilities: self.state.capabilities.clone(),
             },
         })
     }
 }
 
 pub struct ClientAcceptedState {
-    client_addr: SocketAddr,
+    /// Address of the client (as received via PROXY protocol, if any).
+    client_addr: ClientAddr,
+    /// Address the client connected to (TCP only).
+    local_addr: Option<SocketAddr>,
+    /// Subject of the verified client certificate (when using TLS with client authentication).
+    client_subject: Option<String>,
+    /// `None` when the client doesn't use TLS.
+    channel_bindings: Option<ChannelBindings>,
     client_to_proxy: Stream,
+    client_starttls: Option<StartTls>,
+    upstream: Arc<Pool<Upstream>>,
+    director: Option<Arc<Director>>,
+    terminator: Option<Arc<Terminator>>,
+    throttle: Option<Arc<Throttle>>,
+    shutdown: Shutdown,
+    malformed: Arc<malformed::Counters>,
+    capabilities: Arc<CapabilityFilter>,
+}
+
+/// STARTTLS offered to a client that is not using TLS (yet).
+pub struct StartTls {
+    acceptor: Acceptor,
+    login_disabled: bool,
 }
 
 impl State for ClientAcceptedState {}
 
 impl Proxy<ClientAcceptedState> {
-    pub fn client_addr(&self) -> SocketAddr {
-        self.state.client_addr
+    pub fn client_addr(&self) -> &ClientAddr {
+        &self.state.client_addr
     }
 
-    pub async fn connect_to_server(self) -> Result<Proxy<ConnectedState>, ProxyError> {
-        let server_addr_port = self.service.connect.addr_port();
-        info!(?server_addr_port, "Connecting to server");
-        let stream_to_server = TcpStream::connect(&server_addr_port).await?;
-        info!(?server_addr_port, "Connected to server");
-
-        let proxy_to_server = match self.service.connect {
-            Connect::Tls { ref host, .. } => {
-                let config = {
-                    let mut config = ClientConfig::builder()
-                        .with_root_certificates(ROOT_CERT_STORE.clone())
-                        .with_no_client_auth();
+    pub fn client_subject(&self) -> Option<&str> {
+        self.state.client_subject.as_deref()
+    }
 
-                    // See <https://www.iana.org/assignments/tls-extensiontype-values/tls-extensiontype-values.xhtml#alpn-protocol-ids>
-                    config.alpn_protocols = vec![b"imap".to_vec()];
+    /// IP address of the client (TCP only).
+    fn client_ip(&self) -> Option<IpAddr> {
+        match &self.state.client_addr {
+            ClientAddr::Tcp(addr) => Some(addr.ip().to_canonical()),
+            ClientAddr::Unix(_) => None,
+        }
+    }
 
-                    config
-                };
+    /// Addresses for the PROXY protocol (TCP only).
+    fn addresses(&self) -> Option<Addresses> {
+        match (&self.state.client_addr, self.state.local_addr) {
+            (ClientAddr::Tcp(source), Some(destination)) => Some(Addresses {
+                source: *source,
+                destination,
+            }),
+            _ => None,
+        }
+    }
 
-                let connector = TlsConnector::from(Arc::new(config));
-                let dnsname = ServerName::try_from(host.clone()).unwrap();
+    /// Connect to the server.
+    ///
+    /// In director mode (or with authentication termination), the proxy authenticates the
+    /// client first. Returns `None` when the client left before.
+    pub async fn connect_to_server(self) -> Result<Option<Proxy<ConnectedState>>, ProxyError> {
+        if self.state.director.is_some() || self.state.terminator.is_some() {
+            return self.direct().await;
+        }
 
-                info!(?server_addr_port, "Starting TLS with server");
-                Stream::tls(connector.connect(dnsname, stream_to_server).await?.into())
+        let result = connect_to_pool(&self.state.upstream, self.addresses().as_ref()).await;
+        let client_ip = self.client_ip();
+        let (proxy_to_server, greeting, lease) = match result {
+            Ok(result) => result,
+            Err(error) => {
+                refuse(self.state.client_to_proxy).await;
+                return Err(error);
             }
-            Connect::Insecure { .. } => Stream::insecure(stream_to_server),
         };
 
-        Ok(Proxy {
+        Ok(Some(Proxy {
             service: self.service,
             state: ConnectedState {
                 client_to_proxy: self.state.client_to_proxy,
+                client_starttls: self.state.client_starttls,
                 proxy_to_server,
+                start: Start::Greeting(greeting),
+                shutdown: self.state.shutdown,
+                malformed: self.state.malformed,
+                capabilities: self.state.capabilities,
+                throttle: self.state.throttle,
+                client_ip,
+                _lease: lease,
             },
-        })
+        }))
     }
-}
-
-pub struct ConnectedState {
-    client_to_proxy: Stream,
-    proxy_to_server: Stream,
-}
-
-impl State for ConnectedState {}
-
-impl Proxy<ConnectedState> {
-    pub async fn start_conversation(self) {
-        let client_span = info_span!("proxy", with = "client");
-        let server_span = info_span!("proxy", with = "server");
 
-        let mut proxy_to_server = {
-            let mut options = client::Options::default();
-            options.crlf_relaxed = true;
-            Client::new(options)
-        };
-        let mut proxy_to_server_stream = self.state.proxy_to_server;
-        let stream_event = proxy_to_server_stream
-            .next(&mut proxy_to_server)
-            .instrument(server_span.clone())
-            .await;
-        let Some(server_event) = handle_stream_event("s2p", stream_event) else {
-            return;
-        };
-        let Some(mut greeting) = handle_initial_server_event(server_event) else {
-            return;
-        };
+    /// Handle the not authenticated state until the client authenticated with its server.
+    async fn direct(self) -> Result<Option<Proxy<ConnectedState>>, ProxyError> {
+        let client_ip = self.client_ip();
+        let addresses = self.addresses();
+        let timeouts = self.service.timeouts;
+        let mut shutdown = self.state.shutdown;
+        let max_login_failures = self
+            .state
+            .director
+            .as_ref()
+            .map_or(u32::MAX, |director| director.max_login_failures);
 
-        util::filter_capabilities_in_greeting(&mut greeting);
+        let mut greeting = Greeting::ok(
+            Some(Code::Capability(director_capabilities(
+                self.state.terminator.as_deref(),
+                self.state.channel_bindings.is_some(),
+            ))),
+            DIRECTOR_GREETING_TEXT,
+        )
+        .unwrap();
+        if let Some(starttls) = &self.state.client_starttls {
+            util::advertise_starttls_in_greeting(&mut greeting, starttls.login_disabled);
+        }
 
-        let mut client_to_proxy = {
-            let mut options = server::Options::default();
-            options.crlf_relaxed = true;
-            options
-                .set_literal_accept_text(LITERAL_ACCEPT_TEXT.to_string())
-                .unwrap();
-            options
-                .set_literal_reject_text(LITERAL_REJECT_TEXT.to_string())
-                .unwrap();
-            Server::new(options, greeting)
+        let mut session = NotAuthenticated {
+            client_to_proxy_stream: self.state.client_to_proxy,
+            client_to_proxy: Server::new(server_options(), greeting),
+            client_starttls: self.state.client_starttls,
+            client_subject: self.state.client_subject,
+            channel_bindings: self.state.channel_bindings,
+            client_ip,
+            addresses,
+            terminator: self.state.terminator,
+            throttle: self.state.throttle.clone(),
+            capabilities: self.state.capabilities.clone(),
+            pending_authenticate: None,
+            login_failures: 0,
         };
-        let mut client_to_proxy_stream = self.state.client_to_proxy;
 
         loop {
-            tokio::select! {
-                stream_event = client_to_proxy_stream
-                    .next(&mut client_to_proxy)
-                    .instrument(client_span.clone()) =>
-                {
-                    let Some(client_event) = handle_stream_event("c2p", stream_event) else {
-                        break;
-                    };
-                    handle_client_event(client_event, &mut proxy_to_server)
+            if session.login_failures >= max_login_failures {
+                info!(
+                    role = "p2c",
+                    login_failures = session.login_failures,
+                    "Closing session after failed authentications"
+                );
+                session
+                    .close(Status::bye(None, LOGIN_FAILURES_TEXT).unwrap())
+                    .await;
+                return Ok(None);
+            }
+
+            let stream_event = tokio::select! {
+                stream_event = tokio::time::timeout(
+                    Duration::from_secs(timeouts.unauthenticated),
+                    session.client_to_proxy_stream.next(&mut session.client_to_proxy),
+                ) => stream_event,
+                _ = shutdown.started() => {
+                    info!(role = "p2c", "Closing session for shutdown");
+                    session.close(shutdown.bye()).await;
+                    return Ok(None);
                 }
-                stream_event = proxy_to_server_stream
-                    .next(&mut proxy_to_server)
-                    .instrument(server_span.clone()) =>
-                {
-                    let Some(server_event) = handle_stream_event("s2p", stream_event) else {
-                        break;
+            };
+            let Ok(stream_event) = stream_event else {
+                info!(role = "p2c", "Logging out inactive client");
+                session
+                    .close(Status::bye(None, INACTIVITY_TEXT).unwrap())
+                    .await;
+                return Ok(None);
+            };
+            let Some(client_event) = handle_stream_event("c2p", stream_event) else {
+                return Ok(None);
+            };
+            let event = match client_event {
+                Ok(event) => event,
+                Err(error) => {
+                    // There is no server to forward the command to yet.
+                    let policy = match self.service.malformed {
+                        Malformed::Forward => Malformed::Reject,
+                        policy => policy,
                     };
-                    handle_server_event(server_event, &mut client_to_proxy)
+                    let action = handle_malformed_command(
+                        error,
+                        policy,
+                        &self.state.malformed,
+                        &mut session.client_to_proxy,
+                        &mut Activity::default(),
+                    );
+                    if let MalformedAction::Close(text) = action {
+                        session.close(Status::bye(None, text).unwrap()).await;
+                        return Ok(None);
+                    }
+                    continue;
+                }
+            };
+
+            let directed = match event {
+                server::Event::GreetingSent { greeting } => {
+                    trace!(role = "p2c", ?greeting, "<---");
+                    continue;
+                }
+                server::Event::ResponseSent { handle, .. } => {
+                    trace!(role = "p2c", ?handle, "<---");
+                    continue;
                 }
+                server::Event::CommandReceived { command } => {
+                    trace!(role = "c2p", command=%format!("{:?}", command).red(), "|-->");
+
+                    match command.body {
+                        CommandBody::StartTLS if session.client_starttls.is_some() => {
+                            match session.start_tls(command.tag, timeouts.accept_tls).await {
+                                Some(upgraded) => session = upgraded,
+                                None => return Ok(None),
+                            }
+                            continue;
+                        }
+                        body => session.handle_command(command.tag, body).await,
+                    }
+                }
+                server::Event::CommandAuthenticateReceived {
+                    command_authenticate,
+                } => session.handle_authenticate(command_authenticate).await,
+                server::Event::AuthenticateDataReceived { authenticate_data } => {
+                    session.handle_authenticate_data(authenticate_data).await
+                }
+                server::Event::IdleCommandReceived { tag } => {
+                    let status =
+                        Status::bad(Some(tag), None, DIRECTOR_UNAUTHENTICATED_TEXT).unwrap();
+                    if session.client_to_proxy.idle_reject(status).is_err() {
+                        error!(role = "p2c", "Failed to reject IDLE");
+                    }
+                    continue;
+                }
+                server::Event::IdleDoneReceived => {
+                    error!(role = "c2p", "Unexpected DONE");
+                    continue;
+                }
+            };
+            let (tag, presented, method) = match directed {
+                Directed::Continue => continue,
+                Directed::Presented(tag, presented, method) => (tag, presented, method),
+                Directed::Closed => return Ok(None),
+            };
+
+            let username = presented.username().to_owned();
+            let verified = session
+                .verify(tag.clone(), &username, presented, method)
+                .await;
+            let (server_credentials, server_method) = match verified {
+                Verified::Mapped(server_credentials, server_method) => {
+                    (server_credentials, server_method)
+                }
+                Verified::Answered => continue,
+                Verified::Closed => return Ok(None),
+            };
+
+            let upstream = route(
+                self.state.director.as_deref(),
+                &self.state.upstream,
+                session.client_subject.as_deref(),
+                &username,
+            )
+            .await;
+
+            let connected = session
+                .login(
+                    upstream,
+                    tag,
+                    method,
+                    &username,
+                    &server_credentials,
+                    server_method,
+                )
+                .await;
+            // Try again with the next credentials (which might select another server).
+            let Some((proxy_to_server_stream, proxy_to_server, lease)) = connected else {
+                continue;
             };
+
+            return Ok(Some(Proxy {
+                service: self.service,
+                state: ConnectedState {
+                    client_to_proxy: session.client_to_proxy_stream,
+                    client_starttls: None,
+                    proxy_to_server: proxy_to_server_stream,
+                    start: Start::Session(Box::new(Session {
+                        client_to_proxy: session.client_to_proxy,
+                        proxy_to_server,
+                    })),
+                    shutdown,
+                    malformed: self.state.malformed,
+                    capabilities: self.state.capabilities,
+                    throttle: self.state.throttle,
+                    client_ip,
+                    _lease: lease,
+                },
+            }));
         }
     }
 }
 
-fn handle_stream_event<T, E>(
-    role: &'static str,
-    stream_event: Result<T, stream::Error<E>>,
-) -> Option<Result<T, E>> {
-    match stream_event {
-        Ok(event) => Some(Ok(event)),
-        Err(stream::Error::Closed) => {
-            info!(role, "Connection closed");
-            None
-        }
-        Err(stream::Error::Io(error)) => {
-            error!(role, %error, "Connection terminated");
-            None
-        }
-        Err(stream::Error::Tls(error)) => {
-            error!(role, %error, "Connection terminated");
-            None
+/// Select the server of an authenticated client.
+///
+/// In director mode, clients are routed by certificate subject or username (and unknown users
+/// to the default server).
+async fn route<'a>(
+    director: Option<&'a Director>,
+    default: &'a Pool<Upstream>,
+    subject: Option<&str>,
+    username: &str,
+) -> &'a Pool<Upstream> {
+    let Some(director) = director else {
+        return default;
+    };
+
+    match director.lookup(subject, username).await {
+        Some(upstream) => upstream,
+        None => {
+            info!(username, "Routing unknown user to default server");
+            default
         }
-        Err(stream::Error::State(error)) => Some(Err(error)),
     }
 }
 
-fn handle_initial_server_event(
-    server_event: Result<client::Event, client::Error>,
-) -> Option<Greeting<'static>> {
-    match server_event {
-        Ok(client::Event::GreetingReceived { greeting }) => {
-            trace!(role = "s2p", greeting=%format!("{:?}", greeting).blue(), "<--|");
-            Some(greeting)
-        }
-        Ok(event) => {
-            error!(role = "s2p", ?event, "Unexpected event instead of greeting");
-            None
-        }
-        Err(error) => {
-            error!(role = "s2p", ?error, "Failed to receive greeting");
-            None
-        }
-    }
+/// A client in the not authenticated state, handled by the director (or terminator).
+struct NotAuthenticated {
+    client_to_proxy_stream: Stream,
+    client_to_proxy: Server,
+    client_starttls: Option<StartTls>,
+    client_subject: Option<String>,
+    channel_bindings: Option<ChannelBindings>,
+    client_ip: Option<IpAddr>,
+    addresses: Option<Addresses>,
+    terminator: Option<Arc<Terminator>>,
+    throttle: Option<Arc<Throttle>>,
+    capabilities: Arc<CapabilityFilter>,
+    /// AUTHENTICATE that waits for the client's response.
+    pending_authenticate: Option<(Tag<'static>, Pending)>,
+    /// Rejected credentials (the session is closed after `max_login_failures`).
+    login_failures: u32,
 }
 
-fn handle_client_event(
-    client_event: Result<server::Event, server::Error>,
-    proxy_to_server: &mut Client,
-) {
-    let event = match client_event {
-        Ok(event) => event,
-        Err(
-            ref error @ (server::Error::ExpectedCrlfGotLf {
-                ref discarded_bytes,
-            }
-            | server::Error::MalformedMessage {
-                ref discarded_bytes,
-            }
-            | server::Error::LiteralTooLong {
-                ref discarded_bytes,
+/// What the director does after handling a client's message.
+enum Directed {
+    /// Wait for the client's next message.
+    Continue,
+    /// The client presented credentials (for LOGIN or AUTHENTICATE with this tag).
+    Presented(Tag<'static>, Presented, Method),
+    /// The session was closed.
+    Closed,
+}
+
+/// Outcome of verifying the credentials a client presented.
+enum Verified {
+    /// Credentials (and method) for the server.
+    Mapped(Credentials, Method),
+    /// The client was answered (and may try again).
+    Answered,
+    /// The session was closed.
+    Closed,
+}
+
+impl NotAuthenticated {
+    async fn close(&mut self, bye: Status<'static>) {
+        send_bye(
+            &mut self.client_to_proxy_stream,
+            &mut self.client_to_proxy,
+            bye,
+        )
+        .await;
+    }
+
+    /// Handle a command (except STARTTLS while it is offered).
+    async fn handle_command(&mut self, tag: Tag<'static>, body: CommandBody<'static>) -> Directed {
+        match body {
+            CommandBody::Capability | CommandBody::Id { .. } | CommandBody::Noop => {
+                self.answer(tag, &body);
+                Directed::Continue
+            }
+            CommandBody::Login { username, password } => {
+                self.handle_login(tag, &username, password.declassify())
+                    .await
+            }
+            CommandBody::Logout => {
+                let bye = Status::bye(None, DIRECTOR_LOGOUT_TEXT).unwrap();
+                self.client_to_proxy.enqueue_status(bye);
+                let handle = enqueue_ok(&mut self.client_to_proxy, tag);
+                flush_until(
+                    &mut self.client_to_proxy_stream,
+                    &mut self.client_to_proxy,
+                    handle,
+                )
+                .await;
+                Directed::Closed
+            }
+            CommandBody::StartTLS => {
+                let status = Status::bad(Some(tag), None, STARTTLS_REJECT_TEXT).unwrap();
+                self.client_to_proxy.enqueue_status(status);
+                Directed::Continue
+            }
+            _ => {
+                let status = Status::bad(Some(tag), None, DIRECTOR_UNAUTHENTICATED_TEXT).unwrap();
+                self.client_to_proxy.enqueue_status(status);
+                Directed::Continue
+            }
+        }
+    }
+
+    /// Answer CAPABILITY, ID, or NOOP (without a server).
+    fn answer(&mut self, tag: Tag<'static>, body: &CommandBody) {
+        match body {
+            CommandBody::Capability => {
+                let mut data = Data::Capability(director_capabilities(
+                    self.terminator.as_deref(),
+                    self.channel_bindings.is_some(),
+                ));
+                if let Some(starttls) = &self.client_starttls {
+                    util::advertise_starttls_in_data(&mut data, starttls.login_disabled);
+                }
+                self.client_to_proxy.enqueue_data(data);
+            }
+            CommandBody::Id { .. } => {
+                self.client_to_proxy
+                    .enqueue_data(Data::Id { parameters: None });
+            }
+            // NOOP
+            _ => {}
+        }
+        enqueue_ok(&mut self.client_to_proxy, tag);
+    }
+
+    async fn handle_login(
+        &mut self,
+        tag: Tag<'static>,
+        username: &AString<'_>,
+        password: &AString<'_>,
+    ) -> Directed {
+        if is_login_disabled(self.client_starttls.as_ref()) {
+            self.client_to_proxy
+                .enqueue_status(login_disabled_status(tag));
+            return Directed::Continue;
+        }
+
+        match Credentials::from_login(username, password) {
+            Some(credentials) => {
+                Directed::Presented(tag, Presented::Password(credentials), Method::Login)
+            }
+            None => {
+                // Malformed credentials count, too (without username).
+                delay_failure(self.throttle.as_deref(), self.client_ip, None).await;
+                self.client_to_proxy.enqueue_status(credentials_status(tag));
+                self.login_failures += 1;
+                Directed::Continue
+            }
+        }
+    }
+
+    async fn handle_authenticate(&mut self, command_authenticate: CommandAuthenticate) -> Directed {
+        let CommandAuthenticate {
+            tag,
+            mechanism,
+            initial_response,
+        } = command_authenticate;
+        trace!(role = "c2p", ?tag, ?mechanism, "|--> AUTHENTICATE");
+
+        if is_login_disabled(self.client_starttls.as_ref()) {
+            authenticate_finish(&mut self.client_to_proxy, login_disabled_status(tag));
+            return Directed::Continue;
+        }
+        // The username isn't known before the exchange (except with SASL-IR).
+        if let Some(throttle) = self
+            .throttle
+            .as_deref()
+            .filter(|throttle| throttle.is_blocked(self.client_ip, None))
+        {
+            let refused = refuse_blocked(
+                throttle,
+                &mut self.client_to_proxy_stream,
+                &mut self.client_to_proxy,
+                tag,
+                true,
+            )
+            .await;
+            return if refused {
+                Directed::Continue
+            } else {
+                Directed::Closed
+            };
+        }
+
+        let pending = match mechanism {
+            AuthMechanism::Plain => Pending::Plain {
+                initial_response: initial_response.is_some(),
+            },
+            mechanism => {
+                let scram = self.terminator.as_ref().filter(|terminator| {
+                    terminator
+                        .scram_mechanisms(self.channel_bindings.is_some())
+                        .contains(&mechanism)
+                });
+                match scram {
+                    Some(terminator) => Pending::ScramFirst(mechanism, terminator.clone()),
+                    None => {
+                        let status = Status::no(Some(tag), None, DIRECTOR_MECHANISM_TEXT).unwrap();
+                        authenticate_finish(&mut self.client_to_proxy, status);
+                        return Directed::Continue;
+                    }
+                }
+            }
+        };
+
+        match initial_response {
+            Some(initial_response) => {
+                self.exchange(tag, pending, initial_response.declassify())
+                    .await
+            }
+            None => {
+                authenticate_continue(&mut self.client_to_proxy, vec![]);
+                self.pending_authenticate = Some((tag, pending));
+                Directed::Continue
+            }
+        }
+    }
+
+    async fn handle_authenticate_data(
+        &mut self,
+        authenticate_data: AuthenticateData<'static>,
+    ) -> Directed {
+        trace!(role = "c2p", ?authenticate_data, "|-->");
+
+        let Some((tag, pending)) = self.pending_authenticate.take() else {
+            error!(role = "c2p", "Unexpected authentication data");
+            return Directed::Continue;
+        };
+
+        let AuthenticateData::Continue(message) = authenticate_data else {
+            let status = Status::bad(Some(tag), None, DIRECTOR_CANCEL_TEXT).unwrap();
+            authenticate_finish(&mut self.client_to_proxy, status);
+            return Directed::Continue;
+        };
+
+        self.exchange(tag, pending, message.declassify()).await
+    }
+
+    /// Process the client's message of an AUTHENTICATE.
+    async fn exchange(&mut self, tag: Tag<'static>, pending: Pending, message: &[u8]) -> Directed {
+        match exchange(pending, message, self.channel_bindings.as_ref()).await {
+            Exchanged::Challenge(pending, challenge) => {
+                authenticate_continue(&mut self.client_to_proxy, challenge);
+                self.pending_authenticate = Some((tag, pending));
+                Directed::Continue
+            }
+            Exchanged::Presented(presented, method) => Directed::Presented(tag, presented, method),
+            Exchanged::Rejected(username) => {
+                // Malformed credentials count, too (without username).
+                delay_failure(
+                    self.throttle.as_deref(),
+                    self.client_ip,
+                    username.as_deref(),
+                )
+                .await;
+                authenticate_finish(&mut self.client_to_proxy, credentials_status(tag));
+                self.login_failures += 1;
+                Directed::Continue
+            }
+        }
+    }
+
+    /// Upgrade the client connection via STARTTLS (which must be offered).
+    ///
+    /// Returns `None` when the session was closed.
+    async fn start_tls(mut self, tag: Tag<'static>, timeout: u64) -> Option<Self> {
+        // Unwrap: STARTTLS is only started when it is offered.
+        let starttls = self.client_starttls.take().unwrap();
+
+        let (stream, server, subject, bindings) = start_tls_with_client(
+            self.client_to_proxy_stream,
+            self.client_to_proxy,
+            tag,
+            &starttls.acceptor,
+            timeout,
+        )
+        .await?;
+
+        if let Some(subject) = &subject {
+            Span::current().record("subject", subject);
+        }
+        Some(Self {
+            client_to_proxy_stream: stream,
+            client_to_proxy: server,
+            client_subject: subject,
+            channel_bindings: Some(bindings),
+            ..self
+        })
+    }
+
+    /// Verify the client (if not done yet) and map the credentials for the server.
+    async fn verify(
+        &mut self,
+        tag: Tag<'static>,
+        username: &str,
+        presented: Presented,
+        method: Method,
+    ) -> Verified {
+        if let Some(throttle) = self
+            .throttle
+            .as_deref()
+            .filter(|throttle| throttle.is_blocked(self.client_ip, Some(username)))
+        {
+            let refused = refuse_blocked(
+                throttle,
+                &mut self.client_to_proxy_stream,
+                &mut self.client_to_proxy,
+                tag,
+                !matches!(method, Method::Login),
+            )
+            .await;
+            return if refused {
+                Verified::Answered
+            } else {
+                Verified::Closed
+            };
+        }
+
+        let verified = match (presented, self.terminator.as_deref()) {
+            (Presented::Password(credentials), Some(terminator)) => {
+                terminator.authenticate(&credentials).await
+            }
+            (Presented::Scram(username, terminator), _) => terminator.map(&username).await,
+            // Without authentication termination, the server verifies the credentials.
+            (Presented::Password(credentials), None) => Ok((credentials, method)),
+        };
+        match verified {
+            Ok((credentials, method)) => {
+                if self.terminator.is_some() {
+                    info!(username, "Authenticated by proxy");
+                }
+                Verified::Mapped(credentials, method)
+            }
+            Err(rejection @ (Rejection::Unmapped | Rejection::Token(_))) => {
+                error!(username, %rejection, "Failed to map credentials");
+                let status =
+                    Status::no(Some(tag), Some(unavailable_code()), UNAVAILABLE_TEXT).unwrap();
+                respond(&mut self.client_to_proxy, method, status);
+                Verified::Answered
+            }
+            Err(rejection) => {
+                info!(username, %rejection, "Rejected credentials");
+                delay_failure(self.throttle.as_deref(), self.client_ip, Some(username)).await;
+                respond(&mut self.client_to_proxy, method, credentials_status(tag));
+                self.login_failures += 1;
+                Verified::Answered
+            }
+        }
+    }
+
+    /// Authenticate with the server and forward its answer to the client.
+    ///
+    /// Returns the connection to the server when the client is authenticated.
+    async fn login(
+        &mut self,
+        upstream: &Pool<Upstream>,
+        tag: Tag<'static>,
+        method: Method,
+        username: &str,
+        server_credentials: &Credentials,
+        server_method: Method,
+    ) -> Option<(Stream, Client, Lease)> {
+        let result = login_to_server(
+            upstream,
+            self.addresses.as_ref(),
+            &mut self.client_to_proxy,
+            tag.clone(),
+            server_credentials,
+            server_method,
+            &self.capabilities,
+        )
+        .await;
+        let (proxy_to_server_stream, proxy_to_server, mut status, lease) = match result {
+            Ok(result) => result,
+            Err(error) => {
+                error!(?error, "Failed to authenticate with server");
+                let status =
+                    Status::no(Some(tag), Some(unavailable_code()), UNAVAILABLE_TEXT).unwrap();
+                respond(&mut self.client_to_proxy, method, status);
+                return None;
+            }
+        };
+
+        util::filter_capabilities_in_status(&mut status, &self.capabilities);
+        let authenticated = matches!(
+            &status,
+            Status::Tagged(Tagged {
+                body: StatusBody {
+                    kind: StatusKind::Ok,
+                    ..
+                },
+                ..
+            })
+        );
+        if let (false, Some(terminator)) = (authenticated, &self.terminator) {
+            // The client's credentials were fine, but the server's aren't.
+            terminator.report_rejected(username).await;
+            error!(username, ?status, "Server rejected mapped credentials");
+            status = Status::no(Some(tag), Some(unavailable_code()), UNAVAILABLE_TEXT).unwrap();
+        }
+        match (authenticated, &self.throttle) {
+            (true, Some(throttle)) => throttle.succeeded(username),
+            // Without authentication termination, the server verified the credentials.
+            (false, _) if self.terminator.is_none() => {
+                delay_failure(self.throttle.as_deref(), self.client_ip, Some(username)).await;
+            }
+            _ => {}
+        }
+        respond(&mut self.client_to_proxy, method, status);
+
+        if !authenticated {
+            // Mapped credentials that the server rejected aren't the client's fault.
+            if self.terminator.is_none() {
+                self.login_failures += 1;
+            }
+            return None;
+        }
+
+        Some((proxy_to_server_stream, proxy_to_server, lease))
+    }
+}
+
+/// Capabilities announced by the director before authentication.
+fn director_capabilities(
+    terminator: Option<&Terminator>,
+    channel_binding: bool,
+) -> Vec1<Capability<'static>> {
+    let mut capabilities = vec![
+        Capability::Imap4Rev1,
+        Capability::Auth(AuthMechanism::Plain),
+    ];
+    if let Some(terminator) = terminator {
+        capabilities.extend(
+            terminator
+                .scram_mechanisms(channel_binding)
+                .into_iter()
+                .map(Capability::Auth),
+        );
+    }
+    capabilities.extend([Capability::SaslIr, Capability::Id]);
+
+    // Unwrap: The list is not empty.
+    Vec1::try_from(capabilities).unwrap()
+}
+
+/// What a client presented to the director.
+enum Presented {
+    Password(Credentials),
+    /// Username that was verified by the proxy via SCRAM (and the terminator that verified it).
+    Scram(String, Arc<Terminator>),
+}
+
+impl Presented {
+    fn username(&self) -> &str {
+        match self {
+            Self::Password(credentials) => &credentials.username,
+            Self::Scram(username, _) => username,
+        }
+    }
+}
+
+/// AUTHENTICATE of the director that waits for the client's response.
+enum Pending {
+    Plain {
+        initial_response: bool,
+    },
+    /// SCRAM's client-first-message (SCRAM is only offered with authentication termination).
+    ScramFirst(AuthMechanism<'static>, Arc<Terminator>),
+    /// SCRAM's client-final-message.
+    ScramFinal(Box<Exchange>, Arc<Terminator>),
+    /// Empty response after SCRAM's server-final-message.
+    ScramDone(String, Arc<Terminator>),
+}
+
+enum Exchanged {
+    /// Send the challenge and wait for the client's next response.
+    Challenge(Pending, Vec<u8>),
+    Presented(Presented, Method),
+    /// Includes the username when the client failed to prove knowing the password.
+    Rejected(Option<String>),
+}
+
+/// Process the client's response to an AUTHENTICATE of the director.
+async fn exchange(
+    pending: Pending,
+    message: &[u8],
+    channel_bindings: Option<&ChannelBindings>,
+) -> Exchanged {
+    let result = match pending {
+        Pending::Plain { initial_response } => {
+            return match Credentials::from_plain(message) {
+                Some(credentials) => Exchanged::Presented(
+                    Presented::Password(credentials),
+                    Method::AuthenticatePlain { initial_response },
+                ),
+                None => Exchanged::Rejected(None),
+            };
+        }
+        Pending::ScramFirst(mechanism, terminator) => {
+            let channel_bindings = channel_bindings.filter(|_| terminator.binds_channel());
+
+            match ClientFirst::parse(&mechanism, message, channel_bindings) {
+                Ok(client_first) => {
+                    let verifier = terminator
+                        .scram_verifier(client_first.username(), client_first.hash())
+                        .await;
+                    let (exchange, server_first) = client_first.challenge(verifier);
+                    Ok(Exchanged::Challenge(
+                        Pending::ScramFinal(Box::new(exchange), terminator),
+                        server_first,
+                    ))
+                }
+                Err(error) => Err(error),
+            }
+        }
+        Pending::ScramFinal(exchange, terminator) => {
+            let username = exchange.username().to_owned();
+            match exchange.finish(message) {
+                Ok((username, server_final)) => Ok(Exchanged::Challenge(
+                    Pending::ScramDone(username, terminator),
+                    server_final,
+                )),
+                Err(error) => {
+                    info!(%error, "Rejected SCRAM authentication");
+                    return Exchanged::Rejected(Some(username));
+                }
+            }
+        }
+        Pending::ScramDone(username, terminator) if message.is_empty() => Ok(Exchanged::Presented(
+            Presented::Scram(username, terminator),
+            Method::AuthenticateScram,
+        )),
+        Pending::ScramDone(..) => Err(ScramError::Malformed),
+    };
+
+    result.unwrap_or_else(|error| {
+        info!(%error, "Rejected SCRAM authentication");
+        Exchanged::Rejected(None)
+    })
+}
+
+fn authenticate_continue(client_to_proxy: &mut Server, challenge: Vec<u8>) {
+    let continuation = CommandContinuationRequest::base64(challenge);
+    if client_to_proxy.authenticate_continue(continuation).is_err() {
+        error!(role = "p2c", "Failed to continue authentication");
+    }
+}
+
+fn enqueue_ok(client_to_proxy: &mut Server, tag: Tag<'static>) -> ResponseHandle {
+    let status = Status::ok(Some(tag), None, DIRECTOR_OK_TEXT).unwrap();
+    client_to_proxy.enqueue_status(status)
+}
+
+fn credentials_status(tag: Tag<'static>) -> Status<'static> {
+    Status::no(
+        Some(tag),
+        Some(Code::Other(CodeOther::unvalidated(
+            b"AUTHENTICATIONFAILED".as_slice(),
+        ))),
+        DIRECTOR_CREDENTIALS_TEXT,
+    )
+    .unwrap()
+}
+
+fn authenticate_finish(client_to_proxy: &mut Server, status: Status<'static>) {
+    if let Err(status) = client_to_proxy.authenticate_finish(status) {
+        error!(role = "p2c", ?status, "Failed to finish authentication");
+    }
+}
+
+/// Answer a LOGIN or AUTHENTICATE of a blocked IP address or username.
+///
+/// Returns `false` when the session was closed.
+async fn refuse_blocked(
+    throttle: &Throttle,
+    client_to_proxy_stream: &mut Stream,
+    client_to_proxy: &mut Server,
+    tag: Tag<'static>,
+    authenticate: bool,
+) -> bool {
+    if reject_blocked(throttle, client_to_proxy, tag, authenticate) {
+        return true;
+    }
+
+    close_blocked(client_to_proxy_stream, client_to_proxy).await;
+    false
+}
+
+/// Answer a LOGIN or AUTHENTICATE of a blocked IP address or username with `NO`.
+///
+/// Returns `false` when the session must be closed instead.
+fn reject_blocked(
+    throttle: &Throttle,
+    client_to_proxy: &mut Server,
+    tag: Tag<'static>,
+    authenticate: bool,
+) -> bool {
+    if throttle.blocked() == Blocked::Close {
+        return false;
+    }
+
+    let status = blocked_status(tag);
+    if authenticate {
+        authenticate_finish(client_to_proxy, status);
+    } else {
+        client_to_proxy.enqueue_status(status);
+    }
+    true
+}
+
+async fn close_blocked(client_to_proxy_stream: &mut Stream, client_to_proxy: &mut Server) {
+    info!(role = "p2c", "Closing session of blocked client");
+    let bye = Status::bye(None, BLOCKED_TEXT).unwrap();
+    send_bye(client_to_proxy_stream, client_to_proxy, bye).await;
+}
+
+fn blocked_status(tag: Tag<'static>) -> Status<'static> {
+    Status::no(Some(tag), Some(unavailable_code()), BLOCKED_TEXT).unwrap()
+}
+
+/// Record a failed LOGIN or AUTHENTICATE and wait before answering it.
+async fn delay_failure(throttle: Option<&Throttle>, ip: Option<IpAddr>, username: Option<&str>) {
+    if let Some(throttle) = throttle {
+        tokio::time::sleep(throttle.failed(ip, username)).await;
+    }
+}
+
+/// Respond to the client's LOGIN or AUTHENTICATE.
+fn respond(client_to_proxy: &mut Server, method: Method, status: Status<'static>) {
+    match method {
+        Method::Login => {
+            client_to_proxy.enqueue_status(status);
+        }
+        Method::AuthenticatePlain { .. }
+        | Method::AuthenticateLogin
+        | Method::AuthenticateXOAuth2
+        | Method::AuthenticateOAuthBearer
+        | Method::AuthenticateScram => authenticate_finish(client_to_proxy, status),
+    }
+}
+
+/// Drive the client connection until the response was sent.
+async fn flush_until(
+    client_to_proxy_stream: &mut Stream,
+    client_to_proxy: &mut Server,
+    handle: ResponseHandle,
+) {
+    loop {
+        match client_to_proxy_stream.next(&mut *client_to_proxy).await {
+            Ok(server::Event::ResponseSent { handle: sent, .. }) if sent == handle => {
+                trace!(role = "p2c", ?handle, "<---");
+                break;
+            }
+            Ok(event) => trace!(role = "c2p", ?event, "Discard message"),
+            Err(_) => break,
+        }
+    }
+}
+
+/// Send `BYE` to the client (before the connection is closed).
+async fn send_bye(
+    client_to_proxy_stream: &mut Stream,
+    client_to_proxy: &mut Server,
+    bye: Status<'static>,
+) {
+    let handle = client_to_proxy.enqueue_status(bye);
+    flush_before_close(client_to_proxy_stream, client_to_proxy, handle).await;
+}
+
+/// Answer the client after the server connection was lost.
+///
+/// Commands the server didn't complete are completed with `NO [UNAVAILABLE]`, followed by a
+/// `BYE` (unless the server already sent one).
+async fn abandon(
+    client_to_proxy_stream: &mut Stream,
+    client_to_proxy: &mut Server,
+    activity: Activity,
+    unsent: Option<ResponseHandle>,
+) {
+    if let Some(handle) = enqueue_abandon(client_to_proxy, activity, unsent) {
+        flush_before_close(client_to_proxy_stream, client_to_proxy, handle).await;
+    }
+}
+
+/// Enqueue the answers of [`abandon`] and return the handle of the last response (if any).
+fn enqueue_abandon(
+    client_to_proxy: &mut Server,
+    activity: Activity,
+    unsent: Option<ResponseHandle>,
+) -> Option<ResponseHandle> {
+    let mut last = unsent;
+    for tag in activity.in_flight {
+        let status = Status::no(Some(tag), Some(unavailable_code()), SERVER_LOST_TEXT).unwrap();
+        last = Some(client_to_proxy.enqueue_status(status));
+    }
+    if !activity.server_bye {
+        let bye = Status::bye(Some(unavailable_code()), SERVER_LOST_TEXT).unwrap();
+        last = Some(client_to_proxy.enqueue_status(bye));
+    }
+
+    last
+}
+
+/// Drive the client connection until the response was sent (or a timeout elapsed).
+async fn flush_before_close(
+    client_to_proxy_stream: &mut Stream,
+    client_to_proxy: &mut Server,
+    handle: ResponseHandle,
+) {
+    // A client that doesn't read mustn't keep the session open.
+    let flush = flush_until(client_to_proxy_stream, client_to_proxy, handle);
+    if tokio::time::timeout(BYE_TIMEOUT, flush).await.is_err() {
+        info!(role = "p2c", "Timed out sending BYE");
+    }
+}
+
+/// Greet the client with `BYE [UNAVAILABLE]` instead of the server's greeting.
+async fn refuse(mut client_to_proxy_stream: Stream) {
+    let greeting = Greeting::bye(Some(unavailable_code()), UNAVAILABLE_TEXT).unwrap();
+    let mut client_to_proxy = Server::new(server_options(), greeting);
+
+    let flush = async {
+        loop {
+            match client_to_proxy_stream.next(&mut client_to_proxy).await {
+                Ok(server::Event::GreetingSent { greeting }) => {
+                    trace!(role = "p2c", ?greeting, "<---");
+                    break;
+                }
+                Ok(event) => trace!(role = "c2p", ?event, "Discard message"),
+                Err(_) => break,
+            }
+        }
+    };
+    if tokio::time::timeout(BYE_TIMEOUT, flush).await.is_err() {
+        info!(role = "p2c", "Timed out sending BYE");
+    }
+}
+
+fn tag_of(status: &Status) -> Option<Tag<'static>> {
+    status.tag().map(ToStatic::to_static)
+}
+
+fn unavailable_code() -> Code<'static> {
+    // See RFC 5530.
+    Code::Other(CodeOther::unvalidated(b"UNAVAILABLE".as_slice()))
+}
+
+/// Connect to the server and authenticate with the client's credentials.
+///
+/// Untagged data received during authentication is forwarded to the client.
+/// Returns the server's (tagged) status.
+async fn login_to_server(
+    upstream: &Pool<Upstream>,
+    addresses: Option<&Addresses>,
+    client_to_proxy: &mut Server,
+    tag: Tag<'static>,
+    credentials: &Credentials,
+    method: Method,
+    capabilities: &CapabilityFilter,
+) -> Result<(Stream, Client, Status<'static>, Lease), ProxyError> {
+    let (mut proxy_to_server_stream, greeting, lease) =
+        connect_to_pool(upstream, addresses).await?;
+    if greeting.kind != GreetingKind::Ok {
+        return Err(ProxyError::UnexpectedGreeting(Box::new(greeting)));
+    }
+    let mut proxy_to_server = Client::new(client_options(true));
+
+    let body = method
+        .command_body(credentials)
+        .ok_or(ProxyError::InvalidCredentials)?;
+    let handle = proxy_to_server.enqueue_command(Command { tag, body });
+    trace!(role = "p2s", ?handle, "enqueue_command");
+
+    let mut continuations = 0;
+    loop {
+        match proxy_to_server_stream.next(&mut proxy_to_server).await? {
+            client::Event::CommandSent { handle, .. }
+            | client::Event::AuthenticateStarted { handle } => {
+                trace!(role = "p2s", ?handle, "--->");
+            }
+            client::Event::AuthenticateContinuationRequestReceived { handle, .. } => {
+                trace!(role = "s2p", ?handle, "<--| continuation");
+
+                let data = method.response(credentials, continuations);
+                continuations += 1;
+
+                if proxy_to_server.set_authenticate_data(data).is_err() {
+                    return Err(ProxyError::InvalidCredentials);
+                }
+            }
+            client::Event::AuthenticateStatusReceived { status, .. }
+            | client::Event::CommandRejected { status, .. }
+            | client::Event::StatusReceived {
+                status: status @ Status::Tagged(_),
+            } => {
+                trace!(role = "s2p", status=%format!("{:?}", status).blue(), "<--|");
+                return Ok((proxy_to_server_stream, proxy_to_server, status, lease));
+            }
+            client::Event::DataReceived { mut data } => {
+                trace!(role = "s2p", data=%format!("{:?}", data).blue(), "<--|");
+                util::filter_capabilities_in_data(&mut data, capabilities);
+                client_to_proxy.enqueue_data(data);
+            }
+            event => {
+                trace!(
+                    role = "s2p",
+                    ?event,
+                    "Ignore server event during authentication"
+                );
+            }
+        }
+    }
+}
+
+fn client_options(discard_greeting: bool) -> client::Options {
+    let mut options = client::Options::default();
+    options.crlf_relaxed = true;
+    options.discard_greeting = discard_greeting;
+    options
+}
+
+async fn receive_greeting(
+    proxy_to_server_stream: &mut Stream,
+) -> Result<Greeting<'static>, ProxyError> {
+    let mut proxy_to_server = Client::new(client_options(false));
+
+    match proxy_to_server_stream.next(&mut proxy_to_server).await? {
+        client::Event::GreetingReceived { greeting } => {
+            trace!(role = "s2p", greeting=%format!("{:?}", greeting).blue(), "<--|");
+            Ok(greeting)
+        }
+        event => Err(ProxyError::UnexpectedServerEvent(Box::new(event))),
+    }
+}
+
+/// Upgrade the server connection via STARTTLS before any client command is forwarded.
+///
+/// Returns the greeting to be presented to the client. It contains the capabilities the
+/// server announced *after* STARTTLS.
+async fn start_tls_with_server(
+    mut proxy_to_server_stream: Stream,
+    greeting: Greeting<'static>,
+    connector: &ServerConnector,
+    server_addr_port: &str,
+) -> Result<(Stream, Greeting<'static>), ProxyError> {
+    let mut proxy_to_server = Client::new(client_options(true));
+
+    if greeting.kind != GreetingKind::Ok {
+        return Err(ProxyError::UnexpectedGreeting(Box::new(greeting)));
+    }
+
+    let command = Command::new(PROXY_TAG, CommandBody::StartTLS).unwrap();
+    let handle = proxy_to_server.enqueue_command(command);
+    trace!(role = "p2s", ?handle, "enqueue_command");
+    let status = receive_tagged_status(&mut proxy_to_server_stream, &mut proxy_to_server).await?;
+    if status.kind != StatusKind::Ok {
+        return Err(ProxyError::StartTlsRejected(Box::new(status)));
+    }
+
+    info!(?server_addr_port, "Starting TLS with server");
+    let stream_to_server = proxy_to_server_stream.into_socket();
+    let mut proxy_to_server_stream = Stream::tls(connector.connect(stream_to_server).await?.into());
+    // Everything the server sent before the handshake must be discarded.
+    let mut proxy_to_server = Client::new(client_options(true));
+
+    // Capabilities may have changed after STARTTLS.
+    let command = Command::new(PROXY_TAG, CommandBody::Capability).unwrap();
+    let handle = proxy_to_server.enqueue_command(command);
+    trace!(role = "p2s", ?handle, "enqueue_command");
+
+    let mut capabilities = None;
+    loop {
+        match proxy_to_server_stream.next(&mut proxy_to_server).await? {
+            client::Event::CommandSent { handle, .. } => {
+                trace!(role = "p2s", ?handle, "--->");
+            }
+            client::Event::DataReceived {
+                data: Data::Capability(received),
+            } => {
+                trace!(role = "s2p", capabilities=%format!("{:?}", received).blue(), "<--|");
+                capabilities = Some(received);
+            }
+            client::Event::StatusReceived {
+                status: Status::Tagged(Tagged { body, .. }),
+            } => {
+                trace!(role = "s2p", status=%format!("{:?}", body).blue(), "<--|");
+                if body.kind != StatusKind::Ok {
+                    return Err(ProxyError::UnexpectedStatus(Box::new(body)));
+                }
+                if let Some(Code::Capability(received)) = body.code {
+                    capabilities = Some(received);
+                }
+                break;
+            }
+            event => {
+                trace!(role = "s2p", ?event, "Ignore server event during STARTTLS");
+            }
+        }
+    }
+
+    let greeting = Greeting {
+        kind: greeting.kind,
+        code: capabilities.map(Code::Capability),
+        text: greeting.text,
+    };
+
+    Ok((proxy_to_server_stream, greeting))
+}
+
+/// Drive the server connection until the tagged status of the proxy's own command arrives.
+async fn receive_tagged_status(
+    proxy_to_server_stream: &mut Stream,
+    proxy_to_server: &mut Client,
+) -> Result<StatusBody<'static>, ProxyError> {
+    loop {
+        match proxy_to_server_stream.next(&mut *proxy_to_server).await? {
+            client::Event::CommandSent { handle, .. } => {
+                trace!(role = "p2s", ?handle, "--->");
+            }
+            client::Event::StatusReceived {
+                status: Status::Tagged(Tagged { body, .. }),
+            } => {
+                trace!(role = "s2p", status=%format!("{:?}", body).blue(), "<--|");
+                return Ok(body);
+            }
+            client::Event::StatusReceived {
+                status: status @ Status::Bye(_),
+            } => {
+                return Err(ProxyError::UnexpectedServerEvent(Box::new(
+                    client::Event::StatusReceived { status },
+                )));
+            }
+            event => {
+                trace!(role = "s2p", ?event, "Ignore server event");
             }
-            | server::Error::CommandTooLong {
-                ref discarded_bytes,
+        }
+    }
+}
+
+pub struct ConnectedState {
+    client_to_proxy: Stream,
+    client_starttls: Option<StartTls>,
+    proxy_to_server: Stream,
+    start: Start,
+    shutdown: Shutdown,
+    malformed: Arc<malformed::Counters>,
+    capabilities: Arc<CapabilityFilter>,
+    throttle: Option<Arc<Throttle>>,
+    /// IP address of the client (TCP only).
+    client_ip: Option<IpAddr>,
+    /// Counts the connection to the server (for least-connections).
+    _lease: Lease,
+}
+
+/// How to start the conversation.
+enum Start {
+    /// Present the server's greeting to the client.
+    Greeting(Greeting<'static>),
+    /// Continue a session that was already established, e.g., by the director.
+    Session(Box<Session>),
+}
+
+pub struct Session {
+    client_to_proxy: Server,
+    proxy_to_server: Client,
+}
+
+impl State for ConnectedState {}
+
+impl Proxy<ConnectedState> {
+    pub async fn start_conversation(self) {
+        let client_span = info_span!("proxy", with = "client");
+        let server_span = info_span!("proxy", with = "server");
+
+        let timeouts = self.service.timeouts;
+        let mut shutdown = self.state.shutdown;
+        // Close the session once the client's commands are completed.
+        let mut draining = false;
+        let mut proxy_to_server_stream = self.state.proxy_to_server;
+        let mut client_to_proxy_stream = self.state.client_to_proxy;
+        let mut client_starttls = self.state.client_starttls;
+        let malformed = self.state.malformed;
+        let capabilities = self.state.capabilities;
+        let throttle = self.state.throttle;
+        let client_ip = self.state.client_ip;
+        let mut activity = Activity::default();
+        let mut last_activity = Instant::now();
+        // Response that is enqueued but not sent to the client yet.
+        let mut unsent = None;
+        // Malformed messages to forward once the messages enqueued before them were sent.
+        let mut forward_to_client: Option<Box<[u8]>> = None;
+        let mut forward_to_server: Option<Box<[u8]>> = None;
+        // Server event answering a failed attempt, held back until the throttle's delay passed.
+        let mut delayed: Option<(Instant, client::Event)> = None;
+
+        let (mut client_to_proxy, mut proxy_to_server) = match self.state.start {
+            Start::Session(session) => {
+                activity.authenticated = true;
+                (session.client_to_proxy, session.proxy_to_server)
+            }
+            Start::Greeting(mut greeting) => {
+                activity.authenticated = greeting.kind == GreetingKind::PreAuth;
+                activity.translation = Translation::new(self.service.auth_translation);
+                if let Some(Code::Capability(list)) = &greeting.code {
+                    activity.translation.learn(list.as_ref());
+                }
+                util::filter_capabilities_in_greeting(&mut greeting, &capabilities);
+                if let Some(Code::Capability(list)) = &mut greeting.code {
+                    activity.translation.advertise(list, &capabilities);
+                }
+
+                if let Some(starttls) = &client_starttls {
+                    util::advertise_starttls_in_greeting(&mut greeting, starttls.login_disabled);
+                }
+
+                (
+                    Server::new(server_options(), greeting),
+                    Client::new(client_options(true)),
+                )
+            }
+        };
+
+        loop {
+            if let Some(bytes) = forward_to_server.take_if(|_| activity.unsent_command.is_none()) {
+                forward(&mut proxy_to_server_stream, "p2s", &bytes)
+                    .instrument(server_span.clone())
+                    .await;
+            }
+            if let Some(bytes) = forward_to_client.take_if(|_| unsent.is_none()) {
+                forward(&mut client_to_proxy_stream, "p2c", &bytes)
+                    .instrument(client_span.clone())
+                    .await;
+            }
+
+            if draining && activity.is_quiet() {
+                info!(role = "p2c", "Closing session for shutdown");
+                send_bye(
+                    &mut client_to_proxy_stream,
+                    &mut client_to_proxy,
+                    shutdown.bye(),
+                )
+                .instrument(client_span.clone())
+                .await;
+                break;
+            }
+
+            let server_event = tokio::select! {
+                // No commands are enqueued before a malformed one is forwarded.
+                stream_event = client_to_proxy_stream
+                    .next(&mut client_to_proxy)
+                    .instrument(client_span.clone()),
+                    if forward_to_server.is_none() =>
+                {
+                    let Some(client_event) = handle_stream_event("c2p", stream_event) else {
+                        break;
+                    };
+                    if let Ok(server::Event::ResponseSent { handle, .. }) = &client_event {
+                        if unsent == Some(*handle) {
+                            unsent = None;
+                        }
+                    }
+                    // Responses sent to the client, e.g., during IDLE, are no client activity.
+                    if !matches!(
+                        client_event,
+                        Ok(server::Event::GreetingSent { .. } | server::Event::ResponseSent { .. })
+                    ) {
+                        last_activity = Instant::now();
+                    }
+                    let mut client_event = match client_event {
+                        Ok(event) => event,
+                        Err(error) => {
+                            let action = handle_malformed_command(
+                                error,
+                                self.service.malformed,
+                                &malformed,
+                                &mut client_to_proxy,
+                                &mut activity,
+                            );
+                            match action {
+                                MalformedAction::Handled(handle) => unsent = handle.or(unsent),
+                                MalformedAction::Forward(bytes) => {
+                                    forward_to_server = Some(bytes);
+                                }
+                                MalformedAction::Close(text) => {
+                                    let bye = Status::bye(None, text).unwrap();
+                                    send_bye(&mut client_to_proxy_stream, &mut client_to_proxy, bye)
+                                        .instrument(client_span.clone())
+                                        .await;
+                                    break;
+                                }
+                            }
+                            continue;
+                        }
+                    };
+                    if let Some(throttle) = throttle.as_deref() {
+                        let throttled = throttle_client_event(
+                            client_event,
+                            &mut client_to_proxy,
+                            throttle,
+                            client_ip,
+                            &mut activity,
+                        );
+                        client_event = match throttled {
+                            Throttled::Handle(event) => event,
+                            Throttled::Answered => continue,
+                            Throttled::Close => {
+                                close_blocked(&mut client_to_proxy_stream, &mut client_to_proxy)
+                                    .instrument(client_span.clone())
+                                    .await;
+                                break;
+                            }
+                        };
+                    }
+                    let result = handle_client_event(
+                        client_event,
+                        &mut client_to_proxy,
+                        &mut proxy_to_server,
+                        client_starttls.as_ref(),
+                        &mut activity,
+                    );
+                    let action = match result {
+                        Ok(action) => action,
+                        Err(error) => {
+                            error!(role = "c2p", %error, "Closing session");
+                            let bye = Status::bye(None, OUT_OF_SYNC_TEXT).unwrap();
+                            send_bye(&mut client_to_proxy_stream, &mut client_to_proxy, bye)
+                                .instrument(client_span.clone())
+                                .await;
+                            break;
+                        }
+                    };
+
+                    if let Action::StartTls { tag } = action {
+                        // Unwrap: STARTTLS is only requested when it was offered.
+                        let starttls = client_starttls.take().unwrap();
+
+                        let Some((stream, server, subject, _)) = start_tls_with_client(
+                            client_to_proxy_stream,
+                            client_to_proxy,
+                            tag,
+                            &starttls.acceptor,
+                            timeouts.accept_tls,
+                        )
+                        .instrument(client_span.clone())
+                        .await
+                        else {
+                            break;
+                        };
+
+                        if let Some(subject) = &subject {
+                            Span::current().record("subject", subject);
+                        }
+                        client_to_proxy_stream = stream;
+                        client_to_proxy = server;
+                    }
+                    continue;
+                }
+                // No responses are enqueued before a malformed one is forwarded (or while an
+                // earlier one is delayed).
+                stream_event = proxy_to_server_stream
+                    .next(&mut proxy_to_server)
+                    .instrument(server_span.clone()),
+                    if forward_to_client.is_none() && delayed.is_none() =>
+                {
+                    let Some(server_event) = handle_stream_event("s2p", stream_event) else {
+                        abandon(&mut client_to_proxy_stream, &mut client_to_proxy, activity, unsent)
+                            .instrument(client_span.clone())
+                            .await;
+                        break;
+                    };
+                    let server_event = match server_event {
+                        Ok(event) => event,
+                        Err(error) => {
+                            let action = handle_malformed_response(
+                                error,
+                                self.service.malformed,
+                                &malformed,
+                                &mut client_to_proxy,
+                                &mut activity,
+                            );
+                            match action {
+                                MalformedAction::Handled(handle) => unsent = handle.or(unsent),
+                                MalformedAction::Forward(bytes) => {
+                                    forward_to_client = Some(bytes);
+                                }
+                                MalformedAction::Close(text) => {
+                                    let bye = Status::bye(None, text).unwrap();
+                                    send_bye(&mut client_to_proxy_stream, &mut client_to_proxy, bye)
+                                        .instrument(client_span.clone())
+                                        .await;
+                                    break;
+                                }
+                            }
+                            continue;
+                        }
+                    };
+                    // The client waits for its commands (without inactivity) while the server
+                    // answers them.
+                    if !activity.is_quiet() {
+                        last_activity = Instant::now();
+                    }
+                    if let Some(throttle) = throttle.as_deref() {
+                        match complete_attempt(
+                            &server_event,
+                            &mut client_to_proxy,
+                            throttle,
+                            &mut activity,
+                        ) {
+                            Completion::Handle => {}
+                            Completion::Replaced => continue,
+                            Completion::Failed(username) => {
+                                // The client's other commands keep being forwarded meanwhile.
+                                let delay = throttle.failed(client_ip, username.as_deref());
+                                delayed = Some((Instant::now() + delay, server_event));
+                                continue;
+                            }
+                        }
+                    }
+                    server_event
+                }
+                _ = tokio::time::sleep_until(
+                    delayed.as_ref().map_or_else(Instant::now, |(until, _)| *until),
+                ), if delayed.is_some() => {
+                    // Unwrap: The branch is only enabled while an event is delayed.
+                    let (_, server_event) = delayed.take().unwrap();
+                    server_event
+                }
+                _ = tokio::time::sleep_until(last_activity + activity.timeout(&timeouts)),
+                    if activity.is_quiet() =>
+                {
+                    info!(role = "p2c", "Logging out inactive client");
+                    let bye = Status::bye(None, INACTIVITY_TEXT).unwrap();
+                    send_bye(&mut client_to_proxy_stream, &mut client_to_proxy, bye)
+                        .instrument(client_span.clone())
+                        .await;
+                    break;
+                }
+                _ = shutdown.started(), if !draining => {
+                    info!("Draining session for shutdown");
+                    draining = true;
+                    continue;
+                }
+            };
+
+            let result = handle_server_event(
+                server_event,
+                &mut client_to_proxy,
+                &mut proxy_to_server,
+                client_starttls.as_ref(),
+                &capabilities,
+                self.service.command_rejected,
+                &mut activity,
+            );
+            match result {
+                Ok(Some(handle)) => unsent = Some(handle),
+                Ok(None) => {}
+                Err(error) => {
+                    error!(role = "s2p", %error, "Closing session");
+                    let bye = Status::bye(None, OUT_OF_SYNC_TEXT).unwrap();
+                    send_bye(&mut client_to_proxy_stream, &mut client_to_proxy, bye)
+                        .instrument(client_span.clone())
+                        .await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// What the client is doing (to select the inactivity timeout and for draining).
+#[derive(Default)]
+struct Activity {
+    /// Tag of a forwarded LOGIN or AUTHENTICATE the server didn't complete yet.
+    login_tag: Option<Tag<'static>>,
+    authenticated: bool,
+    /// Tag of a forwarded IDLE the server didn't complete yet.
+    idle_tag: Option<Tag<'static>>,
+    /// The server accepted IDLE.
+    idle: bool,
+    /// Tags of forwarded commands the server didn't complete yet.
+    in_flight: Vec<Tag<'static>>,
+    /// The server announced that it closes the connection.
+    server_bye: bool,
+    /// Authentication translated by the proxy.
+    translation: Translation,
+    /// LOGIN or AUTHENTICATE counted against brute force.
+    attempt: Option<Attempt>,
+    /// The last command enqueued for the server, until it was sent.
+    unsent_command: Option<CommandHandle>,
+}
+
+impl Activity {
+    fn timeout(&self, timeouts: &Timeouts) -> Duration {
+        let seconds = if self.idle {
+            timeouts.idle
+        } else if self.authenticated {
+            timeouts.authenticated
+        } else {
+            timeouts.unauthenticated
+        };
+
+        Duration::from_secs(seconds)
+    }
+
+    /// Track the sending of a command to the server.
+    fn sent_command(&mut self, handle: CommandHandle) {
+        if self.unsent_command == Some(handle) {
+            self.unsent_command = None;
+        }
+    }
+
+    /// The client can be sent a `BYE` without interrupting a command.
+    fn is_quiet(&self) -> bool {
+        self.idle || self.in_flight.is_empty()
+    }
+
+    /// Track the completion of a forwarded command.
+    fn completed(&mut self, status: &Status) {
+        if let Status::Tagged(Tagged { tag, body }) = status {
+            self.completed_tag(tag, body.kind == StatusKind::Ok);
+        }
+    }
+
+    fn completed_tag(&mut self, tag: &Tag, ok: bool) {
+        if let Some(index) = self.in_flight.iter().position(|pending| pending == tag) {
+            self.in_flight.remove(index);
+        }
+
+        if self.login_tag.as_ref() == Some(tag) {
+            self.login_tag = None;
+            self.authenticated = ok;
+        }
+        if self.idle_tag.as_ref() == Some(tag) {
+            self.idle_tag = None;
+        }
+    }
+}
+
+/// LOGIN or AUTHENTICATE of a client whose outcome is counted against brute force.
+struct Attempt {
+    tag: Tag<'static>,
+    username: Option<String>,
+    /// The client's next authentication data includes the username.
+    mechanism: Option<AuthMechanism<'static>>,
+    login: bool,
+    /// The username turned out to be blocked, so the exchange was cancelled.
+    blocked: bool,
+}
+
+impl Attempt {
+    /// Start an attempt with the client's LOGIN or AUTHENTICATE.
+    fn start(event: &server::Event) -> Option<Self> {
+        match event {
+            server::Event::CommandReceived {
+                command:
+                    Command {
+                        tag,
+                        body: CommandBody::Login { username, password },
+                    },
+            } => Some(Self {
+                tag: tag.clone(),
+                username: Credentials::from_login(username, password.declassify())
+                    .map(|credentials| credentials.username),
+                mechanism: None,
+                login: true,
+                blocked: false,
             }),
-        ) => {
-            error!(role = "c2p", %error, ?discarded_bytes, "Discard client message");
-            return;
+            server::Event::CommandAuthenticateReceived {
+                command_authenticate:
+                    CommandAuthenticate {
+                        tag,
+                        mechanism,
+                        initial_response,
+                    },
+            } => {
+                let mut attempt = Self {
+                    tag: tag.clone(),
+                    username: None,
+                    mechanism: Some(mechanism.clone()),
+                    login: false,
+                    blocked: false,
+                };
+                if let Some(initial_response) = initial_response {
+                    attempt.learn(&AuthenticateData::Continue(initial_response.clone()));
+                }
+
+                Some(attempt)
+            }
+            _ => None,
+        }
+    }
+
+    /// Learn the username from the client's first authentication data (PLAIN and LOGIN).
+    ///
+    /// Returns the username when it was learned right now.
+    fn learn(&mut self, authenticate_data: &AuthenticateData) -> Option<String> {
+        let AuthenticateData::Continue(message) = authenticate_data else {
+            return None;
+        };
+        self.username = match self.mechanism.take() {
+            Some(AuthMechanism::Plain) => Credentials::from_plain(message.declassify())
+                .map(|credentials| credentials.username),
+            Some(AuthMechanism::Login) => String::from_utf8(message.declassify().to_vec()).ok(),
+            _ => None,
+        };
+
+        self.username.clone()
+    }
+
+    /// Kind of the server's status that completed the attempt (if it did).
+    fn completed(&self, event: &client::Event) -> Option<StatusKind> {
+        match event {
+            client::Event::StatusReceived { status }
+            | client::Event::AuthenticateStatusReceived { status, .. }
+            | client::Event::CommandRejected { status, .. } => match status {
+                Status::Tagged(Tagged { tag, body }) if *tag == self.tag => Some(body.kind),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// What to do with a client event after it was counted against brute force.
+enum Throttled {
+    /// Handle the (possibly rewritten) event.
+    Handle(server::Event),
+    /// The proxy answered the client itself.
+    Answered,
+    /// Close the session of the blocked client.
+    Close,
+}
+
+/// Track the client's LOGIN or AUTHENTICATE and refuse it when it is blocked.
+fn throttle_client_event(
+    mut event: server::Event,
+    client_to_proxy: &mut Server,
+    throttle: &Throttle,
+    client_ip: Option<IpAddr>,
+    activity: &mut Activity,
+) -> Throttled {
+    if activity.authenticated {
+        return Throttled::Handle(event);
+    }
+
+    if let Some(attempt) = Attempt::start(&event) {
+        // Pipelined attempts would pass before the failures are counted.
+        if activity.login_tag.is_some() {
+            let status = Status::bad(Some(attempt.tag), None, AUTHENTICATION_PENDING_TEXT).unwrap();
+            if attempt.login {
+                client_to_proxy.enqueue_status(status);
+            } else {
+                authenticate_finish(client_to_proxy, status);
+            }
+            return Throttled::Answered;
+        }
+        if throttle.is_blocked(client_ip, attempt.username.as_deref()) {
+            if reject_blocked(throttle, client_to_proxy, attempt.tag, !attempt.login) {
+                return Throttled::Answered;
+            }
+            return Throttled::Close;
+        }
+        activity.attempt = Some(attempt);
+    } else if let server::Event::AuthenticateDataReceived { authenticate_data } = &event {
+        let username = activity
+            .attempt
+            .as_mut()
+            .and_then(|attempt| attempt.learn(authenticate_data));
+        let blocked =
+            username.is_some_and(|username| throttle.is_blocked(client_ip, Some(&username)));
+
+        if blocked && activity.login_tag.is_some() && throttle.blocked() == Blocked::Reject {
+            // The server's answer to the cancellation is replaced.
+            if let Some(attempt) = &mut activity.attempt {
+                attempt.blocked = true;
+            }
+            event = server::Event::AuthenticateDataReceived {
+                authenticate_data: AuthenticateData::Cancel,
+            };
+        } else if blocked {
+            // Unless the server is already involved, the proxy collects the credentials
+            // (translation).
+            let _ = activity.translation.collect(&AuthenticateData::Cancel);
+            // Unwrap: The username was learned by the attempt.
+            let attempt = activity.attempt.take().unwrap();
+            if reject_blocked(throttle, client_to_proxy, attempt.tag, true) {
+                return Throttled::Answered;
+            }
+            return Throttled::Close;
+        }
+    }
+
+    Throttled::Handle(event)
+}
+
+/// What to do with a server event after the client's attempt was counted.
+enum Completion {
+    /// Handle the event.
+    Handle,
+    /// The proxy answered the client instead of the server.
+    Replaced,
+    /// The attempt failed, so the server's answer must be delayed.
+    Failed(Option<String>),
+}
+
+/// Count the outcome of the client's LOGIN or AUTHENTICATE when the server completed it.
+fn complete_attempt(
+    event: &client::Event,
+    client_to_proxy: &mut Server,
+    throttle: &Throttle,
+    activity: &mut Activity,
+) -> Completion {
+    // Attempts the proxy answered itself are never completed by the server.
+    let kind = activity
+        .attempt
+        .as_ref()
+        .filter(|attempt| activity.login_tag.as_ref() == Some(&attempt.tag))
+        .and_then(|attempt| attempt.completed(event));
+    let Some(kind) = kind else {
+        return Completion::Handle;
+    };
+
+    // Unwrap: The attempt was just completed.
+    let attempt = activity.attempt.take().unwrap();
+    if attempt.blocked {
+        let status = blocked_status(attempt.tag);
+        activity.completed(&status);
+        authenticate_finish(client_to_proxy, status);
+        return Completion::Replaced;
+    }
+
+    match (kind, attempt.username) {
+        (StatusKind::Ok, Some(username)) => {
+            throttle.succeeded(&username);
+            Completion::Handle
+        }
+        (StatusKind::No, username) => Completion::Failed(username),
+        _ => Completion::Handle,
+    }
+}
+
+/// Follow-up action requested by an event handler.
+enum Action {
+    /// Keep forwarding messages.
+    Continue,
+    /// Upgrade the client connection via STARTTLS.
+    StartTls { tag: Tag<'static> },
+}
+
+/// Status for a command the server rejected.
+///
+/// The kind (`NO` or `BAD`) and code, e.g., `TRYCREATE`, are kept, the text depends on the policy.
+fn rejected_status(
+    tag: Tag<'static>,
+    status: Status<'static>,
+    policy: CommandRejected,
+) -> Status<'static> {
+    let (kind, code, text) = match status {
+        Status::Tagged(Tagged {
+            body: StatusBody { kind, code, text },
+            ..
+        })
+        | Status::Untagged(StatusBody { kind, code, text }) => (kind, code, text),
+        Status::Bye(Bye { code, text }) => (StatusKind::Bad, code, text),
+    };
+
+    let text = match policy {
+        CommandRejected::PassThrough => text,
+        // Keep the alert message because it MUST be displayed to the user
+        CommandRejected::Generic if code == Some(Code::Alert) => text,
+        CommandRejected::Generic => Text::unvalidated(COMMAND_REJECTED_TEXT),
+        // Unwrap: Prefixing a valid text keeps it valid.
+        CommandRejected::Prefix => Text::try_from(format!("proxy: {text}")).unwrap(),
+    };
+
+    Status::Tagged(Tagged {
+        tag,
+        body: StatusBody { kind, code, text },
+    })
+}
+
+/// Follow-up for a malformed message.
+enum MalformedAction {
+    /// The message was answered (or dropped). Returns the handle of the answer (if any).
+    Handled(Option<ResponseHandle>),
+    /// Send the message to the other side unmodified.
+    Forward(Box<[u8]>),
+    /// Close the session with a `BYE` using this text.
+    Close(&'static str),
+}
+
+/// Apply the service's policy to a command that failed to parse.
+///
+/// Commands are forwarded only when they were received completely.
+fn handle_malformed_command(
+    error: server::Error,
+    policy: Malformed,
+    counters: &malformed::Counters,
+    client_to_proxy: &mut Server,
+    activity: &mut Activity,
+) -> MalformedAction {
+    let (discarded_bytes, complete) = match &error {
+        server::Error::ExpectedCrlfGotLf { discarded_bytes }
+        | server::Error::MalformedMessage { discarded_bytes } => (discarded_bytes, true),
+        server::Error::LiteralTooLong { discarded_bytes }
+        | server::Error::CommandTooLong { discarded_bytes } => (discarded_bytes, false),
+    };
+    let tag = malformed::recover_tag(discarded_bytes.declassify());
+
+    let outcome = match policy {
+        Malformed::Close => Outcome::Closed,
+        Malformed::Forward if complete => Outcome::Forwarded,
+        _ => Outcome::Rejected,
+    };
+    let count = counters.count(Origin::Client, outcome);
+    warn!(role = "c2p", %error, ?tag, ?outcome, count, "Malformed command");
+
+    match outcome {
+        // imap-next already rejected the literal.
+        Outcome::Rejected if matches!(error, server::Error::LiteralTooLong { .. }) => {
+            MalformedAction::Handled(None)
         }
+        Outcome::Rejected => {
+            let status = Status::bad(tag, None, MALFORMED_COMMAND_TEXT).unwrap();
+            let handle = client_to_proxy.enqueue_status(status);
+            trace!(role = "p2c", ?handle, "enqueue_status");
+
+            MalformedAction::Handled(Some(handle))
+        }
+        Outcome::Forwarded => {
+            if let Some(tag) = tag {
+                activity.in_flight.push(tag);
+            }
+
+            MalformedAction::Forward(discarded_bytes.declassify().clone())
+        }
+        Outcome::Closed => MalformedAction::Close(MALFORMED_COMMAND_TEXT),
+    }
+}
+
+/// Apply the service's policy to a response that failed to parse.
+///
+/// Responses are forwarded only when they were received completely.
+fn handle_malformed_response(
+    error: client::Error,
+    policy: Malformed,
+    counters: &malformed::Counters,
+    client_to_proxy: &mut Server,
+    activity: &mut Activity,
+) -> MalformedAction {
+    let (discarded_bytes, complete) = match &error {
+        client::Error::ExpectedCrlfGotLf { discarded_bytes }
+        | client::Error::MalformedMessage { discarded_bytes } => (discarded_bytes, true),
+        client::Error::ResponseTooLong { discarded_bytes } => (discarded_bytes, false),
     };
+    let bytes = discarded_bytes.declassify();
+    // A tag is only used to complete a command the client is waiting for.
+    let tag = malformed::recover_tag(bytes).filter(|tag| activity.in_flight.contains(tag));
 
+    let outcome = match policy {
+        Malformed::Close => Outcome::Closed,
+        Malformed::Forward if complete => Outcome::Forwarded,
+        _ => Outcome::Rejected,
+    };
+    let count = counters.count(Origin::Server, outcome);
+    warn!(role = "s2p", %error, ?tag, ?outcome, count, "Malformed response");
+
+    match outcome {
+        Outcome::Rejected => {
+            let status = Status::bad(tag, None, MALFORMED_RESPONSE_TEXT).unwrap();
+            activity.completed(&status);
+            let handle = client_to_proxy.enqueue_status(status);
+            trace!(role = "p2c", ?handle, "enqueue_status");
+
+            MalformedAction::Handled(Some(handle))
+        }
+        Outcome::Forwarded => {
+            if let Some(tag) = tag {
+                activity.completed_tag(&tag, malformed::is_tagged_ok(bytes));
+            }
+
+            MalformedAction::Forward(bytes.clone())
+        }
+        Outcome::Closed => MalformedAction::Close(MALFORMED_RESPONSE_TEXT),
+    }
+}
+
+/// Forward a malformed message (the connection fails on the next read when this fails).
+async fn forward(stream: &mut Stream, role: &'static str, bytes: &[u8]) {
+    if let Err(error) = stream.write_raw(bytes).await {
+        error!(role, %error, "Failed to forward malformed message");
+    }
+}
+
+fn server_options() -> server::Options {
+    let mut options = server::Options::default();
+    options.crlf_relaxed = true;
+    options
+        .set_literal_accept_text(LITERAL_ACCEPT_TEXT.to_string())
+        .unwrap();
+    options
+        .set_literal_reject_text(LITERAL_REJECT_TEXT.to_string())
+        .unwrap();
+    options
+}
+
+/// Confirm STARTTLS to the client and perform the TLS handshake.
+///
+/// Returns a fresh [`Server`] because everything the client sent before the handshake
+/// must be discarded, the subject of the verified client certificate (if any), and the
+/// channel bindings of the connection.
+async fn start_tls_with_client(
+    mut client_to_proxy_stream: Stream,
+    mut client_to_proxy: Server,
+    tag: Tag<'static>,
+    acceptor: &Acceptor,
+    timeout: u64,
+) -> Option<(Stream, Server, Option<String>, ChannelBindings)> {
+    let status = Status::ok(Some(tag), None, STARTTLS_ACCEPT_TEXT).unwrap();
+    let status_handle = client_to_proxy.enqueue_status(status);
+
+    loop {
+        let stream_event = client_to_proxy_stream.next(&mut client_to_proxy).await;
+        match handle_stream_event("c2p", stream_event)? {
+            Ok(server::Event::ResponseSent { handle, .. }) if handle == status_handle => {
+                trace!(role = "p2c", ?handle, "<---");
+                break;
+            }
+            event => {
+                trace!(role = "c2p", ?event, "Discard message during STARTTLS");
+            }
+        }
+    }
+
+    info!("Starting TLS with client");
+    let stream = client_to_proxy_stream.into_socket();
+    let handshake = with_timeout(timeout, "TLS handshake with client", async {
+        Ok(acceptor.accept(stream).await?)
+    });
+    let (client_to_proxy_stream, subject, channel_bindings) = match handshake.await {
+        Ok(stream) => {
+            tls::log_negotiated("c2p", stream.get_ref().1);
+            let subject = tls::peer_subject(stream.get_ref().1);
+            if let Some(subject) = &subject {
+                info!(subject, "Verified client certificate");
+            }
+            let channel_bindings = acceptor.channel_bindings(stream.get_ref().1);
+
+            (Stream::tls(stream.into()), subject, channel_bindings)
+        }
+        Err(error) => {
+            error!(role = "c2p", %error, "Failed to start TLS");
+            return None;
+        }
+    };
+
+    let client_to_proxy = match greeted_server() {
+        Ok(client_to_proxy) => client_to_proxy,
+        Err(error) => {
+            error!(role = "c2p", %error, "Failed to start TLS");
+            return None;
+        }
+    };
+
+    Some((
+        client_to_proxy_stream,
+        client_to_proxy,
+        subject,
+        channel_bindings,
+    ))
+}
+
+/// Fresh [`Server`] for a client that already received the greeting (before STARTTLS).
+fn greeted_server() -> Result<Server, ProxyError> {
+    // We process the greeting without sending it.
+    let greeting = Greeting::ok(None, STARTTLS_ACCEPT_TEXT).unwrap();
+    let mut client_to_proxy = Server::new(server_options(), greeting);
+    loop {
+        match client_to_proxy.next() {
+            Ok(server::Event::GreetingSent { .. }) => return Ok(client_to_proxy),
+            Err(Interrupt::Io(Io::Output(_))) => continue,
+            Ok(event) => return Err(ProxyError::StartTlsResume(format!("{event:?}"))),
+            Err(interrupt) => return Err(ProxyError::StartTlsResume(format!("{interrupt:?}"))),
+        }
+    }
+}
+
+fn handle_stream_event<T, E>(
+    role: &'static str,
+    stream_event: Result<T, stream::Error<E>>,
+) -> Option<Result<T, E>> {
+    match stream_event {
+        Ok(event) => Some(Ok(event)),
+        Err(stream::Error::Closed) => {
+            info!(role, "Connection closed");
+            None
+        }
+        Err(stream::Error::Io(error)) => {
+            error!(role, %error, "Connection terminated");
+            None
+        }
+        Err(stream::Error::Tls(error)) => {
+            error!(role, %error, "Connection terminated");
+            None
+        }
+        Err(stream::Error::State(error)) => Some(Err(error)),
+    }
+}
+
+fn handle_client_event(
+    event: server::Event,
+    client_to_proxy: &mut Server,
+    proxy_to_server: &mut Client,
+    client_starttls: Option<&StartTls>,
+    activity: &mut Activity,
+) -> Result<Action, ProxyError> {
     match event {
         server::Event::GreetingSent { greeting } => {
             trace!(role = "p2c", ?greeting, "<---");
@@ -321,22 +2841,54 @@ fn handle_client_event(
         server::Event::CommandReceived { command } => {
             trace!(role = "c2p", command=%format!("{:?}", command).red(), "|-->");
 
-            let handle = proxy_to_server.enqueue_command(command);
-            trace!(role = "p2s", ?handle, "enqueue_command");
+            match command.body {
+                CommandBody::StartTLS => {
+                    if client_starttls.is_some() {
+                        return Ok(Action::StartTls { tag: command.tag });
+                    }
+
+                    let status =
+                        Status::bad(Some(command.tag), None, STARTTLS_REJECT_TEXT).unwrap();
+                    let handle = client_to_proxy.enqueue_status(status);
+                    trace!(role = "p2c", ?handle, "enqueue_status");
+                }
+                CommandBody::Login { .. } if is_login_disabled(client_starttls) => {
+                    let status = login_disabled_status(command.tag);
+                    let handle = client_to_proxy.enqueue_status(status);
+                    trace!(role = "p2c", ?handle, "enqueue_status");
+                }
+                CommandBody::Login { .. } => {
+                    let step = activity.translation.login(command);
+                    authenticate_step(step, client_to_proxy, proxy_to_server, activity);
+                }
+                _ => {
+                    activity.in_flight.push(command.tag.clone());
+
+                    let handle = proxy_to_server.enqueue_command(command);
+                    trace!(role = "p2s", ?handle, "enqueue_command");
+                    activity.unsent_command = Some(handle);
+                }
+            }
         }
         server::Event::CommandAuthenticateReceived {
             command_authenticate,
         } => {
-            let command_authenticate: Command<'static> = command_authenticate.into();
-
             trace!(
                 role = "c2p",
                 command_authenticate=%format!("{:?}", command_authenticate).red(),
                 "|-->"
             );
 
-            let handle = proxy_to_server.enqueue_command(command_authenticate);
-            trace!(role = "p2s", ?handle, "enqueue_command");
+            if is_login_disabled(client_starttls) {
+                let status = login_disabled_status(command_authenticate.tag);
+                // Unwrap: We just received AUTHENTICATE and didn't continue it.
+                let handle = client_to_proxy.authenticate_finish(status).unwrap();
+                trace!(role = "p2c", ?handle, "authenticate_finish");
+                return Ok(Action::Continue);
+            }
+
+            let step = activity.translation.authenticate(command_authenticate);
+            authenticate_step(step, client_to_proxy, proxy_to_server, activity);
         }
         server::Event::AuthenticateDataReceived { authenticate_data } => {
             trace!(
@@ -345,10 +2897,15 @@ fn handle_client_event(
                 "|-->"
             );
 
-            // TODO(#145): Fix unwrap
+            if let Some(step) = activity.translation.collect(&authenticate_data) {
+                authenticate_step(step, client_to_proxy, proxy_to_server, activity);
+                return Ok(Action::Continue);
+            }
+
+            // The server didn't ask for data (yet), so the client and server disagree.
             let handle = proxy_to_server
                 .set_authenticate_data(authenticate_data)
-                .unwrap();
+                .map_err(|_| ProxyError::UnexpectedAuthenticateData(activity.login_tag.clone()))?;
             trace!(role = "p2s", ?handle, "set_authenticate_data");
         }
         server::Event::IdleCommandReceived { tag } => {
@@ -359,48 +2916,85 @@ fn handle_client_event(
 
             trace!(role = "c2p", idle=%format!("{:?}", idle).red(), "|-->");
 
+            activity.idle_tag = Some(idle.tag.clone());
+            activity.in_flight.push(idle.tag.clone());
             let handle = proxy_to_server.enqueue_command(idle);
             trace!(role = "p2s", ?handle, "enqueue_command");
+            activity.unsent_command = Some(handle);
         }
         server::Event::IdleDoneReceived => {
             trace!(role = "c2p", done=%format!("{:?}", IdleDone).red(), "|-->");
 
+            activity.idle = false;
             let handle = proxy_to_server.set_idle_done();
             trace!(role = "p2s", ?handle, "set_idle_done");
         }
     }
+
+    Ok(Action::Continue)
 }
 
-fn handle_server_event(
-    server_event: Result<client::Event, client::Error>,
+/// Carry out a step of the client's (possibly translated) authentication.
+fn authenticate_step(
+    step: Step,
     client_to_proxy: &mut Server,
+    proxy_to_server: &mut Client,
+    activity: &mut Activity,
 ) {
-    let event = match server_event {
-        Ok(event) => event,
-        Err(
-            ref error @ (client::Error::ExpectedCrlfGotLf {
-                ref discarded_bytes,
-            }
-            | client::Error::MalformedMessage {
-                ref discarded_bytes,
+    match step {
+        Step::Send(command) => {
+            activity.login_tag = Some(command.tag.clone());
+            activity.in_flight.push(command.tag.clone());
+            let handle = proxy_to_server.enqueue_command(command);
+            trace!(role = "p2s", ?handle, "enqueue_command");
+            activity.unsent_command = Some(handle);
+        }
+        Step::Continue(continuation_request) => {
+            match client_to_proxy.authenticate_continue(continuation_request) {
+                Ok(handle) => trace!(role = "p2c", ?handle, "authenticate_continue"),
+                Err(_) => error!(role = "p2c", "Failed to continue authentication"),
             }
-            | client::Error::ResponseTooLong {
-                ref discarded_bytes,
-            }),
-        ) => {
-            error!(role = "s2p", %error, ?discarded_bytes, "Discard server message");
-            return;
         }
-    };
+        Step::Reject { tag, client } => respond(client_to_proxy, client, credentials_status(tag)),
+        Step::Cancel(tag) => {
+            let status = Status::bad(Some(tag), None, DIRECTOR_CANCEL_TEXT).unwrap();
+            authenticate_finish(client_to_proxy, status);
+        }
+    }
+}
 
+fn is_login_disabled(client_starttls: Option<&StartTls>) -> bool {
+    client_starttls.is_some_and(|starttls| starttls.login_disabled)
+}
+
+fn login_disabled_status(tag: Tag<'static>) -> Status<'static> {
+    let code = Code::Other(CodeOther::unvalidated(b"PRIVACYREQUIRED".as_slice()));
+    Status::no(Some(tag), Some(code), LOGIN_DISABLED_TEXT).unwrap()
+}
+
+/// Returns the handle of the response enqueued for the client (if any).
+///
+/// Fails when the server continues an exchange (AUTHENTICATE or IDLE) the client isn't in.
+fn handle_server_event(
+    event: client::Event,
+    client_to_proxy: &mut Server,
+    proxy_to_server: &mut Client,
+    client_starttls: Option<&StartTls>,
+    capabilities: &CapabilityFilter,
+    command_rejected: CommandRejected,
+    activity: &mut Activity,
+) -> Result<Option<ResponseHandle>, ProxyError> {
     match event {
         client::Event::GreetingReceived { greeting } => {
             // This event is emitted only at the beginning so we must have already
             // handled it somewhere else.
             error!(role = "s2p", ?greeting, "Unexpected greeting");
+            Ok(None)
         }
         client::Event::CommandSent { handle, .. } => {
             trace!(role = "p2s", ?handle, "--->");
+            activity.sent_command(handle);
+            Ok(None)
         }
         client::Event::CommandRejected {
             handle,
@@ -409,21 +3003,10 @@ fn handle_server_event(
         } => {
             trace!(role = "s2p", ?handle, status=%format!("{:?}", status).blue(), "<--|");
 
-            let modified_status = match status.code() {
-                Some(Code::Alert) => {
-                    // Keep the alert message because it MUST be displayed to the user
-                    Status::bad(
-                        Some(command.tag),
-                        Some(Code::Alert),
-                        status.text().to_static(),
-                    )
-                    .unwrap()
-                }
-                _ => {
-                    // Use generic message because the original code and text might be misleading
-                    Status::bad(Some(command.tag), None, COMMAND_REJECTED_TEXT).unwrap()
-                }
-            };
+            activity.sent_command(handle);
+            activity.completed(&status);
+            let mut modified_status = rejected_status(command.tag, status, command_rejected);
+            util::filter_capabilities_in_status(&mut modified_status, capabilities);
             let handle = client_to_proxy.enqueue_status(modified_status.clone());
             trace!(
                 role = "p2c",
@@ -431,9 +3014,12 @@ fn handle_server_event(
                 modified_status=%format!("{:?}", modified_status).yellow(),
                 "enqueue_status"
             );
+            Ok(Some(handle))
         }
         client::Event::AuthenticateStarted { handle } => {
             trace!(role = "p2s", ?handle, "--->");
+            activity.sent_command(handle);
+            Ok(None)
         }
         client::Event::AuthenticateContinuationRequestReceived {
             continuation_request,
@@ -445,33 +3031,69 @@ fn handle_server_event(
                 "<--|"
             );
 
+            // The proxy answers when it translated the client's authentication.
+            if let Some(data) = activity.translation.continuation() {
+                let handle = proxy_to_server.set_authenticate_data(data).map_err(|_| {
+                    ProxyError::UnexpectedAuthenticateContinuation(activity.login_tag.clone())
+                })?;
+                trace!(role = "p2s", ?handle, "set_authenticate_data");
+                return Ok(None);
+            }
+
             let handle = client_to_proxy
                 .authenticate_continue(continuation_request)
-                .unwrap();
+                .map_err(|_| {
+                    ProxyError::UnexpectedAuthenticateContinuation(activity.login_tag.clone())
+                })?;
             trace!(role = "p2c", ?handle, "authenticate_continue");
+            Ok(Some(handle))
         }
         client::Event::AuthenticateStatusReceived { status, .. } => {
             trace!(role = "s2p", authenticate_status=%format!("{:?}", status).blue(), "<--|");
 
-            // TODO(#145): Fix unwrap
-            let handle = client_to_proxy.authenticate_finish(status).unwrap();
+            activity.completed(&status);
+            let handle = match translated_method(&status, activity) {
+                // The client sent LOGIN.
+                Some(Method::Login) => client_to_proxy.enqueue_status(status),
+                _ => finish_authenticate(client_to_proxy, status),
+            };
             trace!(role = "p2c", ?handle, "authenticate_finish");
+            Ok(Some(handle))
         }
         client::Event::DataReceived { mut data } => {
             trace!(role = "s2p", data=%format!("{:?}", data).blue(), "<--|");
 
-            util::filter_capabilities_in_data(&mut data);
+            if let Data::Capability(list) = &data {
+                activity.translation.learn(list.as_ref());
+            }
+            util::filter_capabilities_in_data(&mut data, capabilities);
+            if let Data::Capability(list) = &mut data {
+                activity.translation.advertise(list, capabilities);
+            }
+            if let Some(starttls) = client_starttls {
+                util::advertise_starttls_in_data(&mut data, starttls.login_disabled);
+            }
 
             let handle = client_to_proxy.enqueue_data(data);
             trace!(role = "p2c", ?handle, "enqueue_data");
+            Ok(Some(handle))
         }
         client::Event::StatusReceived { mut status } => {
             trace!(role = "s2p", status=%format!("{:?}", status).blue(), "<--|");
 
-            util::filter_capabilities_in_status(&mut status);
+            util::filter_capabilities_in_status(&mut status, capabilities);
+            activity.completed(&status);
+            if let Status::Bye(_) = status {
+                activity.server_bye = true;
+            }
 
-            let handle = client_to_proxy.enqueue_status(status);
+            let handle = match translated_method(&status, activity) {
+                // The client sent AUTHENTICATE, but the proxy sent LOGIN.
+                Some(Method::Login) | None => client_to_proxy.enqueue_status(status),
+                Some(_) => finish_authenticate(client_to_proxy, status),
+            };
             trace!(role = "p2c", ?handle, "enqueue_status");
+            Ok(Some(handle))
         }
         client::Event::ContinuationRequestReceived {
             mut continuation_request,
@@ -482,13 +3104,16 @@ fn handle_server_event(
                 "<--|"
             );
 
-            util::filter_capabilities_in_continuation(&mut continuation_request);
+            util::filter_capabilities_in_continuation(&mut continuation_request, capabilities);
 
             let handle = client_to_proxy.enqueue_continuation_request(continuation_request);
             trace!(role = "p2c", ?handle, "enqueue_continuation_request");
+            Ok(Some(handle))
         }
         client::Event::IdleCommandSent { handle } => {
             trace!(role = "p2s", ?handle, "--->");
+            activity.sent_command(handle);
+            Ok(None)
         }
         client::Event::IdleAccepted {
             handle,
@@ -501,9 +3126,13 @@ fn handle_server_event(
                 "<--|"
             );
 
-            // TODO(#145): Fix unwrap
-            let handle = client_to_proxy.idle_accept(continuation_request).unwrap();
+            activity.idle = true;
+
+            let handle = client_to_proxy
+                .idle_accept(continuation_request)
+                .map_err(|_| ProxyError::UnexpectedIdleAccept(activity.idle_tag.clone()))?;
             trace!(role = "p2c", ?handle, "idle_accept");
+            Ok(Some(handle))
         }
         client::Event::IdleRejected { handle, status } => {
             trace!(
@@ -513,12 +3142,1120 @@ fn handle_server_event(
                 "<--|"
             );
 
-            // TODO(#145): Fix unwrap
-            let handle = client_to_proxy.idle_reject(status).unwrap();
+            activity.completed(&status);
+            let handle = match client_to_proxy.idle_reject(status) {
+                Ok(handle) => handle,
+                Err(status) => {
+                    let error = ProxyError::UnexpectedIdleReject(tag_of(&status));
+                    warn!(role = "s2p", %error, "Forward as status");
+                    client_to_proxy.enqueue_status(status)
+                }
+            };
             trace!(role = "p2c", ?handle, "idle_reject");
+            Ok(Some(handle))
         }
         client::Event::IdleDoneSent { handle } => {
             trace!(role = "p2s", ?handle, "--->");
+            Ok(None)
+        }
+    }
+}
+
+/// How the client authenticated, when the status completes a translated authentication.
+fn translated_method(status: &Status, activity: &mut Activity) -> Option<Method> {
+    activity.translation.completed(status.tag()?)
+}
+
+/// Complete the client's AUTHENTICATE with the server's status.
+fn finish_authenticate(client_to_proxy: &mut Server, status: Status<'static>) -> ResponseHandle {
+    match client_to_proxy.authenticate_finish(status) {
+        Ok(handle) => handle,
+        Err(status) => {
+            // The client isn't authenticating (anymore), so the status completes its command as
+            // usual.
+            let error = ProxyError::UnexpectedAuthenticateStatus(tag_of(&status));
+            warn!(role = "s2p", %error, "Forward as status");
+            client_to_proxy.enqueue_status(status)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use imap_next::{
+        client::{self, Client},
+        imap_types::{
+            auth::{AuthMechanism, AuthenticateData},
+            command::{Command, CommandBody},
+            core::{Tag, Vec1},
+            response::{Capability, Code, CommandContinuationRequest, Data, Greeting, Status},
+            secret::Secret,
+        },
+        server::{self, Server},
+        types::CommandAuthenticate,
+        Interrupt, Io, State,
+    };
+    use std::{net::IpAddr, sync::Arc};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{
+        client_options, complete_attempt, enqueue_abandon, enqueue_ok, flush_until,
+        handle_client_event, handle_malformed_command, handle_malformed_response,
+        handle_server_event, rejected_status, server_options, start_tls_with_client,
+        throttle_client_event, Action, Activity, BoundState, Completion, Directed, MalformedAction,
+        NotAuthenticated, Proxy, ProxyError, StartTls, Throttled, Upstream,
+    };
+    use crate::{
+        config::{
+            Blocked, BruteForce, CommandRejected, Connect, ConnectTls, Identity, Malformed,
+            Service, Timeouts,
+        },
+        malformed::Counters,
+        sasl::Translation,
+        shutdown::Shutdown,
+        stream::Stream,
+        throttle::Throttle,
+        tls::{
+            tests::{acceptor, testdata},
+            ServerConnector,
+        },
+        util::CapabilityFilter,
+    };
+
+    fn tag() -> Tag<'static> {
+        Tag::try_from("A1").unwrap()
+    }
+
+    /// Client connection that sent its greeting and waits for a command.
+    fn client_to_proxy() -> Server {
+        let greeting = Greeting::ok(None, "Hello").unwrap();
+        let mut client_to_proxy = Server::new(server_options(), greeting);
+        output(&mut client_to_proxy);
+        client_to_proxy
+    }
+
+    /// Drive the state until it needs input and return its output.
+    fn output(state: &mut impl State) -> String {
+        let mut output = Vec::new();
+        loop {
+            match state.next() {
+                Ok(_) => continue,
+                Err(Interrupt::Io(Io::Output(bytes))) => output.extend(bytes),
+                Err(Interrupt::Io(Io::NeedMoreInput)) => break,
+                Err(Interrupt::Error(_)) => panic!("unexpected error"),
+            }
+        }
+        String::from_utf8(output).unwrap()
+    }
+
+    /// Feed the client's input and return the resulting event.
+    fn received(client_to_proxy: &mut Server, input: &[u8]) -> server::Event {
+        client_to_proxy.enqueue_input(input);
+        loop {
+            match client_to_proxy.next() {
+                Ok(event) => return event,
+                Err(Interrupt::Io(Io::Output(_))) => continue,
+                Err(interrupt) => panic!("unexpected interrupt: {interrupt:?}"),
+            }
+        }
+    }
+
+    /// Throttle that blocks a username after its first failure.
+    fn throttle(blocked: Blocked) -> Throttle {
+        Throttle::new(&BruteForce {
+            window: 900,
+            max_failures_per_ip: 10,
+            max_failures_per_user: 1,
+            delay: 0,
+            max_delay: 0,
+            blocked,
+            state_path: None,
+        })
+    }
+
+    /// Server event for a command the proxy didn't send.
+    fn server_event(event: impl FnOnce(client::CommandHandle) -> client::Event) -> client::Event {
+        let mut proxy_to_server = Client::new(client_options(true));
+        let handle =
+            proxy_to_server.enqueue_command(Command::new("A1", CommandBody::Noop).unwrap());
+        event(handle)
+    }
+
+    /// Handle the server's event with the defaults of a plain session.
+    fn handle_server(
+        event: client::Event,
+        client_to_proxy: &mut Server,
+        proxy_to_server: &mut Client,
+        activity: &mut Activity,
+    ) {
+        let got = handle_server_event(
+            event,
+            client_to_proxy,
+            proxy_to_server,
+            None,
+            &CapabilityFilter::default(),
+            CommandRejected::Generic,
+            activity,
+        );
+        assert!(got.is_ok());
+    }
+
+    #[test]
+    fn test_activity() {
+        let mut client_to_proxy = client_to_proxy();
+        let mut proxy_to_server = Client::new(client_options(true));
+        let mut activity = Activity::default();
+        assert!(activity.is_quiet());
+
+        for input in [b"A1 NOOP\r\n".as_slice(), b"A2 SELECT INBOX\r\n"] {
+            let event = received(&mut client_to_proxy, input);
+            let action = handle_client_event(
+                event,
+                &mut client_to_proxy,
+                &mut proxy_to_server,
+                None,
+                &mut activity,
+            );
+            assert!(matches!(action, Ok(Action::Continue)));
+        }
+        assert_eq!(
+            vec![tag(), Tag::try_from("A2").unwrap()],
+            activity.in_flight
+        );
+        assert!(!activity.is_quiet());
+
+        // Sending the last command clears the unsent command.
+        let handle = activity.unsent_command.unwrap();
+        let command = Command::new("A2", CommandBody::select("INBOX").unwrap()).unwrap();
+        let event = client::Event::CommandSent { handle, command };
+        handle_server(
+            event,
+            &mut client_to_proxy,
+            &mut proxy_to_server,
+            &mut activity,
+        );
+        assert_eq!(None, activity.unsent_command);
+
+        // Untagged responses and other tags don't complete a command.
+        for status in [
+            Status::ok(None, None, "Still here").unwrap(),
+            Status::ok(Some(Tag::try_from("A9").unwrap()), None, "Completed").unwrap(),
+            // Completed out of order.
+            Status::ok(Some(Tag::try_from("A2").unwrap()), None, "Completed").unwrap(),
+        ] {
+            let event = client::Event::StatusReceived { status };
+            handle_server(
+                event,
+                &mut client_to_proxy,
+                &mut proxy_to_server,
+                &mut activity,
+            );
+        }
+        assert_eq!(vec![tag()], activity.in_flight);
+        assert!(!activity.is_quiet());
+
+        let status = Status::no(Some(tag()), None, "Failed").unwrap();
+        let event = client::Event::StatusReceived { status };
+        handle_server(
+            event,
+            &mut client_to_proxy,
+            &mut proxy_to_server,
+            &mut activity,
+        );
+        assert!(activity.in_flight.is_empty());
+        assert!(activity.is_quiet());
+
+        assert_eq!(
+            "* OK Still here\r\nA9 OK Completed\r\nA2 OK Completed\r\nA1 NO Failed\r\n",
+            output(&mut client_to_proxy)
+        );
+
+        // IDLE is in flight until the server accepted it and after DONE.
+        let event = received(&mut client_to_proxy, b"A3 IDLE\r\n");
+        let action = handle_client_event(
+            event,
+            &mut client_to_proxy,
+            &mut proxy_to_server,
+            None,
+            &mut activity,
+        );
+        assert!(matches!(action, Ok(Action::Continue)));
+        assert_eq!(Some(Tag::try_from("A3").unwrap()), activity.idle_tag);
+        assert!(!activity.is_quiet());
+
+        let event = server_event(|handle| client::Event::IdleAccepted {
+            handle,
+            continuation_request: CommandContinuationRequest::basic(None, "idling").unwrap(),
+        });
+        handle_server(
+            event,
+            &mut client_to_proxy,
+            &mut proxy_to_server,
+            &mut activity,
+        );
+        assert!(activity.idle);
+        assert!(activity.is_quiet());
+        assert_eq!("+ idling\r\n", output(&mut client_to_proxy));
+
+        let event = received(&mut client_to_proxy, b"DONE\r\n");
+        let action = handle_client_event(
+            event,
+            &mut client_to_proxy,
+            &mut proxy_to_server,
+            None,
+            &mut activity,
+        );
+        assert!(matches!(action, Ok(Action::Continue)));
+        assert!(!activity.idle);
+        assert!(!activity.is_quiet());
+
+        let status = Status::ok(Some(Tag::try_from("A3").unwrap()), None, "Done").unwrap();
+        let event = client::Event::StatusReceived { status };
+        handle_server(
+            event,
+            &mut client_to_proxy,
+            &mut proxy_to_server,
+            &mut activity,
+        );
+        assert_eq!(None, activity.idle_tag);
+        assert!(activity.is_quiet());
+    }
+
+    #[test]
+    fn test_abandon() {
+        let mut client_to_proxy = client_to_proxy();
+        let activity = Activity {
+            in_flight: vec![tag(), Tag::try_from("A2").unwrap()],
+            ..Activity::default()
+        };
+        let got = enqueue_abandon(&mut client_to_proxy, activity, None);
+        assert!(got.is_some());
+        assert_eq!(
+            "A1 NO [UNAVAILABLE] proxy: Connection to server lost\r\n\
+             A2 NO [UNAVAILABLE] proxy: Connection to server lost\r\n\
+             * BYE [UNAVAILABLE] proxy: Connection to server lost\r\n",
+            output(&mut client_to_proxy)
+        );
+
+        // The server's BYE was already forwarded.
+        let activity = Activity {
+            in_flight: vec![tag()],
+            server_bye: true,
+            ..Activity::default()
+        };
+        let got = enqueue_abandon(&mut client_to_proxy, activity, None);
+        assert!(got.is_some());
+        assert_eq!(
+            "A1 NO [UNAVAILABLE] proxy: Connection to server lost\r\n",
+            output(&mut client_to_proxy)
+        );
+
+        // Only the unsent response is left to flush.
+        let activity = Activity {
+            server_bye: true,
+            ..Activity::default()
+        };
+        let unsent = client_to_proxy.enqueue_status(Status::bye(None, "Bye").unwrap());
+        let got = enqueue_abandon(&mut client_to_proxy, activity, Some(unsent));
+        assert_eq!(Some(unsent), got);
+        assert_eq!("* BYE Bye\r\n", output(&mut client_to_proxy));
+
+        let got = enqueue_abandon(
+            &mut client_to_proxy,
+            Activity {
+                server_bye: true,
+                ..Activity::default()
+            },
+            None,
+        );
+        assert_eq!(None, got);
+    }
+
+    /// Acceptor that presents `localhost-1.pem` and accepts client certificates of the test CA.
+    #[test]
+    fn test_client_starttls() {
+        let mut client_to_proxy = client_to_proxy();
+        let mut proxy_to_server = Client::new(client_options(true));
+        let mut activity = Activity::default();
+        let starttls = StartTls {
+            acceptor: acceptor(),
+            login_disabled: true,
+        };
+
+        // Authentication is rejected before STARTTLS.
+        for input in [
+            b"A1 LOGIN alice password\r\n".as_slice(),
+            b"A1 AUTHENTICATE PLAIN\r\n",
+        ] {
+            let event = received(&mut client_to_proxy, input);
+            let action = handle_client_event(
+                event,
+                &mut client_to_proxy,
+                &mut proxy_to_server,
+                Some(&starttls),
+                &mut activity,
+            );
+            assert!(matches!(action, Ok(Action::Continue)));
+            assert_eq!(
+                "A1 NO [PRIVACYREQUIRED] proxy: Use STARTTLS before authentication\r\n",
+                output(&mut client_to_proxy)
+            );
+        }
+        assert!(activity.in_flight.is_empty());
+
+        // The server's capabilities are extended.
+        let data = Data::Capability(
+            Vec1::try_from(vec![
+                Capability::Imap4Rev1,
+                Capability::Auth(AuthMechanism::Plain),
+            ])
+            .unwrap(),
+        );
+        let event = client::Event::DataReceived { data };
+        let got = handle_server_event(
+            event,
+            &mut client_to_proxy,
+            &mut proxy_to_server,
+            Some(&starttls),
+            &CapabilityFilter::default(),
+            CommandRejected::Generic,
+            &mut activity,
+        );
+        assert!(matches!(got, Ok(Some(_))));
+        assert_eq!(
+            "* CAPABILITY IMAP4REV1 STARTTLS LOGINDISABLED\r\n",
+            output(&mut client_to_proxy)
+        );
+
+        let event = received(&mut client_to_proxy, b"A2 STARTTLS\r\n");
+        let action = handle_client_event(
+            event,
+            &mut client_to_proxy,
+            &mut proxy_to_server,
+            Some(&starttls),
+            &mut activity,
+        );
+        assert!(matches!(action, Ok(Action::StartTls { tag }) if tag.as_ref() == "A2"));
+
+        // After STARTTLS (or without it), STARTTLS is rejected and LOGIN is forwarded.
+        let event = received(&mut client_to_proxy, b"A3 STARTTLS\r\n");
+        let action = handle_client_event(
+            event,
+            &mut client_to_proxy,
+            &mut proxy_to_server,
+            None,
+            &mut activity,
+        );
+        assert!(matches!(action, Ok(Action::Continue)));
+        assert_eq!(
+            "A3 BAD proxy: STARTTLS not available\r\n",
+            output(&mut client_to_proxy)
+        );
+
+        let event = received(&mut client_to_proxy, b"A4 LOGIN alice password\r\n");
+        let action = handle_client_event(
+            event,
+            &mut client_to_proxy,
+            &mut proxy_to_server,
+            None,
+            &mut activity,
+        );
+        assert!(matches!(action, Ok(Action::Continue)));
+        assert_eq!(vec![Tag::try_from("A4").unwrap()], activity.in_flight);
+    }
+
+    #[tokio::test]
+    async fn test_start_tls_with_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            // The LOGIN is injected before the handshake and must be discarded.
+            stream
+                .write_all(b"A1 STARTTLS\r\nA2 LOGIN mallory password\r\n")
+                .await
+                .unwrap();
+            let ok = read_line(&mut stream).await;
+
+            let options = ConnectTls {
+                ca_bundle_path: Some(testdata("ca.pem")),
+                client_identity: Some(Identity::CertificateChainAndLeafKey {
+                    certificate_chain_path: testdata("alice.pem"),
+                    leaf_key_path: testdata("alice-key.pem"),
+                }),
+                ..ConnectTls::default()
+            };
+            let connector = ServerConnector::new("localhost", &options).unwrap();
+            let mut stream = connector.connect(stream).await.unwrap();
+            stream.write_all(b"A3 NOOP\r\n").await.unwrap();
+
+            (ok, read_line(&mut stream).await)
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = Stream::insecure(stream);
+        let mut client_to_proxy = client_to_proxy();
+        let server::Event::CommandReceived { command } =
+            stream.next(&mut client_to_proxy).await.unwrap()
+        else {
+            panic!("expected STARTTLS");
+        };
+        assert_eq!(CommandBody::StartTLS, command.body);
+
+        let (mut stream, mut client_to_proxy, subject, _) =
+            start_tls_with_client(stream, client_to_proxy, command.tag, &acceptor(), 5)
+                .await
+                .unwrap();
+        assert_eq!(Some("CN=alice"), subject.as_deref());
+
+        // The session continues without another greeting.
+        let server::Event::CommandReceived { command } =
+            stream.next(&mut client_to_proxy).await.unwrap()
+        else {
+            panic!("expected NOOP");
+        };
+        assert_eq!("A3", command.tag.as_ref());
+        let handle = enqueue_ok(&mut client_to_proxy, command.tag);
+        flush_until(&mut stream, &mut client_to_proxy, handle).await;
+
+        let (ok, next) = client.await.unwrap();
+        assert_eq!("A1 OK proxy: Begin TLS negotiation now\r\n", ok);
+        assert_eq!("A3 OK proxy: Completed\r\n", next);
+    }
+
+    /// Server that greets, answers STARTTLS with `reply`, and continues with TLS (if accepted).
+    async fn starttls_server(listener: TcpListener, reply: &'static str) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream
+            .write_all(b"* OK [CAPABILITY IMAP4rev1 STARTTLS LOGINDISABLED] Ready\r\n")
+            .await
+            .unwrap();
+        let line = read_line(&mut stream).await;
+        let (tag, command) = line.split_once(' ').unwrap();
+        assert_eq!("STARTTLS\r\n", command);
+        let tag = tag.to_owned();
+        stream
+            .write_all(format!("{tag} {reply}\r\n").as_bytes())
+            .await
+            .unwrap();
+        if !reply.starts_with("OK") {
+            return;
+        }
+
+        let mut stream = acceptor().accept(stream).await.unwrap();
+        assert_eq!(
+            format!("{tag} CAPABILITY\r\n"),
+            read_line(&mut stream).await
+        );
+        stream
+            .write_all(format!("* CAPABILITY IMAP4rev1 AUTH=PLAIN\r\n{tag} OK Done\r\n").as_bytes())
+            .await
+            .unwrap();
+        // Keep the connection open until the proxy is done.
+        let _ = stream.read_u8().await;
+    }
+
+    fn starttls_upstream(port: u16) -> Upstream {
+        let connect = Connect::StartTls {
+            host: "127.0.0.1".into(),
+            port,
+            tls: ConnectTls {
+                server_name: Some("localhost".into()),
+                ca_bundle_path: Some(testdata("ca.pem")),
+                ..ConnectTls::default()
+            },
+            proxy_protocol: None,
+            via: None,
+        };
+
+        Upstream::new(&connect, &Timeouts::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_server_starttls() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = starttls_upstream(listener.local_addr().unwrap().port());
+        let server = tokio::spawn(starttls_server(listener, "OK Begin TLS"));
+
+        // The client is greeted with the capabilities announced after STARTTLS.
+        let (stream, greeting) = upstream.connect(None).await.unwrap();
+        let expected = Code::Capability(
+            Vec1::try_from(vec![
+                Capability::Imap4Rev1,
+                Capability::Auth(AuthMechanism::Plain),
+            ])
+            .unwrap(),
+        );
+        assert_eq!(Some(expected), greeting.code);
+        assert_eq!("Ready", greeting.text.as_ref());
+        drop(stream);
+        server.await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = starttls_upstream(listener.local_addr().unwrap().port());
+        let server = tokio::spawn(starttls_server(listener, "NO Not today"));
+
+        let result = upstream.connect(None).await;
+        assert!(
+            matches!(result, Err(ProxyError::StartTlsRejected(status)) if status.text.as_ref() == "Not today")
+        );
+        server.await.unwrap();
+    }
+
+    /// Read a line (without reading beyond it).
+    async fn read_line(stream: &mut (impl AsyncReadExt + Unpin)) -> String {
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            line.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(line).unwrap()
+    }
+
+    #[test]
+    fn test_unexpected_authenticate_data() {
+        let mut activity = Activity {
+            login_tag: Some(tag()),
+            ..Activity::default()
+        };
+
+        // The server didn't ask for data.
+        let event = server::Event::AuthenticateDataReceived {
+            authenticate_data: AuthenticateData::r#continue(b"\0alice\0password".as_slice()),
+        };
+        let got = handle_client_event(
+            event,
+            &mut client_to_proxy(),
+            &mut Client::new(client_options(true)),
+            None,
+            &mut activity,
+        );
+        assert!(
+            matches!(got, Err(ProxyError::UnexpectedAuthenticateData(Some(got))) if got == tag())
+        );
+    }
+
+    #[test]
+    fn test_unexpected_authenticate_continuation() {
+        let mut activity = Activity {
+            login_tag: Some(tag()),
+            ..Activity::default()
+        };
+
+        // The client isn't authenticating.
+        let event = server_event(
+            |handle| client::Event::AuthenticateContinuationRequestReceived {
+                handle,
+                continuation_request: CommandContinuationRequest::base64(b"".as_slice()),
+            },
+        );
+        let got = handle_server_event(
+            event,
+            &mut client_to_proxy(),
+            &mut Client::new(client_options(true)),
+            None,
+            &CapabilityFilter::default(),
+            CommandRejected::Generic,
+            &mut activity,
+        );
+        assert!(
+            matches!(got, Err(ProxyError::UnexpectedAuthenticateContinuation(Some(got))) if got == tag())
+        );
+    }
+
+    #[test]
+    fn test_unexpected_authenticate_status() {
+        let mut activity = Activity {
+            login_tag: Some(tag()),
+            in_flight: vec![tag()],
+            ..Activity::default()
+        };
+        let mut client_to_proxy = client_to_proxy();
+
+        // The status is forwarded as usual.
+        let event = server_event(|handle| client::Event::AuthenticateStatusReceived {
+            handle,
+            command_authenticate: CommandAuthenticate {
+                tag: tag(),
+                mechanism: AuthMechanism::Plain,
+                initial_response: None,
+            },
+            status: Status::ok(Some(tag()), None, "Authenticated").unwrap(),
+        });
+        let got = handle_server_event(
+            event,
+            &mut client_to_proxy,
+            &mut Client::new(client_options(true)),
+            None,
+            &CapabilityFilter::default(),
+            CommandRejected::Generic,
+            &mut activity,
+        );
+        assert!(matches!(got, Ok(Some(_))));
+        assert_eq!("A1 OK Authenticated\r\n", output(&mut client_to_proxy));
+        assert!(activity.authenticated);
+        assert!(activity.in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_unexpected_idle_accept() {
+        let mut activity = Activity {
+            idle_tag: Some(tag()),
+            ..Activity::default()
+        };
+
+        // The client isn't waiting for IDLE to be accepted.
+        let event = server_event(|handle| client::Event::IdleAccepted {
+            handle,
+            continuation_request: CommandContinuationRequest::basic(None, "idling").unwrap(),
+        });
+        let got = handle_server_event(
+            event,
+            &mut client_to_proxy(),
+            &mut Client::new(client_options(true)),
+            None,
+            &CapabilityFilter::default(),
+            CommandRejected::Generic,
+            &mut activity,
+        );
+        assert!(matches!(got, Err(ProxyError::UnexpectedIdleAccept(Some(got))) if got == tag()));
+    }
+
+    #[test]
+    fn test_unexpected_idle_reject() {
+        let mut activity = Activity {
+            idle_tag: Some(tag()),
+            in_flight: vec![tag()],
+            ..Activity::default()
+        };
+        let mut client_to_proxy = client_to_proxy();
+
+        // The status is forwarded as usual.
+        let event = server_event(|handle| client::Event::IdleRejected {
+            handle,
+            status: Status::no(Some(tag()), None, "No IDLE").unwrap(),
+        });
+        let got = handle_server_event(
+            event,
+            &mut client_to_proxy,
+            &mut Client::new(client_options(true)),
+            None,
+            &CapabilityFilter::default(),
+            CommandRejected::Generic,
+            &mut activity,
+        );
+        assert!(matches!(got, Ok(Some(_))));
+        assert_eq!("A1 NO No IDLE\r\n", output(&mut client_to_proxy));
+        assert_eq!(None, activity.idle_tag);
+    }
+
+    #[test]
+    fn test_malformed_command() {
+        let counters = Counters::default();
+        let mut activity = Activity::default();
+        let mut client_to_proxy = client_to_proxy();
+        let error = || server::Error::MalformedMessage {
+            discarded_bytes: Secret::new(b"A1 FETCH (\r\n".to_vec().into_boxed_slice()),
+        };
+
+        let got = handle_malformed_command(
+            error(),
+            Malformed::Reject,
+            &counters,
+            &mut client_to_proxy,
+            &mut activity,
+        );
+        assert!(matches!(got, MalformedAction::Handled(Some(_))));
+        assert_eq!(
+            "A1 BAD proxy: Malformed command\r\n",
+            output(&mut client_to_proxy)
+        );
+
+        // Incomplete commands are never forwarded.
+        let too_long = server::Error::CommandTooLong {
+            discarded_bytes: Secret::new(b"A1 FETCH 1:*".to_vec().into_boxed_slice()),
+        };
+        let got = handle_malformed_command(
+            too_long,
+            Malformed::Forward,
+            &counters,
+            &mut client_to_proxy,
+            &mut activity,
+        );
+        assert!(matches!(got, MalformedAction::Handled(Some(_))));
+
+        let got = handle_malformed_command(
+            error(),
+            Malformed::Forward,
+            &counters,
+            &mut client_to_proxy,
+            &mut activity,
+        );
+        assert!(matches!(got, MalformedAction::Forward(bytes) if &*bytes == b"A1 FETCH (\r\n"));
+        assert_eq!(vec![tag()], activity.in_flight);
+
+        assert_eq!(
+            "commands: 2 rejected, 1 forwarded, 0 closed, responses: 0 rejected, 0 forwarded, 0 closed",
+            counters.to_string()
+        );
+    }
+
+    #[test]
+    fn test_malformed_response() {
+        let counters = Counters::default();
+        let mut activity = Activity {
+            login_tag: Some(tag()),
+            in_flight: vec![tag()],
+            ..Activity::default()
+        };
+        let mut client_to_proxy = client_to_proxy();
+        let error = |bytes: &[u8]| client::Error::MalformedMessage {
+            discarded_bytes: Secret::new(bytes.to_vec().into_boxed_slice()),
+        };
+
+        // Only commands the client is waiting for are completed.
+        let got = handle_malformed_response(
+            error(b"B1 OK [\r\n"),
+            Malformed::Reject,
+            &counters,
+            &mut client_to_proxy,
+            &mut activity,
+        );
+        assert!(matches!(got, MalformedAction::Handled(Some(_))));
+        assert_eq!(
+            "* BAD proxy: Malformed response from server\r\n",
+            output(&mut client_to_proxy)
+        );
+
+        let got = handle_malformed_response(
+            error(b"A1 OK [\r\n"),
+            Malformed::Forward,
+            &counters,
+            &mut client_to_proxy,
+            &mut activity,
+        );
+        assert!(matches!(got, MalformedAction::Forward(_)));
+        assert!(activity.in_flight.is_empty());
+        assert!(activity.authenticated);
+
+        let got = handle_malformed_response(
+            error(b"* 1 FETCH (\r\n"),
+            Malformed::Close,
+            &counters,
+            &mut client_to_proxy,
+            &mut activity,
+        );
+        assert!(matches!(got, MalformedAction::Close(_)));
+    }
+
+    #[test]
+    fn test_rejected_status() {
+        let tests = [
+            (
+                CommandRejected::PassThrough,
+                Status::no(Some(tag()), Some(Code::TryCreate), "No such mailbox").unwrap(),
+            ),
+            (
+                CommandRejected::Generic,
+                Status::no(
+                    Some(tag()),
+                    Some(Code::TryCreate),
+                    "proxy: Command rejected by server",
+                )
+                .unwrap(),
+            ),
+            (
+                CommandRejected::Prefix,
+                Status::no(Some(tag()), Some(Code::TryCreate), "proxy: No such mailbox").unwrap(),
+            ),
+        ];
+
+        for (policy, expected) in tests {
+            let status = Status::no(Some(tag()), Some(Code::TryCreate), "No such mailbox").unwrap();
+            assert_eq!(expected, rejected_status(tag(), status, policy));
+        }
+
+        // Alerts must be displayed to the user.
+        let status = Status::bad(Some(tag()), Some(Code::Alert), "Quota exceeded").unwrap();
+        assert_eq!(
+            status,
+            rejected_status(tag(), status.clone(), CommandRejected::Generic)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_greeting_timeout() {
+        // A server that accepts the connection but never greets.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connect = Connect::Insecure {
+            host: "127.0.0.1".into(),
+            port: listener.local_addr().unwrap().port(),
+            proxy_protocol: None,
+            via: None,
+        };
+        let timeouts = Timeouts {
+            greeting: 1,
+            ..Timeouts::default()
+        };
+        let upstream = Upstream::new(&connect, &timeouts).unwrap();
+
+        let result = upstream.connect(None).await;
+        assert!(matches!(
+            result,
+            Err(ProxyError::Timeout("greeting from server"))
+        ));
+    }
+
+    #[test]
+    fn test_throttle_pipelined_attempt() {
+        let throttle = throttle(Blocked::Reject);
+        let mut activity = Activity {
+            login_tag: Some(tag()),
+            ..Activity::default()
+        };
+        let mut client_to_proxy = client_to_proxy();
+
+        // A second LOGIN while the first one is pending.
+        let event = received(&mut client_to_proxy, b"A2 LOGIN alice secret\r\n");
+        let got =
+            throttle_client_event(event, &mut client_to_proxy, &throttle, None, &mut activity);
+        assert!(matches!(got, Throttled::Answered));
+        assert!(output(&mut client_to_proxy).starts_with("A2 BAD "));
+        assert!(activity.attempt.is_none());
+    }
+
+    #[test]
+    fn test_throttle_blocked_login() {
+        for (blocked, answered) in [(Blocked::Reject, true), (Blocked::Close, false)] {
+            let throttle = throttle(blocked);
+            throttle.failed(None, Some("alice"));
+            let mut activity = Activity::default();
+            let mut client_to_proxy = client_to_proxy();
+
+            let event = received(&mut client_to_proxy, b"A1 LOGIN Alice secret\r\n");
+            let got =
+                throttle_client_event(event, &mut client_to_proxy, &throttle, None, &mut activity);
+            if answered {
+                assert!(matches!(got, Throttled::Answered));
+                assert!(output(&mut client_to_proxy).starts_with("A1 NO [UNAVAILABLE] "));
+            } else {
+                assert!(matches!(got, Throttled::Close));
+            }
+        }
+    }
+
+    #[test]
+    fn test_throttle_blocked_authenticate_data() {
+        let throttle = throttle(Blocked::Reject);
+        throttle.failed(None, Some("alice"));
+        let mut activity = Activity::default();
+        let mut client_to_proxy = client_to_proxy();
+
+        let event = received(&mut client_to_proxy, b"A1 AUTHENTICATE PLAIN\r\n");
+        let got =
+            throttle_client_event(event, &mut client_to_proxy, &throttle, None, &mut activity);
+        assert!(matches!(got, Throttled::Handle(_)));
+
+        // The AUTHENTICATE was forwarded and the server asked for data.
+        activity.login_tag = Some(tag());
+        activity.in_flight.push(tag());
+        client_to_proxy
+            .authenticate_continue(CommandContinuationRequest::base64(b"".as_slice()))
+            .unwrap();
+        output(&mut client_to_proxy);
+
+        // The username is blocked, so the proxy cancels the exchange ...
+        let event = received(&mut client_to_proxy, b"AGFsaWNlAHNlY3JldA==\r\n");
+        let got =
+            throttle_client_event(event, &mut client_to_proxy, &throttle, None, &mut activity);
+        assert!(matches!(
+            got,
+            Throttled::Handle(server::Event::AuthenticateDataReceived {
+                authenticate_data: AuthenticateData::Cancel
+            })
+        ));
+
+        // ... and replaces the server's answer.
+        let event = server_event(|handle| client::Event::AuthenticateStatusReceived {
+            handle,
+            command_authenticate: CommandAuthenticate {
+                tag: tag(),
+                mechanism: AuthMechanism::Plain,
+                initial_response: None,
+            },
+            status: Status::bad(Some(tag()), None, "Cancelled").unwrap(),
+        });
+        let got = complete_attempt(&event, &mut client_to_proxy, &throttle, &mut activity);
+        assert!(matches!(got, Completion::Replaced));
+        assert!(output(&mut client_to_proxy).starts_with("A1 NO [UNAVAILABLE] "));
+        assert!(activity.attempt.is_none());
+        assert!(activity.in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_throttle_blocked_translation() {
+        let throttle = throttle(Blocked::Reject);
+        throttle.failed(None, Some("alice"));
+        let mut translation = Translation::new(true);
+        // The server doesn't accept AUTHENTICATE PLAIN, so the proxy collects the credentials.
+        translation.learn(&[Capability::Imap4Rev1]);
+        let mut activity = Activity {
+            translation,
+            ..Activity::default()
+        };
+        let mut client_to_proxy = client_to_proxy();
+        let mut proxy_to_server = Client::new(client_options(true));
+
+        let event = received(&mut client_to_proxy, b"A1 AUTHENTICATE PLAIN\r\n");
+        let Throttled::Handle(event) =
+            throttle_client_event(event, &mut client_to_proxy, &throttle, None, &mut activity)
+        else {
+            panic!("attempt wasn't started");
+        };
+        handle_client_event(
+            event,
+            &mut client_to_proxy,
+            &mut proxy_to_server,
+            None,
+            &mut activity,
+        )
+        .unwrap();
+        assert_eq!("+ \r\n", output(&mut client_to_proxy));
+
+        let event = received(&mut client_to_proxy, b"AGFsaWNlAHNlY3JldA==\r\n");
+        let got =
+            throttle_client_event(event, &mut client_to_proxy, &throttle, None, &mut activity);
+        assert!(matches!(got, Throttled::Answered));
+        assert!(output(&mut client_to_proxy).starts_with("A1 NO [UNAVAILABLE] "));
+        assert!(activity.attempt.is_none());
+        // The translation stopped collecting.
+        let data = AuthenticateData::r#continue(b"\0alice\0secret".as_slice());
+        assert!(activity.translation.collect(&data).is_none());
+    }
+
+    #[test]
+    fn test_complete_attempt() {
+        let throttle = throttle(Blocked::Reject);
+        let mut client_to_proxy = client_to_proxy();
+
+        for (status, failed) in [
+            (Status::no(Some(tag()), None, "Failed").unwrap(), true),
+            (Status::ok(Some(tag()), None, "Logged in").unwrap(), false),
+        ] {
+            let mut activity = Activity::default();
+            let event = received(&mut client_to_proxy, b"A1 LOGIN alice secret\r\n");
+            throttle_client_event(event, &mut client_to_proxy, &throttle, None, &mut activity);
+            // The LOGIN was forwarded.
+            activity.login_tag = Some(tag());
+
+            let event = client::Event::StatusReceived { status };
+            let got = complete_attempt(&event, &mut client_to_proxy, &throttle, &mut activity);
+            match got {
+                Completion::Failed(username) => {
+                    assert!(failed);
+                    assert_eq!(Some("alice"), username.as_deref());
+                }
+                Completion::Handle => assert!(!failed),
+                Completion::Replaced => panic!("answer was replaced"),
+            }
         }
     }
+
+    #[tokio::test]
+    async fn test_director_malformed_credentials() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let ip = Some(IpAddr::from([192, 0, 2, 1]));
+        let throttle = Arc::new(Throttle::new(&BruteForce {
+            window: 900,
+            max_failures_per_ip: 1,
+            max_failures_per_user: 1,
+            delay: 0,
+            max_delay: 0,
+            blocked: Blocked::Reject,
+            state_path: None,
+        }));
+        let mut session = NotAuthenticated {
+            client_to_proxy_stream: Stream::insecure(stream),
+            client_to_proxy: client_to_proxy(),
+            client_starttls: None,
+            client_subject: None,
+            channel_bindings: None,
+            client_ip: ip,
+            addresses: None,
+            terminator: None,
+            throttle: Some(throttle.clone()),
+            capabilities: Arc::new(CapabilityFilter::default()),
+            pending_authenticate: None,
+            login_failures: 0,
+        };
+
+        // PLAIN without password.
+        let server::Event::CommandAuthenticateReceived {
+            command_authenticate,
+        } = received(
+            &mut session.client_to_proxy,
+            b"A1 AUTHENTICATE PLAIN AGFs\r\n",
+        )
+        else {
+            panic!("expected AUTHENTICATE");
+        };
+        let got = session.handle_authenticate(command_authenticate).await;
+        assert!(matches!(got, Directed::Continue));
+        assert!(output(&mut session.client_to_proxy).starts_with("A1 NO [AUTHENTICATIONFAILED] "));
+        assert_eq!(1, session.login_failures);
+
+        // The failure was recorded without username.
+        assert!(throttle.is_blocked(ip, None));
+        let server::Event::CommandAuthenticateReceived {
+            command_authenticate,
+        } = received(&mut session.client_to_proxy, b"A2 AUTHENTICATE PLAIN\r\n")
+        else {
+            panic!("expected AUTHENTICATE");
+        };
+        let got = session.handle_authenticate(command_authenticate).await;
+        assert!(matches!(got, Directed::Continue));
+        assert!(output(&mut session.client_to_proxy).starts_with("A2 NO [UNAVAILABLE] "));
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix() {
+        let path = std::env::temp_dir().join(format!("imap-proxy-{}.sock", std::process::id()));
+        let service = |tls: &str| -> Service {
+            let file = format!(
+                r#"
+                name = "Unix"
+                bind = {{ encryption = "Unix", path = "{}"{tls} }}
+                connect = {{ encryption = "Insecure", host = "127.0.0.1", port = 143 }}
+                "#,
+                path.display()
+            );
+            toml::from_str(&file).unwrap()
+        };
+        let shutdown = || Shutdown::new(tokio::sync::watch::channel(false).1, "Bye").unwrap();
+
+        // Client certificates can't be verified without TLS.
+        let got = Proxy::<BoundState>::bind(
+            service(
+                r#", tls = { client_auth = { mode = "Required", ca_bundle_path = "ca.pem" } }"#,
+            ),
+            shutdown(),
+        )
+        .await;
+        assert!(matches!(got, Err(ProxyError::TlsRequiresIdentity)));
+
+        // The socket file is removed with the listener.
+        let proxy = Proxy::<BoundState>::bind(service(""), shutdown())
+            .await
+            .unwrap();
+        assert!(path.exists());
+        drop(proxy);
+        assert!(!path.exists());
+    }
 }
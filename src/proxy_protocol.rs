@@ -0,0 +1,315 @@
+//! HAProxy PROXY protocol (v1 and v2).
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config::ProxyProtocolVersion;
+
+const V1_PREFIX: &[u8] = b"PROXY";
+/// Maximum length of a v1 header (including CRLF).
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+#[derive(Debug, Error)]
+pub enum ProxyProtocolError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Missing PROXY protocol header")]
+    MissingHeader,
+    #[error("Malformed PROXY protocol header")]
+    MalformedHeader,
+    #[error("Unsupported PROXY protocol version {0}")]
+    UnsupportedVersion(u8),
+}
+
+/// Addresses of a proxied connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Addresses {
+    /// Address of the client.
+    pub source: SocketAddr,
+    /// Address the client connected to.
+    pub destination: SocketAddr,
+}
+
+/// Read a PROXY protocol header (v1 or v2) without consuming any data after it.
+///
+/// Returns `None` when the header doesn't carry addresses, e.g., for health checks.
+pub async fn read_header<R>(stream: &mut R) -> Result<Option<Addresses>, ProxyProtocolError>
+where
+    R: AsyncRead + Unpin,
+{
+    // Both, the v1 prefix and the v2 signature, are at least 5 bytes long.
+    let mut header = vec![0; V1_PREFIX.len()];
+    stream.read_exact(&mut header).await?;
+
+    if header == V1_PREFIX {
+        // The header ends with CRLF, and we must not read beyond it.
+        while !header.ends_with(b"\r\n") {
+            if header.len() == V1_MAX_LENGTH {
+                return Err(ProxyProtocolError::MalformedHeader);
+            }
+            header.push(stream.read_u8().await?);
+        }
+
+        parse_v1(&header)
+    } else if V2_SIGNATURE.starts_with(&header) {
+        header.resize(16, 0);
+        stream.read_exact(&mut header[V1_PREFIX.len()..]).await?;
+        if &header[..12] != V2_SIGNATURE {
+            return Err(ProxyProtocolError::MissingHeader);
+        }
+
+        let length = u16::from_be_bytes([header[14], header[15]]);
+        let mut payload = vec![0; usize::from(length)];
+        stream.read_exact(&mut payload).await?;
+
+        parse_v2(header[12], header[13], &payload)
+    } else {
+        Err(ProxyProtocolError::MissingHeader)
+    }
+}
+
+/// Parse "PROXY <TCP4|TCP6|UNKNOWN> <source> <destination> <source port> <destination port>\r\n".
+fn parse_v1(header: &[u8]) -> Result<Option<Addresses>, ProxyProtocolError> {
+    let header = std::str::from_utf8(header).map_err(|_| ProxyProtocolError::MalformedHeader)?;
+    // Unwrap: The header ends with CRLF.
+    let header = header.strip_suffix("\r\n").unwrap();
+    let mut fields = header.split(' ').skip(1);
+
+    let family = fields.next().ok_or(ProxyProtocolError::MalformedHeader)?;
+    if family == "UNKNOWN" {
+        return Ok(None);
+    }
+
+    let (Some(source), Some(destination), Some(source_port), Some(destination_port), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return Err(ProxyProtocolError::MalformedHeader);
+    };
+
+    let parse = |addr: &str, port: &str| -> Result<SocketAddr, ProxyProtocolError> {
+        let addr = IpAddr::from_str(addr).map_err(|_| ProxyProtocolError::MalformedHeader)?;
+        let port = u16::from_str(port).map_err(|_| ProxyProtocolError::MalformedHeader)?;
+
+        match (family, addr) {
+            ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => Ok(SocketAddr::new(addr, port)),
+            _ => Err(ProxyProtocolError::MalformedHeader),
+        }
+    };
+
+    Ok(Some(Addresses {
+        source: parse(source, source_port)?,
+        destination: parse(destination, destination_port)?,
+    }))
+}
+
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    payload: &[u8],
+) -> Result<Option<Addresses>, ProxyProtocolError> {
+    let version = version_command >> 4;
+    if version != 2 {
+        return Err(ProxyProtocolError::UnsupportedVersion(version));
+    }
+
+    match version_command & 0x0f {
+        // LOCAL, e.g., health checks of the load balancer.
+        0x00 => return Ok(None),
+        // PROXY
+        0x01 => {}
+        _ => return Err(ProxyProtocolError::MalformedHeader),
+    }
+
+    // TLVs after the addresses are ignored.
+    match family {
+        // TCP over IPv4
+        0x11 => {
+            let Some(addresses) = payload.get(..12) else {
+                return Err(ProxyProtocolError::MalformedHeader);
+            };
+            let ip = |offset: usize| -> IpAddr {
+                // Unwrap: The slice has a length of 4.
+                let octets: [u8; 4] = addresses[offset..offset + 4].try_into().unwrap();
+                IpAddr::from(octets)
+            };
+            let port =
+                |offset: usize| u16::from_be_bytes([addresses[offset], addresses[offset + 1]]);
+
+            Ok(Some(Addresses {
+                source: SocketAddr::new(ip(0), port(8)),
+                destination: SocketAddr::new(ip(4), port(10)),
+            }))
+        }
+        // TCP over IPv6
+        0x21 => {
+            let Some(addresses) = payload.get(..36) else {
+                return Err(ProxyProtocolError::MalformedHeader);
+            };
+            let ip = |offset: usize| -> IpAddr {
+                // Unwrap: The slice has a length of 16.
+                let octets: [u8; 16] = addresses[offset..offset + 16].try_into().unwrap();
+                IpAddr::from(octets)
+            };
+            let port =
+                |offset: usize| u16::from_be_bytes([addresses[offset], addresses[offset + 1]]);
+
+            Ok(Some(Addresses {
+                source: SocketAddr::new(ip(0), port(32)),
+                destination: SocketAddr::new(ip(16), port(34)),
+            }))
+        }
+        // UNSPEC, UDP, or UNIX sockets
+        _ => Ok(None),
+    }
+}
+
+/// Encode a PROXY protocol header.
+///
+/// Without addresses, e.g., for clients connected via Unix socket, the header tells the server
+/// to use the connection's addresses ("UNKNOWN" in v1, "LOCAL" in v2).
+pub fn encode_header(version: ProxyProtocolVersion, addresses: Option<&Addresses>) -> Vec<u8> {
+    let Some(addresses) = addresses else {
+        return match version {
+            ProxyProtocolVersion::V1 => b"PROXY UNKNOWN\r\n".to_vec(),
+            ProxyProtocolVersion::V2 => {
+                let mut header = V2_SIGNATURE.to_vec();
+                // Version 2, LOCAL command, UNSPEC family, no payload
+                header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+                header
+            }
+        };
+    };
+
+    // Both addresses must be of the same family.
+    let (source, destination) = match (addresses.source.ip(), addresses.destination.ip()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+            (addresses.source, addresses.destination)
+        }
+        _ => (to_ipv6(addresses.source), to_ipv6(addresses.destination)),
+    };
+
+    match version {
+        ProxyProtocolVersion::V1 => {
+            let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+
+            format!(
+                "PROXY {family} {} {} {} {}\r\n",
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            // Version 2, PROXY command
+            header.push(0x21);
+
+            let mut payload = Vec::with_capacity(36);
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                    header.push(0x11);
+                    payload.extend_from_slice(&source_ip.octets());
+                    payload.extend_from_slice(&destination_ip.octets());
+                }
+                (IpAddr::V6(source_ip), IpAddr::V6(destination_ip)) => {
+                    header.push(0x21);
+                    payload.extend_from_slice(&source_ip.octets());
+                    payload.extend_from_slice(&destination_ip.octets());
+                }
+                _ => unreachable!(),
+            }
+            payload.extend_from_slice(&source.port().to_be_bytes());
+            payload.extend_from_slice(&destination.port().to_be_bytes());
+
+            // Unwrap: The payload has at most 36 bytes.
+            header.extend_from_slice(&u16::try_from(payload.len()).unwrap().to_be_bytes());
+            header.extend_from_slice(&payload);
+            header
+        }
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_header, read_header, Addresses, ProxyProtocolError};
+    use crate::config::ProxyProtocolVersion;
+
+    #[tokio::test]
+    async fn test_read_header_v1() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 143\r\n* OK";
+
+        let got = read_header(&mut stream).await.unwrap();
+        let expected = Addresses {
+            source: "192.0.2.1:56324".parse().unwrap(),
+            destination: "198.51.100.1:143".parse().unwrap(),
+        };
+        assert_eq!(Some(expected), got);
+        assert_eq!(b"* OK", stream);
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(None, read_header(&mut stream).await.unwrap());
+
+        let mut stream: &[u8] = b"PROXY TCP4 2001:db8::1 192.0.2.1 1 2\r\n";
+        assert!(matches!(
+            read_header(&mut stream).await,
+            Err(ProxyProtocolError::MalformedHeader)
+        ));
+
+        let mut stream: &[u8] = b"A1 CAPABILITY\r\n";
+        assert!(matches!(
+            read_header(&mut stream).await,
+            Err(ProxyProtocolError::MissingHeader)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_header_roundtrip() {
+        let tests = [
+            ("192.0.2.1:56324", "198.51.100.1:993"),
+            ("[2001:db8::1]:56324", "[2001:db8::2]:993"),
+        ];
+
+        for (source, destination) in tests {
+            let addresses = Addresses {
+                source: source.parse().unwrap(),
+                destination: destination.parse().unwrap(),
+            };
+
+            for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+                let mut header = encode_header(version, Some(&addresses));
+                header.extend_from_slice(b"\x16\x03\x01");
+
+                let mut stream = header.as_slice();
+                let got = read_header(&mut stream).await.unwrap();
+                assert_eq!(Some(addresses), got);
+                assert_eq!(b"\x16\x03\x01", stream);
+            }
+        }
+
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            let header = encode_header(version, None);
+            assert_eq!(None, read_header(&mut header.as_slice()).await.unwrap());
+        }
+    }
+}
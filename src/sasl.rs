@@ -0,0 +1,522 @@
+//! Translation between `LOGIN`, `AUTHENTICATE LOGIN`, and `AUTHENTICATE PLAIN`.
+//!
+//! Clients can use any of them (with or without SASL-IR). When the server doesn't accept what a
+//! client uses, the proxy collects the credentials and presents them as the server accepts.
+
+use imap_next::{
+    imap_types::{
+        auth::{AuthMechanism, AuthenticateData},
+        command::{Command, CommandBody},
+        core::{Tag, Vec1},
+        response::{Capability, CommandContinuationRequest},
+        secret::Secret,
+    },
+    types::CommandAuthenticate,
+};
+
+use crate::{
+    auth::{Credentials, Method},
+    util::CapabilityFilter,
+};
+
+/// Ways to authenticate the server accepts.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Accepted {
+    /// LOGIN command (not disabled).
+    login: bool,
+    plain: bool,
+    auth_login: bool,
+    sasl_ir: bool,
+}
+
+impl Accepted {
+    fn new(capabilities: &[Capability]) -> Self {
+        Self {
+            login: !capabilities.contains(&Capability::LoginDisabled),
+            plain: capabilities.contains(&Capability::Auth(AuthMechanism::Plain)),
+            auth_login: capabilities.contains(&Capability::Auth(AuthMechanism::Login)),
+            sasl_ir: capabilities.contains(&Capability::SaslIr),
+        }
+    }
+
+    /// Any method the proxy can translate to.
+    fn any(&self) -> bool {
+        self.login || self.plain || self.auth_login
+    }
+
+    /// Preferred method to present translated credentials.
+    fn method(&self, credentials: &Credentials) -> Option<Method> {
+        if self.plain {
+            Some(Method::AuthenticatePlain {
+                initial_response: self.sasl_ir,
+            })
+        } else if credentials.authzid.is_some() {
+            // Only PLAIN can carry an authorization identity.
+            None
+        } else if self.login {
+            Some(Method::Login)
+        } else if self.auth_login {
+            Some(Method::AuthenticateLogin)
+        } else {
+            None
+        }
+    }
+}
+
+/// What the proxy does next in the client's authentication.
+#[derive(Debug)]
+pub enum Step {
+    /// Send the command to the server.
+    Send(Command<'static>),
+    /// Ask the client for more data.
+    Continue(CommandContinuationRequest<'static>),
+    /// The credentials can't be presented to the server.
+    Reject { tag: Tag<'static>, client: Method },
+    /// The client cancelled the authentication.
+    Cancel(Tag<'static>),
+}
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Idle,
+    /// The proxy asks the client for its credentials.
+    Collecting { tag: Tag<'static>, next: Collect },
+    /// The proxy holds the client's initial response until the server asks for it.
+    Withheld { tag: Tag<'static>, data: Vec<u8> },
+    /// The proxy presents the client's credentials to the server.
+    Presenting {
+        tag: Tag<'static>,
+        client: Method,
+        server: Method,
+        credentials: Credentials,
+        continuations: usize,
+    },
+}
+
+#[derive(Debug)]
+enum Collect {
+    Plain,
+    Username,
+    Password { username: String },
+}
+
+/// Translation of a session's authentication.
+#[derive(Debug, Default)]
+pub struct Translation {
+    enabled: bool,
+    /// `None` until the server announced its capabilities.
+    accepted: Option<Accepted>,
+    state: State,
+}
+
+impl Translation {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            ..Self::default()
+        }
+    }
+
+    /// Learn what the server accepts from its (unfiltered) capabilities.
+    pub fn learn(&mut self, capabilities: &[Capability]) {
+        if self.enabled {
+            self.accepted = Some(Accepted::new(capabilities));
+        }
+    }
+
+    /// Announce what the proxy can translate (in filtered capabilities).
+    pub fn advertise(
+        &self,
+        capabilities: &mut Vec1<Capability<'static>>,
+        filter: &CapabilityFilter,
+    ) {
+        if !self.accepted.is_some_and(|accepted| accepted.any()) {
+            return;
+        }
+
+        let translated = [
+            Capability::Auth(AuthMechanism::Plain),
+            Capability::Auth(AuthMechanism::Login),
+            Capability::SaslIr,
+        ];
+        let mut advertised: Vec<_> = capabilities
+            .as_ref()
+            .iter()
+            .filter(|capability| !translated.contains(capability))
+            .cloned()
+            .collect();
+        advertised.extend(
+            translated
+                .into_iter()
+                .filter(|capability| !filter.is_denied(capability)),
+        );
+
+        // Unwrap: The list contains at least the retained capabilities.
+        *capabilities = Vec1::try_from(advertised).unwrap();
+    }
+
+    /// Translate the client's LOGIN (if the server doesn't accept it).
+    pub fn login(&mut self, command: Command<'static>) -> Step {
+        let Some(accepted) = self.accepted else {
+            return Step::Send(command);
+        };
+        let CommandBody::Login { username, password } = &command.body else {
+            return Step::Send(command);
+        };
+        if accepted.login || !accepted.any() {
+            return Step::Send(command);
+        }
+        let Some(credentials) = Credentials::from_login(username, password.declassify()) else {
+            return Step::Send(command);
+        };
+
+        self.present(command.tag, Method::Login, credentials, accepted)
+    }
+
+    /// Translate the client's AUTHENTICATE (if the server doesn't accept it).
+    pub fn authenticate(&mut self, command: CommandAuthenticate) -> Step {
+        let Some(accepted) = self.accepted else {
+            return Step::Send(command.into());
+        };
+
+        let translate = match command.mechanism {
+            AuthMechanism::Plain => !accepted.plain,
+            AuthMechanism::Login => !accepted.auth_login,
+            _ => false,
+        };
+        if translate && accepted.any() {
+            let CommandAuthenticate {
+                tag,
+                mechanism,
+                initial_response,
+            } = command;
+            let initial_response = initial_response.map(|data| data.declassify().to_vec());
+
+            return self.collect_from(tag, mechanism, initial_response, accepted);
+        }
+
+        match (&command.initial_response, accepted.sasl_ir) {
+            (Some(data), false) => {
+                self.state = State::Withheld {
+                    tag: command.tag.clone(),
+                    data: data.declassify().to_vec(),
+                };
+
+                Step::Send(Command {
+                    tag: command.tag,
+                    body: CommandBody::authenticate(command.mechanism),
+                })
+            }
+            _ => Step::Send(command.into()),
+        }
+    }
+
+    fn collect_from(
+        &mut self,
+        tag: Tag<'static>,
+        mechanism: AuthMechanism<'static>,
+        initial_response: Option<Vec<u8>>,
+        accepted: Accepted,
+    ) -> Step {
+        match (mechanism, initial_response) {
+            (AuthMechanism::Plain, Some(message)) => {
+                let method = Method::AuthenticatePlain {
+                    initial_response: true,
+                };
+                match Credentials::from_plain(&message) {
+                    Some(credentials) => self.present(tag, method, credentials, accepted),
+                    None => Step::Reject {
+                        tag,
+                        client: method,
+                    },
+                }
+            }
+            (AuthMechanism::Plain, None) => {
+                self.state = State::Collecting {
+                    tag,
+                    next: Collect::Plain,
+                };
+                Step::Continue(CommandContinuationRequest::base64(b"".as_slice()))
+            }
+            (_, Some(username)) => match String::from_utf8(username) {
+                Ok(username) => {
+                    self.state = State::Collecting {
+                        tag,
+                        next: Collect::Password { username },
+                    };
+                    Step::Continue(CommandContinuationRequest::base64(b"Password:".as_slice()))
+                }
+                Err(_) => Step::Reject {
+                    tag,
+                    client: Method::AuthenticateLogin,
+                },
+            },
+            (_, None) => {
+                self.state = State::Collecting {
+                    tag,
+                    next: Collect::Username,
+                };
+                Step::Continue(CommandContinuationRequest::base64(b"Username:".as_slice()))
+            }
+        }
+    }
+
+    /// Collect the client's response, `None` when it's for the server.
+    pub fn collect(&mut self, data: &AuthenticateData) -> Option<Step> {
+        let (State::Collecting { .. }, Some(accepted)) = (&self.state, self.accepted) else {
+            return None;
+        };
+        let State::Collecting { tag, next } = std::mem::take(&mut self.state) else {
+            return None;
+        };
+        let AuthenticateData::Continue(message) = data else {
+            return Some(Step::Cancel(tag));
+        };
+        let message = message.declassify().to_vec();
+
+        let step = match next {
+            Collect::Plain => {
+                let method = Method::AuthenticatePlain {
+                    initial_response: false,
+                };
+                match Credentials::from_plain(&message) {
+                    Some(credentials) => self.present(tag, method, credentials, accepted),
+                    None => Step::Reject {
+                        tag,
+                        client: method,
+                    },
+                }
+            }
+            Collect::Username => match String::from_utf8(message) {
+                Ok(username) => {
+                    self.state = State::Collecting {
+                        tag,
+                        next: Collect::Password { username },
+                    };
+                    Step::Continue(CommandContinuationRequest::base64(b"Password:".as_slice()))
+                }
+                Err(_) => Step::Reject {
+                    tag,
+                    client: Method::AuthenticateLogin,
+                },
+            },
+            Collect::Password { username } => match String::from_utf8(message) {
+                Ok(password) => {
+                    let credentials = Credentials {
+                        authzid: None,
+                        username,
+                        password: Secret::new(password),
+                    };
+                    self.present(tag, Method::AuthenticateLogin, credentials, accepted)
+                }
+                Err(_) => Step::Reject {
+                    tag,
+                    client: Method::AuthenticateLogin,
+                },
+            },
+        };
+
+        Some(step)
+    }
+
+    fn present(
+        &mut self,
+        tag: Tag<'static>,
+        client: Method,
+        credentials: Credentials,
+        accepted: Accepted,
+    ) -> Step {
+        let Some(server) = accepted.method(&credentials) else {
+            return Step::Reject { tag, client };
+        };
+        let Some(body) = server.command_body(&credentials) else {
+            return Step::Reject { tag, client };
+        };
+
+        self.state = State::Presenting {
+            tag: tag.clone(),
+            client,
+            server,
+            credentials,
+            continuations: 0,
+        };
+
+        Step::Send(Command { tag, body })
+    }
+
+    /// Answer to the server's continuation request, `None` when it's for the client.
+    pub fn continuation(&mut self) -> Option<AuthenticateData<'static>> {
+        match std::mem::take(&mut self.state) {
+            State::Withheld { data, .. } => Some(AuthenticateData::r#continue(data)),
+            State::Presenting {
+                tag,
+                client,
+                server,
+                credentials,
+                continuations,
+            } => {
+                let data = server.response(&credentials, continuations);
+                self.state = State::Presenting {
+                    tag,
+                    client,
+                    server,
+                    credentials,
+                    continuations: continuations + 1,
+                };
+                Some(data)
+            }
+            state => {
+                self.state = state;
+                None
+            }
+        }
+    }
+
+    /// How the client presented the credentials, when the server's status completes a
+    /// translated authentication.
+    pub fn completed(&mut self, completed: &Tag) -> Option<Method> {
+        match &self.state {
+            State::Presenting { tag, client, .. } if tag == completed => {
+                let client = *client;
+                self.state = State::Idle;
+                Some(client)
+            }
+            State::Withheld { tag, .. } if tag == completed => {
+                self.state = State::Idle;
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use imap_next::imap_types::core::AString;
+
+    use super::*;
+
+    fn learned(capabilities: &[Capability]) -> Translation {
+        let mut translation = Translation::new(true);
+        translation.learn(capabilities);
+        translation
+    }
+
+    fn tag() -> Tag<'static> {
+        Tag::try_from("A1").unwrap()
+    }
+
+    fn sent(step: Step) -> CommandBody<'static> {
+        match step {
+            Step::Send(command) => command.body,
+            step => panic!("unexpected {step:?}"),
+        }
+    }
+
+    #[test]
+    fn test_login_to_authenticate_plain() {
+        let mut translation = learned(&[
+            Capability::LoginDisabled,
+            Capability::Auth(AuthMechanism::Plain),
+        ]);
+
+        let login = Command::new(tag(), CommandBody::login("alice", "password").unwrap()).unwrap();
+        let body = sent(translation.login(login));
+        assert_eq!(CommandBody::authenticate(AuthMechanism::Plain), body);
+
+        let data = translation.continuation().unwrap();
+        assert_eq!(
+            AuthenticateData::r#continue(b"\0alice\0password".to_vec()),
+            data
+        );
+        assert_eq!(Some(Method::Login), translation.completed(&tag()));
+        assert!(translation.continuation().is_none());
+    }
+
+    #[test]
+    fn test_authenticate_login_to_login() {
+        // The server accepts LOGIN only.
+        let mut translation = learned(&[]);
+
+        let authenticate = CommandAuthenticate {
+            tag: tag(),
+            mechanism: AuthMechanism::Login,
+            initial_response: None,
+        };
+        assert!(matches!(
+            translation.authenticate(authenticate),
+            Step::Continue(_)
+        ));
+        let username = AuthenticateData::r#continue(b"alice".to_vec());
+        assert!(matches!(
+            translation.collect(&username),
+            Some(Step::Continue(_))
+        ));
+        let password = AuthenticateData::r#continue(b"password".to_vec());
+        let Some(step) = translation.collect(&password) else {
+            panic!("not collecting");
+        };
+        let CommandBody::Login { username, password } = sent(step) else {
+            panic!("not LOGIN");
+        };
+        assert_eq!(AString::try_from("alice").unwrap(), username);
+        assert_eq!(
+            AString::try_from("password").unwrap(),
+            *password.declassify()
+        );
+        assert_eq!(
+            Some(Method::AuthenticateLogin),
+            translation.completed(&tag())
+        );
+    }
+
+    #[test]
+    fn test_withheld_initial_response() {
+        let mut translation = learned(&[Capability::Auth(AuthMechanism::Plain)]);
+
+        let authenticate = CommandAuthenticate {
+            tag: tag(),
+            mechanism: AuthMechanism::Plain,
+            initial_response: Some(Secret::new(b"\0alice\0password".to_vec().into())),
+        };
+        let body = sent(translation.authenticate(authenticate));
+        assert_eq!(CommandBody::authenticate(AuthMechanism::Plain), body);
+        assert_eq!(
+            Some(AuthenticateData::r#continue(b"\0alice\0password".to_vec())),
+            translation.continuation()
+        );
+        // The rest of the exchange is forwarded.
+        assert!(translation.continuation().is_none());
+        assert!(translation.collect(&AuthenticateData::Cancel).is_none());
+    }
+
+    #[test]
+    fn test_advertise() {
+        let translation = learned(&[
+            Capability::Imap4Rev1,
+            Capability::LoginDisabled,
+            Capability::Auth(AuthMechanism::Plain),
+        ]);
+
+        let mut capabilities = Vec1::try_from(vec![
+            Capability::Imap4Rev1,
+            Capability::Auth(AuthMechanism::Plain),
+        ])
+        .unwrap();
+        translation.advertise(&mut capabilities, &CapabilityFilter::default());
+        let expected = vec![
+            Capability::Imap4Rev1,
+            Capability::Auth(AuthMechanism::Plain),
+            Capability::Auth(AuthMechanism::Login),
+            Capability::SaslIr,
+        ];
+        assert_eq!(expected, capabilities.into_inner());
+
+        // Nothing the proxy could translate to.
+        let translation = learned(&[Capability::Imap4Rev1, Capability::LoginDisabled]);
+        let mut capabilities = Vec1::from(Capability::Imap4Rev1);
+        translation.advertise(&mut capabilities, &CapabilityFilter::default());
+        assert_eq!(vec![Capability::Imap4Rev1], capabilities.into_inner());
+    }
+}
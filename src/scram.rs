@@ -0,0 +1,577 @@
+//! Server side of SCRAM (RFC 5802), including channel binding (the "-PLUS" mechanisms).
+//!
+//! The proxy terminates SCRAM with locally stored verifiers, so clients can bind the
+//! authentication to their TLS connection with the proxy.
+
+use std::{num::NonZeroU32, sync::LazyLock};
+
+use aws_lc_rs::{constant_time, digest, hmac, pbkdf2, rand};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use imap_next::imap_types::auth::AuthMechanism;
+use thiserror::Error;
+
+use crate::tls::ChannelBindings;
+
+/// Iteration count of verifiers for unknown users.
+const MOCK_ITERATIONS: u32 = 4096;
+
+/// Key to derive stable salts for unknown users (so they can't be told apart from known ones).
+static MOCK_KEY: LazyLock<hmac::Key> = LazyLock::new(|| {
+    let mut key = [0; 32];
+    // Unwrap: The system's random number generator doesn't fail.
+    rand::fill(&mut key).unwrap();
+    hmac::Key::new(hmac::HMAC_SHA256, &key)
+});
+
+#[derive(Debug, Error)]
+pub enum ScramError {
+    #[error("Malformed SCRAM message")]
+    Malformed,
+    #[error("Unsupported channel binding type")]
+    UnsupportedChannelBinding,
+    #[error("Client didn't use channel binding, but it was offered")]
+    Downgrade,
+    #[error("Channel binding differs")]
+    ChannelBinding,
+    #[error("Authorization identity differs from username")]
+    Authzid,
+    #[error("Unknown user")]
+    UnknownUser,
+    #[error("Wrong password")]
+    WrongPassword,
+}
+
+/// Hash function of a SCRAM mechanism.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Hash {
+    Sha1,
+    Sha256,
+}
+
+impl Hash {
+    /// Hash function and whether the mechanism uses channel binding.
+    pub fn of(mechanism: &AuthMechanism) -> Option<(Self, bool)> {
+        match mechanism {
+            AuthMechanism::ScramSha1 => Some((Self::Sha1, false)),
+            AuthMechanism::ScramSha1Plus => Some((Self::Sha1, true)),
+            AuthMechanism::ScramSha256 => Some((Self::Sha256, false)),
+            AuthMechanism::ScramSha256Plus => Some((Self::Sha256, true)),
+            _ => None,
+        }
+    }
+
+    /// Name used in verifiers, e.g., "SCRAM-SHA-256".
+    fn name(self) -> &'static str {
+        match self {
+            Self::Sha1 => "SCRAM-SHA-1",
+            Self::Sha256 => "SCRAM-SHA-256",
+        }
+    }
+
+    fn digest(self) -> &'static digest::Algorithm {
+        match self {
+            Self::Sha1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            Self::Sha256 => &digest::SHA256,
+        }
+    }
+
+    fn hmac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        let algorithm = match self {
+            Self::Sha1 => hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            Self::Sha256 => hmac::HMAC_SHA256,
+        };
+
+        hmac::sign(&hmac::Key::new(algorithm, key), data)
+            .as_ref()
+            .to_vec()
+    }
+
+    fn salted_password(self, password: &str, salt: &[u8], iterations: NonZeroU32) -> Vec<u8> {
+        let algorithm = match self {
+            Self::Sha1 => pbkdf2::PBKDF2_HMAC_SHA1,
+            Self::Sha256 => pbkdf2::PBKDF2_HMAC_SHA256,
+        };
+
+        let mut salted_password = vec![0; self.digest().output_len()];
+        pbkdf2::derive(
+            algorithm,
+            iterations,
+            salt,
+            password.as_bytes(),
+            &mut salted_password,
+        );
+        salted_password
+    }
+}
+
+/// Stored SCRAM credentials of a user (RFC 5803 format).
+///
+/// E.g., "SCRAM-SHA-256$4096:<salt>$<StoredKey>:<ServerKey>" (all in base64).
+#[derive(Clone, Debug)]
+pub struct Verifier {
+    hash: Hash,
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    stored_key: Vec<u8>,
+    server_key: Vec<u8>,
+}
+
+impl Verifier {
+    pub fn parse(verifier: &str) -> Option<Self> {
+        let (name, rest) = verifier.split_once('$')?;
+        let hash = [Hash::Sha1, Hash::Sha256]
+            .into_iter()
+            .find(|hash| hash.name() == name)?;
+        let (parameters, keys) = rest.split_once('$')?;
+        let (iterations, salt) = parameters.split_once(':')?;
+        let (stored_key, server_key) = keys.split_once(':')?;
+
+        let verifier = Self {
+            hash,
+            iterations: iterations.parse().ok()?,
+            salt: BASE64.decode(salt).ok()?,
+            stored_key: BASE64.decode(stored_key).ok()?,
+            server_key: BASE64.decode(server_key).ok()?,
+        };
+        let length = hash.digest().output_len();
+        if verifier.stored_key.len() != length || verifier.server_key.len() != length {
+            return None;
+        }
+
+        Some(verifier)
+    }
+
+    pub fn new(hash: Hash, password: &str, salt: &[u8], iterations: NonZeroU32) -> Self {
+        let salted_password = hash.salted_password(password, salt, iterations);
+        let client_key = hash.hmac(&salted_password, b"Client Key");
+
+        Self {
+            hash,
+            iterations,
+            salt: salt.to_vec(),
+            stored_key: digest::digest(hash.digest(), &client_key).as_ref().to_vec(),
+            server_key: hash.hmac(&salted_password, b"Server Key"),
+        }
+    }
+
+    /// Verifier that no password matches, with a salt that is stable per username.
+    fn mock(hash: Hash, username: &str) -> Self {
+        let salt = hmac::sign(&MOCK_KEY, username.as_bytes());
+        let length = hash.digest().output_len();
+
+        Self {
+            hash,
+            // Unwrap: The constant is not zero.
+            iterations: NonZeroU32::new(MOCK_ITERATIONS).unwrap(),
+            salt: salt.as_ref()[..16].to_vec(),
+            stored_key: vec![0; length],
+            server_key: vec![0; length],
+        }
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    /// Verifier that no password matches, which takes as long to verify as this one.
+    pub fn mock_like(&self) -> Self {
+        let length = self.hash.digest().output_len();
+
+        Self {
+            hash: self.hash,
+            iterations: self.iterations,
+            salt: vec![0; self.salt.len()],
+            stored_key: vec![0; length],
+            server_key: vec![0; length],
+        }
+    }
+
+    /// Verify a password (e.g., received via `LOGIN`).
+    pub fn verify(&self, password: &str) -> bool {
+        let verifier = Self::new(self.hash, password, &self.salt, self.iterations);
+
+        constant_time::verify_slices_are_equal(&verifier.stored_key, &self.stored_key).is_ok()
+    }
+}
+
+impl std::fmt::Display for Verifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}${}:{}${}:{}",
+            self.hash.name(),
+            self.iterations,
+            BASE64.encode(&self.salt),
+            BASE64.encode(&self.stored_key),
+            BASE64.encode(&self.server_key),
+        )
+    }
+}
+
+/// First message of a client.
+#[derive(Debug)]
+pub struct ClientFirst {
+    hash: Hash,
+    /// The "gs2-header" that the client repeats (with the channel binding data) in its final
+    /// message.
+    gs2_header: String,
+    channel_binding: Option<Vec<u8>>,
+    username: String,
+    bare: String,
+    nonce: String,
+}
+
+impl ClientFirst {
+    /// Parse the first message of the client.
+    ///
+    /// `channel_bindings` are `None` when no "-PLUS" mechanism was offered (e.g., because the
+    /// client doesn't use TLS).
+    pub fn parse(
+        mechanism: &AuthMechanism,
+        message: &[u8],
+        channel_bindings: Option<&ChannelBindings>,
+    ) -> Result<Self, ScramError> {
+        let (hash, plus) = Hash::of(mechanism).ok_or(ScramError::Malformed)?;
+        let message = std::str::from_utf8(message).map_err(|_| ScramError::Malformed)?;
+
+        let (flag, rest) = message.split_once(',').ok_or(ScramError::Malformed)?;
+        let (authzid, bare) = rest.split_once(',').ok_or(ScramError::Malformed)?;
+        let gs2_header = &message[..message.len() - bare.len()];
+
+        let channel_binding = match (flag, plus) {
+            ("n", false) => None,
+            ("y", false) if channel_bindings.is_some() => return Err(ScramError::Downgrade),
+            ("y", false) => None,
+            (flag, true) => {
+                let bindings = channel_bindings.ok_or(ScramError::UnsupportedChannelBinding)?;
+                let data = match flag.strip_prefix("p=") {
+                    Some("tls-exporter") => bindings.exporter.clone(),
+                    Some("tls-server-end-point") => bindings.server_end_point.clone(),
+                    Some(_) => None,
+                    None => return Err(ScramError::Malformed),
+                };
+                Some(data.ok_or(ScramError::UnsupportedChannelBinding)?)
+            }
+            _ => return Err(ScramError::Malformed),
+        };
+
+        let mut attributes = bare.split(',');
+        let username = match attributes.next().and_then(|field| field.strip_prefix("n=")) {
+            Some(username) => decode_name(username)?,
+            None => return Err(ScramError::Malformed),
+        };
+        let nonce = match attributes.next().and_then(|field| field.strip_prefix("r=")) {
+            Some(nonce) if !nonce.is_empty() => nonce,
+            _ => return Err(ScramError::Malformed),
+        };
+
+        if let Some(authzid) = authzid.strip_prefix("a=") {
+            if decode_name(authzid)? != username {
+                return Err(ScramError::Authzid);
+            }
+        } else if !authzid.is_empty() {
+            return Err(ScramError::Malformed);
+        }
+
+        Ok(Self {
+            hash,
+            gs2_header: gs2_header.to_owned(),
+            channel_binding,
+            username,
+            bare: bare.to_owned(),
+            nonce: nonce.to_owned(),
+        })
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Answer with the server's first message.
+    ///
+    /// Unknown users (`None`) get a challenge, too, but fail with any proof.
+    pub fn challenge(self, verifier: Option<Verifier>) -> (Exchange, Vec<u8>) {
+        let known = verifier.is_some();
+        let verifier = verifier.unwrap_or_else(|| Verifier::mock(self.hash, &self.username));
+
+        let mut server_nonce = [0; 18];
+        // Unwrap: The system's random number generator doesn't fail.
+        rand::fill(&mut server_nonce).unwrap();
+        let nonce = format!("{}{}", self.nonce, BASE64.encode(server_nonce));
+
+        let server_first = format!(
+            "r={nonce},s={},i={}",
+            BASE64.encode(&verifier.salt),
+            verifier.iterations
+        );
+
+        let exchange = Exchange {
+            client_first: self,
+            server_first: server_first.clone(),
+            nonce,
+            verifier,
+            known,
+        };
+
+        (exchange, server_first.into_bytes())
+    }
+}
+
+/// SCRAM exchange waiting for the client's final message.
+#[derive(Debug)]
+pub struct Exchange {
+    client_first: ClientFirst,
+    server_first: String,
+    nonce: String,
+    verifier: Verifier,
+    known: bool,
+}
+
+impl Exchange {
+    pub fn username(&self) -> &str {
+        self.client_first.username()
+    }
+
+    /// Verify the client's final message and return the username and the server's final message.
+    pub fn finish(self, message: &[u8]) -> Result<(String, Vec<u8>), ScramError> {
+        let message = std::str::from_utf8(message).map_err(|_| ScramError::Malformed)?;
+        let (without_proof, proof) = message.rsplit_once(",p=").ok_or(ScramError::Malformed)?;
+        let proof = BASE64.decode(proof).map_err(|_| ScramError::Malformed)?;
+
+        let mut attributes = without_proof.split(',');
+        let channel_binding = match attributes.next().and_then(|field| field.strip_prefix("c=")) {
+            Some(channel_binding) => BASE64
+                .decode(channel_binding)
+                .map_err(|_| ScramError::Malformed)?,
+            None => return Err(ScramError::Malformed),
+        };
+        match attributes.next().and_then(|field| field.strip_prefix("r=")) {
+            Some(nonce) if nonce == self.nonce => {}
+            _ => return Err(ScramError::Malformed),
+        }
+
+        let mut expected = self.client_first.gs2_header.as_bytes().to_vec();
+        if let Some(data) = &self.client_first.channel_binding {
+            expected.extend_from_slice(data);
+        }
+        if constant_time::verify_slices_are_equal(&channel_binding, &expected).is_err() {
+            return Err(ScramError::ChannelBinding);
+        }
+
+        let hash = self.verifier.hash;
+        let auth_message = format!(
+            "{},{},{without_proof}",
+            self.client_first.bare, self.server_first
+        );
+        let client_signature = hash.hmac(&self.verifier.stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return Err(ScramError::Malformed);
+        }
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(&client_signature)
+            .map(|(proof, signature)| proof ^ signature)
+            .collect();
+        let stored_key = digest::digest(hash.digest(), &client_key);
+
+        if !self.known {
+            return Err(ScramError::UnknownUser);
+        }
+        if constant_time::verify_slices_are_equal(stored_key.as_ref(), &self.verifier.stored_key)
+            .is_err()
+        {
+            return Err(ScramError::WrongPassword);
+        }
+
+        let server_signature = hash.hmac(&self.verifier.server_key, auth_message.as_bytes());
+        let server_final = format!("v={}", BASE64.encode(server_signature));
+
+        Ok((self.client_first.username, server_final.into_bytes()))
+    }
+}
+
+/// Decode a "saslname" ("=2C" is "," and "=3D" is "=").
+fn decode_name(name: &str) -> Result<String, ScramError> {
+    let mut decoded = String::with_capacity(name.len());
+    let mut rest = name;
+
+    while let Some(index) = rest.find('=') {
+        decoded.push_str(&rest[..index]);
+        match rest.get(index..index + 3) {
+            Some("=2C") => decoded.push(','),
+            Some("=3D") => decoded.push('='),
+            _ => return Err(ScramError::Malformed),
+        }
+        rest = &rest[index + 3..];
+    }
+    decoded.push_str(rest);
+
+    if decoded.is_empty() {
+        return Err(ScramError::Malformed);
+    }
+
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Client side of an exchange (RFC 5802, section 3).
+    fn final_message(
+        password: &str,
+        client_first: &str,
+        server_first: &[u8],
+        channel_binding: &[u8],
+    ) -> String {
+        let server_first = std::str::from_utf8(server_first).unwrap();
+        let mut attributes = server_first.split(',');
+        let nonce = attributes.next().unwrap().strip_prefix("r=").unwrap();
+        let salt = BASE64
+            .decode(attributes.next().unwrap().strip_prefix("s=").unwrap())
+            .unwrap();
+        let iterations = attributes
+            .next()
+            .unwrap()
+            .strip_prefix("i=")
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        let hash = Hash::Sha256;
+        let salted_password = hash.salted_password(password, &salt, iterations);
+        let client_key = hash.hmac(&salted_password, b"Client Key");
+        let stored_key = digest::digest(hash.digest(), &client_key);
+        let without_proof = format!("c={},r={nonce}", BASE64.encode(channel_binding));
+        let (_, bare) = client_first.split_at(client_first.find("n=").unwrap());
+        let auth_message = format!("{bare},{server_first},{without_proof}");
+        let client_signature = hash.hmac(stored_key.as_ref(), auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(&client_signature)
+            .map(|(key, signature)| key ^ signature)
+            .collect();
+
+        format!("{without_proof},p={}", BASE64.encode(proof))
+    }
+
+    fn verifier() -> Verifier {
+        Verifier::new(
+            Hash::Sha256,
+            "pencil",
+            b"salt",
+            NonZeroU32::new(4096).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_verifier() {
+        // RFC 7677, section 3
+        let salt = BASE64.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let verifier = Verifier::new(
+            Hash::Sha256,
+            "pencil",
+            &salt,
+            NonZeroU32::new(4096).unwrap(),
+        );
+        let encoded = verifier.to_string();
+        assert_eq!(
+            "SCRAM-SHA-256$4096:W22ZaJ0SNY7soEsUEjb6gQ==$\
+             WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY=:\
+             wfPLwcE6nTWhTAmQ7tl2KeoiWGPlZqQxSrmfPwDl2dU=",
+            encoded
+        );
+
+        let parsed = Verifier::parse(&encoded).unwrap();
+        assert!(parsed.verify("pencil"));
+        assert!(!parsed.verify("Pencil"));
+        assert!(Verifier::parse("SCRAM-SHA-256$4096:c2FsdA==$AAAA:AAAA").is_none());
+    }
+
+    #[test]
+    fn test_exchange() {
+        let client_first = "n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+        let first =
+            ClientFirst::parse(&AuthMechanism::ScramSha256, client_first.as_bytes(), None).unwrap();
+        assert_eq!("user", first.username());
+
+        let (exchange, server_first) = first.challenge(Some(verifier()));
+        let client_final = final_message("pencil", client_first, &server_first, b"n,,");
+        let (username, server_final) = exchange.finish(client_final.as_bytes()).unwrap();
+        assert_eq!("user", username);
+        assert!(server_final.starts_with(b"v="));
+
+        // Wrong password
+        let first =
+            ClientFirst::parse(&AuthMechanism::ScramSha256, client_first.as_bytes(), None).unwrap();
+        let (exchange, server_first) = first.challenge(Some(verifier()));
+        let client_final = final_message("pen", client_first, &server_first, b"n,,");
+        assert!(matches!(
+            exchange.finish(client_final.as_bytes()),
+            Err(ScramError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn test_channel_binding() {
+        let bindings = ChannelBindings {
+            exporter: Some(b"exporter".to_vec()),
+            server_end_point: None,
+        };
+        let client_first = "p=tls-exporter,,n=user,r=abc";
+
+        let first = ClientFirst::parse(
+            &AuthMechanism::ScramSha256Plus,
+            client_first.as_bytes(),
+            Some(&bindings),
+        )
+        .unwrap();
+        let (exchange, server_first) = first.challenge(Some(verifier()));
+        let client_final = final_message(
+            "pencil",
+            client_first,
+            &server_first,
+            b"p=tls-exporter,,exporter",
+        );
+        assert!(exchange.finish(client_final.as_bytes()).is_ok());
+
+        // Binding to another connection
+        let first = ClientFirst::parse(
+            &AuthMechanism::ScramSha256Plus,
+            client_first.as_bytes(),
+            Some(&bindings),
+        )
+        .unwrap();
+        let (exchange, server_first) = first.challenge(Some(verifier()));
+        let client_final = final_message(
+            "pencil",
+            client_first,
+            &server_first,
+            b"p=tls-exporter,,other",
+        );
+        assert!(matches!(
+            exchange.finish(client_final.as_bytes()),
+            Err(ScramError::ChannelBinding)
+        ));
+
+        let unsupported = ClientFirst::parse(
+            &AuthMechanism::ScramSha256Plus,
+            b"p=tls-server-end-point,,n=user,r=abc",
+            Some(&bindings),
+        );
+        assert!(matches!(
+            unsupported,
+            Err(ScramError::UnsupportedChannelBinding)
+        ));
+        let downgrade = ClientFirst::parse(
+            &AuthMechanism::ScramSha256,
+            b"y,,n=user,r=abc",
+            Some(&bindings),
+        );
+        assert!(matches!(downgrade, Err(ScramError::Downgrade)));
+    }
+}
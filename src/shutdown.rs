@@ -0,0 +1,35 @@
+//! Graceful shutdown.
+
+use imap_next::imap_types::{core::Text, error::ValidationError, response::Status};
+use tokio::sync::watch;
+
+/// Shutdown of the proxy as observed by services and clients.
+///
+/// Every service and client holds an instance. Thus, the proxy is drained once all instances
+/// are dropped (see [`watch::Sender::closed`]).
+#[derive(Clone)]
+pub struct Shutdown {
+    receiver: watch::Receiver<bool>,
+    /// Text of the `BYE` sent to clients.
+    text: Text<'static>,
+}
+
+impl Shutdown {
+    pub fn new(receiver: watch::Receiver<bool>, text: &str) -> Result<Self, ValidationError> {
+        Ok(Self {
+            receiver,
+            text: Text::try_from(text.to_owned())?,
+        })
+    }
+
+    /// Wait until the shutdown started.
+    pub async fn started(&mut self) {
+        // The sender is only dropped when the proxy exits.
+        let _ = self.receiver.wait_for(|started| *started).await;
+    }
+
+    pub fn bye(&self) -> Status<'static> {
+        // Unwrap: The text was already validated.
+        Status::bye(None, self.text.clone()).unwrap()
+    }
+}
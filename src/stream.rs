@@ -0,0 +1,422 @@
+//! Connections to clients and servers over TCP or Unix sockets, with or without TLS.
+//!
+//! Adapted from imap-next's `Stream`, which only supports `TcpStream`s and can't write bytes
+//! that bypass the client or server state (see [`Stream::write_raw`]).
+
+use std::{
+    convert::Infallible,
+    io::{ErrorKind, Read, Write},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use imap_next::{Interrupt, Io, State};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpStream, UnixStream},
+    select,
+};
+use tokio_rustls::{rustls, TlsStream};
+use tracing::instrument;
+#[cfg(debug_assertions)]
+use tracing::trace;
+
+/// Socket of a connection.
+pub enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl From<TcpStream> for Socket {
+    fn from(stream: TcpStream) -> Self {
+        Self::Tcp(stream)
+    }
+}
+
+impl From<UnixStream> for Socket {
+    fn from(stream: UnixStream) -> Self {
+        Self::Unix(stream)
+    }
+}
+
+impl AsyncRead for Socket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Socket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+pub struct Stream {
+    socket: Socket,
+    tls: Option<rustls::Connection>,
+    read_buffer: BytesMut,
+    write_buffer: BytesMut,
+}
+
+impl Stream {
+    pub fn insecure(socket: impl Into<Socket>) -> Self {
+        Self {
+            socket: socket.into(),
+            tls: None,
+            read_buffer: BytesMut::default(),
+            write_buffer: BytesMut::default(),
+        }
+    }
+
+    /// Take over a connection after the TLS handshake.
+    ///
+    /// The TLS state is kept next to the socket, so the socket can be split for reading and
+    /// writing simultaneously (which `TlsStream` doesn't support).
+    pub fn tls(stream: TlsStream<Socket>) -> Self {
+        let (socket, tls) = match stream {
+            TlsStream::Client(stream) => {
+                let (socket, tls) = stream.into_inner();
+                (socket, rustls::Connection::Client(tls))
+            }
+            TlsStream::Server(stream) => {
+                let (socket, tls) = stream.into_inner();
+                (socket, rustls::Connection::Server(tls))
+            }
+        };
+
+        Self {
+            socket,
+            tls: Some(tls),
+            read_buffer: BytesMut::default(),
+            write_buffer: BytesMut::default(),
+        }
+    }
+
+    /// Take the socket out of a connection without TLS, e.g., to start TLS.
+    ///
+    /// Bytes that were received, but not processed yet, are discarded.
+    pub fn into_socket(self) -> Socket {
+        debug_assert!(self.tls.is_none(), "TLS was already started");
+        self.socket
+    }
+
+    pub async fn flush(&mut self) -> Result<(), Error<Infallible>> {
+        if let Some(tls) = &mut self.tls {
+            tls.writer().flush()?;
+            encrypt(tls, &mut self.write_buffer, Vec::new())?;
+        }
+
+        write(&mut self.socket, &mut self.write_buffer).await?;
+        self.socket.flush().await?;
+
+        Ok(())
+    }
+
+    /// Write bytes that bypass the client or server state, e.g., a malformed message.
+    ///
+    /// Messages already enqueued in the state, but not yet encoded, are sent afterwards, so the
+    /// caller must wait until they were sent.
+    pub async fn write_raw(&mut self, bytes: &[u8]) -> Result<(), Error<Infallible>> {
+        match &mut self.tls {
+            Some(tls) => encrypt(tls, &mut self.write_buffer, bytes.to_vec())?,
+            None => self.write_buffer.extend_from_slice(bytes),
+        }
+
+        self.flush().await
+    }
+
+    pub async fn next<F: State>(&mut self, mut state: F) -> Result<F::Event, Error<F::Error>> {
+        let event = loop {
+            match &mut self.tls {
+                None => {
+                    if !self.read_buffer.is_empty() {
+                        state.enqueue_input(&self.read_buffer);
+                        self.read_buffer.clear();
+                    }
+                }
+                Some(tls) => {
+                    let plain_bytes = decrypt(tls, &mut self.read_buffer)?;
+                    if !plain_bytes.is_empty() {
+                        state.enqueue_input(&plain_bytes);
+                    }
+                }
+            }
+
+            // Events and errors are returned without doing IO.
+            let io = match state.next() {
+                Ok(event) => break event,
+                Err(Interrupt::Io(io)) => io,
+                Err(Interrupt::Error(error)) => return Err(Error::State(error)),
+            };
+
+            let plain_bytes = match io {
+                Io::Output(bytes) => bytes,
+                Io::NeedMoreInput => Vec::new(),
+            };
+            match &mut self.tls {
+                None => self.write_buffer.extend(plain_bytes),
+                Some(tls) => encrypt(tls, &mut self.write_buffer, plain_bytes)?,
+            }
+
+            if self.write_buffer.is_empty() {
+                read(&mut self.socket, &mut self.read_buffer).await?;
+            } else {
+                // Reading and writing simultaneously prevents a deadlock between client and
+                // server when both sides only write.
+                match &mut self.socket {
+                    Socket::Tcp(stream) => {
+                        let (reader, writer) = stream.split();
+                        read_or_write(
+                            reader,
+                            writer,
+                            &mut self.read_buffer,
+                            &mut self.write_buffer,
+                        )
+                        .await?
+                    }
+                    Socket::Unix(stream) => {
+                        let (reader, writer) = stream.split();
+                        read_or_write(
+                            reader,
+                            writer,
+                            &mut self.read_buffer,
+                            &mut self.write_buffer,
+                        )
+                        .await?
+                    }
+                }
+            }
+        };
+
+        Ok(event)
+    }
+}
+
+/// Error during reading from or writing to a connection.
+#[derive(Debug, Error)]
+pub enum Error<E> {
+    /// The connection was closed (by the peer).
+    #[error("Stream was closed")]
+    Closed,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Tls(#[from] rustls::Error),
+    /// The client or server state failed.
+    #[error(transparent)]
+    State(E),
+}
+
+async fn read_or_write<R, W>(
+    reader: R,
+    writer: W,
+    read_buffer: &mut BytesMut,
+    write_buffer: &mut BytesMut,
+) -> Result<(), ReadWriteError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    select! {
+        result = read(reader, read_buffer) => result,
+        result = write(writer, write_buffer) => result,
+    }
+}
+
+#[instrument(name = "io", skip_all, fields(action = "read"))]
+async fn read<R: AsyncRead + Unpin>(
+    mut reader: R,
+    read_buffer: &mut BytesMut,
+) -> Result<(), ReadWriteError> {
+    #[cfg(debug_assertions)]
+    let old_len = read_buffer.len();
+    let byte_count = reader.read_buf(read_buffer).await?;
+    #[cfg(debug_assertions)]
+    trace!(data = %read_buffer[old_len..].escape_ascii());
+
+    // The buffer is unlimited, so reading nothing means "end of file".
+    if byte_count == 0 {
+        return Err(ReadWriteError::Closed);
+    }
+
+    Ok(())
+}
+
+#[instrument(name = "io", skip_all, fields(action = "write"))]
+async fn write<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    write_buffer: &mut BytesMut,
+) -> Result<(), ReadWriteError> {
+    while !write_buffer.is_empty() {
+        let byte_count = writer.write(write_buffer).await?;
+        #[cfg(debug_assertions)]
+        trace!(data = %write_buffer[..byte_count].escape_ascii());
+        write_buffer.advance(byte_count);
+
+        // The buffer isn't empty, so writing nothing means the connection was closed.
+        if byte_count == 0 {
+            return Err(ReadWriteError::Closed);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+enum ReadWriteError {
+    #[error("Stream was closed")]
+    Closed,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl<E> From<ReadWriteError> for Error<E> {
+    fn from(error: ReadWriteError) -> Self {
+        match error {
+            ReadWriteError::Closed => Error::Closed,
+            ReadWriteError::Io(error) => Error::Io(error),
+        }
+    }
+}
+
+fn decrypt(
+    tls: &mut rustls::Connection,
+    read_buffer: &mut BytesMut,
+) -> Result<Vec<u8>, DecryptEncryptError> {
+    let mut plain_bytes = Vec::new();
+
+    while tls.wants_read() && !read_buffer.is_empty() {
+        tls.read_tls(&mut read_buffer.reader())?;
+        tls.process_new_packets()?;
+    }
+
+    loop {
+        let mut chunk = [0; 128];
+        // See https://docs.rs/rustls/latest/rustls/struct.Reader.html#method.read
+        match tls.reader().read(&mut chunk) {
+            Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+            // The TLS session was closed uncleanly.
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => {
+                return Err(DecryptEncryptError::Closed)
+            }
+            Err(error) => return Err(DecryptEncryptError::Io(error)),
+            // The TLS session was closed cleanly.
+            Ok(0) => return Err(DecryptEncryptError::Closed),
+            Ok(n) => plain_bytes.extend(&chunk[..n]),
+        };
+    }
+
+    Ok(plain_bytes)
+}
+
+fn encrypt(
+    tls: &mut rustls::Connection,
+    write_buffer: &mut BytesMut,
+    plain_bytes: Vec<u8>,
+) -> Result<(), DecryptEncryptError> {
+    if !plain_bytes.is_empty() {
+        tls.writer().write_all(&plain_bytes)?;
+    }
+
+    while tls.wants_write() {
+        tls.write_tls(&mut write_buffer.writer())?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+enum DecryptEncryptError {
+    #[error("Session was closed")]
+    Closed,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Tls(#[from] rustls::Error),
+}
+
+impl<E> From<DecryptEncryptError> for Error<E> {
+    fn from(error: DecryptEncryptError) -> Self {
+        match error {
+            DecryptEncryptError::Closed => Error::Closed,
+            DecryptEncryptError::Io(error) => Error::Io(error),
+            DecryptEncryptError::Tls(error) => Error::Tls(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use imap_next::{
+        imap_types::{command::CommandBody, response::Greeting},
+        server::{self, Server},
+    };
+    use tokio::net::UnixStream;
+
+    use super::*;
+    use crate::tls::tests::{acceptor, connector};
+
+    #[tokio::test]
+    async fn test_tls_unix() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = connector().connect(theirs).await.unwrap();
+            stream.write_all(b"A1 NOOP\r\n").await.unwrap();
+
+            let mut received = [0; 21];
+            stream.read_exact(&mut received).await.unwrap();
+            received
+        });
+
+        let stream = acceptor().accept(Socket::from(ours)).await.unwrap();
+        let mut stream = Stream::tls(stream.into());
+        let greeting = Greeting::ok(None, "Hello").unwrap();
+        let mut server = Server::new(server::Options::default(), greeting);
+        let event = loop {
+            match stream.next(&mut server).await.unwrap() {
+                server::Event::GreetingSent { .. } => continue,
+                event => break event,
+            }
+        };
+        assert!(matches!(
+            event,
+            server::Event::CommandReceived { command } if command.body == CommandBody::Noop
+        ));
+
+        // Raw bytes are encrypted, too.
+        stream.write_raw(b"* OK [\xff\r\n").await.unwrap();
+
+        let received = client.await.unwrap();
+        assert_eq!(b"* OK Hello\r\n* OK [\xff\r\n", &received);
+    }
+}
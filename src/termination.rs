@@ -0,0 +1,652 @@
+//! Authentication termination: clients authenticate with the proxy, which authenticates with
+//! the server using mapped credentials.
+
+use std::{collections::HashMap, sync::LazyLock, time::Duration};
+
+use argon2::{
+    password_hash::{Output, Salt, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use imap_next::imap_types::{auth::AuthMechanism, secret::Secret};
+use thiserror::Error;
+use tokio::{sync::Mutex, time::Instant};
+use tracing::{error, info, warn};
+
+use crate::{
+    auth::{Credentials, Method},
+    config::{
+        self, AuthTermination, OAuth2Mechanism, PasswordPolicy, ScramMechanism, UpstreamCredentials,
+    },
+    oauth2::{OAuth2Error, TokenEndpoint},
+    proxy::ProxyError,
+    scram::{Hash, Verifier},
+    util,
+};
+
+/// Refresh access tokens that expire within this duration.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// Salt of mock hashes (Base64 of a valid length).
+const MOCK_SALT: &str = "bW9ja3NhbHRtb2Nrc2FsdA";
+
+/// Hash that passwords of unknown users are verified against when the users file has no hash
+/// to mock (argon2 with the default parameters, which no password is rejected by quickly).
+static MOCK_HASH: LazyLock<String> = LazyLock::new(|| {
+    // Unwrap: The salt is valid Base64 of a valid length.
+    let salt = SaltString::from_b64(MOCK_SALT).unwrap();
+    // Unwrap: Hashing with the default parameters never fails.
+    Argon2::default()
+        .hash_password(b"", &salt)
+        .unwrap()
+        .to_string()
+});
+
+/// Why the proxy rejected a client's credentials.
+#[derive(Debug, Error)]
+pub enum Rejection {
+    #[error("Password violates policy")]
+    Policy,
+    #[error("Authorization identity differs from username")]
+    Authzid,
+    #[error("Unknown user")]
+    UnknownUser,
+    #[error("Wrong password")]
+    WrongPassword,
+    #[error("No server credentials for user")]
+    Unmapped,
+    #[error("Failed to obtain access token: {0}")]
+    Token(#[from] OAuth2Error),
+}
+
+/// Verifies client credentials and maps them to server credentials.
+pub struct Terminator {
+    users_path: String,
+    policy: PasswordPolicy,
+    upstream: Option<Mapping>,
+    users: HashMap<String, Mapping>,
+    token_endpoint: Option<TokenEndpoint>,
+    mechanism: OAuth2Mechanism,
+    scram: Vec<ScramMechanism>,
+}
+
+struct Mapping {
+    username: String,
+    secret: MappedSecret,
+    impersonate: bool,
+}
+
+enum MappedSecret {
+    Password(Secret<String>),
+    RefreshToken(Mutex<Tokens>),
+}
+
+struct Tokens {
+    refresh_token: Secret<String>,
+    /// Where the refresh token was loaded from (and rotated ones are saved to).
+    source: config::Secret,
+    /// Cached access token and when it expires.
+    access_token: Option<(Secret<String>, Option<Instant>)>,
+}
+
+impl Mapping {
+    fn new(
+        name: &str,
+        credentials: &UpstreamCredentials,
+        oauth2: bool,
+    ) -> Result<Self, ProxyError> {
+        let invalid = |reason| ProxyError::InvalidUpstreamCredentials(name.to_owned(), reason);
+
+        let secret = match (&credentials.password, &credentials.refresh_token) {
+            (Some(password), None) => {
+                MappedSecret::Password(Secret::new(util::load_secret(password)?))
+            }
+            (None, Some(_)) if credentials.impersonate => {
+                return Err(invalid("`impersonate` requires `password`"));
+            }
+            (None, Some(_)) if !oauth2 => {
+                return Err(invalid("`refresh_token` requires `oauth2`"));
+            }
+            (None, Some(refresh_token)) => MappedSecret::RefreshToken(Mutex::new(Tokens {
+                refresh_token: Secret::new(util::load_secret(refresh_token)?),
+                source: refresh_token.clone(),
+                access_token: None,
+            })),
+            _ => return Err(invalid("either `password` or `refresh_token` is required")),
+        };
+
+        Ok(Self {
+            username: credentials.username.clone(),
+            secret,
+            impersonate: credentials.impersonate,
+        })
+    }
+}
+
+impl Terminator {
+    pub fn new(termination: &AuthTermination) -> Result<Self, ProxyError> {
+        let oauth2 = termination.oauth2.is_some();
+        let upstream = match &termination.upstream {
+            Some(credentials) => Some(Mapping::new("upstream", credentials, oauth2)?),
+            None => None,
+        };
+        let users = termination
+            .users
+            .iter()
+            .map(|(user, credentials)| {
+                Ok((
+                    user.to_lowercase(),
+                    Mapping::new(user, credentials, oauth2)?,
+                ))
+            })
+            .collect::<Result<_, ProxyError>>()?;
+        let token_endpoint = match &termination.oauth2 {
+            Some(oauth2) => Some(TokenEndpoint::new(oauth2)?),
+            None => None,
+        };
+
+        Ok(Self {
+            users_path: termination.users_path.clone(),
+            policy: termination.password_policy,
+            upstream,
+            users,
+            token_endpoint,
+            mechanism: termination
+                .oauth2
+                .as_ref()
+                .map(|oauth2| oauth2.mechanism)
+                .unwrap_or_default(),
+            scram: termination.scram.clone(),
+        })
+    }
+
+    /// SCRAM mechanisms offered to clients (with "-PLUS" only when the client uses TLS).
+    pub fn scram_mechanisms(&self, channel_binding: bool) -> Vec<AuthMechanism<'static>> {
+        self.scram
+            .iter()
+            .filter(|mechanism| channel_binding || !mechanism.binds_channel())
+            .map(|mechanism| match mechanism {
+                ScramMechanism::ScramSha1 => AuthMechanism::ScramSha1,
+                ScramMechanism::ScramSha1Plus => AuthMechanism::ScramSha1Plus,
+                ScramMechanism::ScramSha256 => AuthMechanism::ScramSha256,
+                ScramMechanism::ScramSha256Plus => AuthMechanism::ScramSha256Plus,
+            })
+            .collect()
+    }
+
+    /// Whether a "-PLUS" mechanism is offered to clients using TLS.
+    pub fn binds_channel(&self) -> bool {
+        self.scram.iter().any(ScramMechanism::binds_channel)
+    }
+
+    /// SCRAM verifier of `username` for the hash function.
+    pub async fn scram_verifier(&self, username: &str, hash: Hash) -> Option<Verifier> {
+        let (hashes, _) = self.lookup_hashes(&username.to_lowercase()).await;
+        hashes
+            .iter()
+            .filter_map(|hash| Verifier::parse(hash))
+            .find(|verifier| verifier.hash() == hash)
+    }
+
+    /// Verify the client's credentials and return the credentials (and method) for the server.
+    pub async fn authenticate(
+        &self,
+        client: &Credentials,
+    ) -> Result<(Credentials, Method), Rejection> {
+        let password = client.password.declassify();
+        let length = password.chars().count();
+        if length < self.policy.min_length || length > self.policy.max_length {
+            return Err(Rejection::Policy);
+        }
+
+        if client
+            .authzid
+            .as_ref()
+            .is_some_and(|authzid| *authzid != client.username)
+        {
+            return Err(Rejection::Authzid);
+        }
+
+        let (hashes, first) = self.lookup_hashes(&client.username.to_lowercase()).await;
+        let hash = hashes.into_iter().next();
+        let known = hash.is_some();
+
+        // Hashing is slow by design, so it mustn't block other sessions.
+        let password = password.clone();
+        let verified = tokio::task::spawn_blocking(move || {
+            // Unknown users are verified, too, so the answer doesn't reveal them by its timing.
+            let hash = hash
+                .or_else(|| first.as_deref().and_then(mock_hash))
+                .unwrap_or_else(|| MOCK_HASH.clone());
+            verify(&password, &hash)
+        })
+        .await
+        .unwrap_or(false);
+        if !known {
+            return Err(Rejection::UnknownUser);
+        }
+        if !verified {
+            return Err(Rejection::WrongPassword);
+        }
+
+        self.map(&client.username).await
+    }
+
+    /// Return the credentials (and method) for the server of an authenticated user.
+    pub async fn map(&self, client_username: &str) -> Result<(Credentials, Method), Rejection> {
+        let username = client_username.to_lowercase();
+        let mapping = self.mapping(&username).ok_or(Rejection::Unmapped)?;
+        let (password, method) = match &mapping.secret {
+            // Only AUTHENTICATE PLAIN can carry an authorization identity.
+            MappedSecret::Password(password) if mapping.impersonate => (
+                password.clone(),
+                Method::AuthenticatePlain {
+                    initial_response: false,
+                },
+            ),
+            MappedSecret::Password(password) => (password.clone(), Method::Login),
+            MappedSecret::RefreshToken(tokens) => {
+                let access_token = self.access_token(&username, tokens).await?;
+                let method = match self.mechanism {
+                    OAuth2Mechanism::XOAuth2 => Method::AuthenticateXOAuth2,
+                    OAuth2Mechanism::OAuthBearer => Method::AuthenticateOAuthBearer,
+                };
+                (access_token, method)
+            }
+        };
+
+        let credentials = Credentials {
+            authzid: mapping.impersonate.then(|| client_username.to_owned()),
+            username: mapping.username.clone(),
+            password,
+        };
+
+        Ok((credentials, method))
+    }
+
+    /// The server rejected the mapped credentials of `username`.
+    ///
+    /// Drops the cached access token (if any), so the next login obtains a new one.
+    pub async fn report_rejected(&self, username: &str) {
+        if let Some(Mapping {
+            secret: MappedSecret::RefreshToken(tokens),
+            ..
+        }) = self.mapping(&username.to_lowercase())
+        {
+            tokens.lock().await.access_token = None;
+        }
+    }
+
+    fn mapping(&self, username: &str) -> Option<&Mapping> {
+        self.users.get(username).or(self.upstream.as_ref())
+    }
+
+    /// Cached access token, or a new one when it expired.
+    async fn access_token(
+        &self,
+        username: &str,
+        tokens: &Mutex<Tokens>,
+    ) -> Result<Secret<String>, Rejection> {
+        // Unwrap: Refresh tokens require a token endpoint (see `Mapping::new`).
+        let token_endpoint = self.token_endpoint.as_ref().unwrap();
+
+        // Holding the lock makes concurrent logins wait for a single refresh.
+        let mut tokens = tokens.lock().await;
+        if let Some((access_token, expires_at)) = &tokens.access_token {
+            if expires_at.is_none_or(|expires_at| Instant::now() + TOKEN_EXPIRY_MARGIN < expires_at)
+            {
+                return Ok(access_token.clone());
+            }
+        }
+
+        let token = token_endpoint
+            .refresh(tokens.refresh_token.declassify())
+            .await?;
+        info!(username, "Obtained access token");
+        if let Some(refresh_token) = token.refresh_token {
+            // The previous refresh token may be revoked, so the new one must survive restarts.
+            save_refresh_token(username, &tokens.source, refresh_token.declassify()).await;
+            tokens.refresh_token = refresh_token;
+        }
+        tokens.access_token = Some((token.access_token.clone(), token.expires_at));
+
+        Ok(token.access_token)
+    }
+
+    /// Hashes of `username` (in the order of the file).
+    async fn lookup_hashes(&self, username: &str) -> (Vec<String>, Option<String>) {
+        let path = &self.users_path;
+
+        let content = match tokio::fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(error) => {
+                error!(path, %error, "Failed to read users file");
+                return (Vec::new(), None);
+            }
+        };
+
+        let mut hashes = Vec::new();
+        let mut first = None;
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let (Some(user), Some(hash), None) = (fields.next(), fields.next(), fields.next())
+            else {
+                warn!(path, "Ignored malformed line in users file");
+                continue;
+            };
+
+            if first.is_none() {
+                first = Some(hash.to_owned());
+            }
+            if user.to_lowercase() == username {
+                hashes.push(hash.to_owned());
+            }
+        }
+
+        (hashes, first)
+    }
+}
+
+/// Save a refresh token the token endpoint rotated.
+async fn save_refresh_token(username: &str, source: &config::Secret, refresh_token: &str) {
+    match source {
+        config::Secret::File { path } => match util::save_secret(path, refresh_token).await {
+            Ok(()) => info!(username, path, "Saved rotated refresh token"),
+            Err(error) => error!(
+                username,
+                path,
+                ?error,
+                "Failed to save rotated refresh token"
+            ),
+        },
+        config::Secret::Env { name } => error!(
+            username,
+            name, "Rotated refresh token can't be saved to an environment variable (use a file)"
+        ),
+    }
+}
+
+/// Hash with the algorithm and parameters of `hash` that no password matches.
+///
+/// Verifying a password against it takes as long as against `hash`.
+fn mock_hash(hash: &str) -> Option<String> {
+    if hash.starts_with("$argon2") {
+        let mut mock = PasswordHash::new(hash).ok()?;
+        let length = mock.hash?.len();
+        mock.salt = Some(Salt::from_b64(MOCK_SALT).ok()?);
+        mock.hash = Some(Output::new(&vec![0; length]).ok()?);
+        Some(mock.to_string())
+    } else if hash.starts_with("$2") {
+        let cost = hash.parse::<bcrypt::HashParts>().ok()?.get_cost();
+        // Salt and hash in bcrypt's Base64 (where "." is zero).
+        Some(format!("$2b${cost:02}${}", ".".repeat(53)))
+    } else if hash.starts_with("SCRAM-") {
+        Some(Verifier::parse(hash)?.mock_like().to_string())
+    } else {
+        None
+    }
+}
+
+/// Verify a password against an argon2 or bcrypt hash (or a SCRAM verifier).
+fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        match PasswordHash::new(hash) {
+            Ok(hash) => Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok(),
+            Err(error) => {
+                warn!(%error, "Ignored malformed argon2 hash");
+                false
+            }
+        }
+    } else if hash.starts_with("$2") {
+        match bcrypt::verify(password, hash) {
+            Ok(verified) => verified,
+            Err(error) => {
+                warn!(%error, "Ignored malformed bcrypt hash");
+                false
+            }
+        }
+    } else if hash.starts_with("SCRAM-") {
+        match Verifier::parse(hash) {
+            Some(verifier) => verifier.verify(password),
+            None => {
+                warn!("Ignored malformed SCRAM verifier");
+                false
+            }
+        }
+    } else {
+        warn!("Ignored hash of unknown algorithm");
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs::Permissions, os::unix::fs::PermissionsExt};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::config::{OAuth2, Secret as SecretSource, UpstreamCredentials};
+
+    fn credentials(authzid: Option<&str>, username: &str, password: &str) -> Credentials {
+        Credentials {
+            authzid: authzid.map(str::to_owned),
+            username: username.into(),
+            password: Secret::new(password.into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let directory = std::env::temp_dir().join(format!("imap-proxy-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let users_path = directory.join("users");
+        let password_path = directory.join("password");
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        std::fs::write(
+            &users_path,
+            format!("alice {hash}\nbob {hash}\ncarol {hash}\n"),
+        )
+        .unwrap();
+        std::fs::write(&password_path, "server-secret\n").unwrap();
+        let password = SecretSource::File {
+            path: password_path.to_str().unwrap().into(),
+        };
+
+        let mut terminator = Terminator::new(&AuthTermination {
+            users_path: users_path.to_str().unwrap().into(),
+            password_policy: PasswordPolicy {
+                min_length: 4,
+                max_length: 8,
+            },
+            upstream: Some(UpstreamCredentials {
+                username: "master".into(),
+                password: Some(password.clone()),
+                refresh_token: None,
+                impersonate: true,
+            }),
+            users: BTreeMap::from([(
+                "Bob".into(),
+                UpstreamCredentials {
+                    username: "bob@server".into(),
+                    password: Some(password),
+                    refresh_token: None,
+                    impersonate: false,
+                },
+            )]),
+            oauth2: None,
+            scram: Vec::new(),
+        })
+        .unwrap();
+
+        // Mapped to `upstream` (impersonating the client).
+        let (server, method) = terminator
+            .authenticate(&credentials(None, "Alice", "secret"))
+            .await
+            .unwrap();
+        assert_eq!(Some("Alice"), server.authzid.as_deref());
+        assert_eq!("master", server.username);
+        assert_eq!("server-secret", server.password.declassify());
+        assert_eq!(
+            Method::AuthenticatePlain {
+                initial_response: false
+            },
+            method
+        );
+
+        // Mapped to `users`.
+        let (server, method) = terminator
+            .authenticate(&credentials(Some("bob"), "bob", "secret"))
+            .await
+            .unwrap();
+        assert_eq!(None, server.authzid);
+        assert_eq!("bob@server", server.username);
+        assert_eq!(Method::Login, method);
+
+        let rejected = [
+            (credentials(None, "alice", "sec"), "Policy"),
+            (credentials(None, "alice", "secret123"), "Policy"),
+            (credentials(Some("bob"), "alice", "secret"), "Authzid"),
+            (credentials(None, "dave", "secret"), "UnknownUser"),
+            (credentials(None, "alice", "Secret"), "WrongPassword"),
+        ];
+        for (credentials, expected) in rejected {
+            let got = terminator.authenticate(&credentials).await.unwrap_err();
+            assert_eq!(expected, format!("{got:?}"));
+        }
+
+        // Without `upstream`, only users in `users` are mapped.
+        terminator.upstream = None;
+        let got = terminator
+            .authenticate(&credentials(None, "carol", "secret"))
+            .await
+            .unwrap_err();
+        assert!(matches!(got, Rejection::Unmapped));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_verify() {
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let argon2 = Argon2::default()
+            .hash_password(b"secret", &salt)
+            .unwrap()
+            .to_string();
+        let bcrypt = bcrypt::hash("secret", 4).unwrap();
+        let scram = Verifier::new(
+            Hash::Sha256,
+            "secret",
+            b"salt",
+            std::num::NonZeroU32::new(16).unwrap(),
+        )
+        .to_string();
+
+        for hash in [argon2, bcrypt, scram] {
+            assert!(verify("secret", &hash));
+            assert!(!verify("Secret", &hash));
+        }
+        assert!(!verify("secret", "$argon2id$broken"));
+        assert!(!verify("secret", "secret"));
+    }
+
+    #[test]
+    fn test_mock_hash() {
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let params = argon2::Params::new(1024, 3, 2, Some(16)).unwrap();
+        let argon2 = Argon2::new(argon2::Algorithm::Argon2i, argon2::Version::V0x13, params)
+            .hash_password(b"secret", &salt)
+            .unwrap()
+            .to_string();
+        let bcrypt = bcrypt::hash("secret", 5).unwrap();
+        let scram = Verifier::new(
+            Hash::Sha1,
+            "secret",
+            b"salt",
+            std::num::NonZeroU32::new(16).unwrap(),
+        )
+        .to_string();
+
+        let tests = [
+            (argon2, "$argon2i$v=19$m=1024,t=3,p=2$"),
+            (bcrypt, "$2b$05$"),
+            (scram, "SCRAM-SHA-1$16:"),
+        ];
+        for (hash, parameters) in tests {
+            let mock = mock_hash(&hash).unwrap();
+            assert!(mock.starts_with(parameters), "{mock}");
+            assert_ne!(hash, mock);
+            // Malformed hashes would be rejected without hashing.
+            let well_formed = PasswordHash::new(&mock).is_ok()
+                || bcrypt::verify("", &mock).is_ok()
+                || Verifier::parse(&mock).is_some();
+            assert!(well_formed, "{mock}");
+            assert!(!verify("secret", &mock));
+            assert!(!verify("", &mock));
+        }
+        assert_eq!(None, mock_hash("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_rotated_refresh_token() {
+        // Token endpoint that rotates the refresh token.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 4096];
+            let _ = stream.read(&mut request).await.unwrap();
+            let response = b"HTTP/1.1 200 OK\r\n\r\n{\"access_token\": \"access\", \"refresh_token\": \"new\"}";
+            stream.write_all(response).await.unwrap();
+        });
+
+        let directory = std::env::temp_dir().join(format!("imap-proxy-rt-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let refresh_token_path = directory.join("refresh-token");
+        std::fs::write(&refresh_token_path, "old\n").unwrap();
+        std::fs::set_permissions(&refresh_token_path, Permissions::from_mode(0o640)).unwrap();
+
+        let terminator = Terminator::new(&AuthTermination {
+            users_path: directory.join("users").to_str().unwrap().into(),
+            password_policy: PasswordPolicy::default(),
+            upstream: Some(UpstreamCredentials {
+                username: "alice@server".into(),
+                password: None,
+                refresh_token: Some(SecretSource::File {
+                    path: refresh_token_path.to_str().unwrap().into(),
+                }),
+                impersonate: false,
+            }),
+            users: BTreeMap::new(),
+            oauth2: Some(OAuth2 {
+                token_endpoint: format!("http://127.0.0.1:{port}/token"),
+                client_id: "proxy".into(),
+                client_secret: None,
+                mechanism: OAuth2Mechanism::XOAuth2,
+                tls: Default::default(),
+                timeout: 5,
+            }),
+            scram: Vec::new(),
+        })
+        .unwrap();
+
+        let (server, method) = terminator.map("alice").await.unwrap();
+        assert_eq!("access", server.password.declassify());
+        assert_eq!(Method::AuthenticateXOAuth2, method);
+        assert_eq!(
+            "new\n",
+            std::fs::read_to_string(&refresh_token_path).unwrap()
+        );
+        let metadata = std::fs::metadata(&refresh_token_path).unwrap();
+        assert_eq!(0o640, metadata.permissions().mode() & 0o777);
+        // No temporary file is left behind.
+        assert_eq!(1, std::fs::read_dir(&directory).unwrap().count());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,274 @@
+//! Protection against password guessing (failed authentications per IP address and username).
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Mutex, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{Blocked, BruteForce},
+    util,
+};
+
+/// How often to write changed failures to the state file.
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct Throttle {
+    /// Count failures of the last this many seconds.
+    window: u64,
+    max_failures_per_ip: usize,
+    max_failures_per_user: usize,
+    delay: Duration,
+    max_delay: Duration,
+    blocked: Blocked,
+    state_path: Option<String>,
+    failures: Mutex<Failures>,
+}
+
+/// Times of failures (in seconds since the Unix epoch), oldest first.
+#[derive(Default, Deserialize, Serialize)]
+struct Failures {
+    ips: HashMap<IpAddr, VecDeque<u64>>,
+    /// Keyed by lowercase username.
+    users: HashMap<String, VecDeque<u64>>,
+    /// Changed since the last save?
+    #[serde(skip)]
+    dirty: bool,
+    #[serde(skip)]
+    last_pruned: u64,
+}
+
+impl Failures {
+    /// Forget failures that left the window.
+    fn prune(&mut self, now: u64, window: u64) {
+        let expired = |times: &mut VecDeque<u64>| {
+            while times.front().is_some_and(|time| time + window <= now) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        };
+        self.ips.retain(|_, times| expired(times));
+        self.users.retain(|_, times| expired(times));
+        self.last_pruned = now;
+    }
+
+    fn count(times: Option<&VecDeque<u64>>, now: u64, window: u64) -> usize {
+        times.map_or(0, |times| {
+            times.iter().filter(|time| *time + window > now).count()
+        })
+    }
+}
+
+impl Throttle {
+    pub fn new(config: &BruteForce) -> Self {
+        let failures = match &config.state_path {
+            Some(path) => load(path),
+            None => Failures::default(),
+        };
+
+        Self {
+            window: config.window,
+            max_failures_per_ip: config.max_failures_per_ip,
+            max_failures_per_user: config.max_failures_per_user,
+            delay: Duration::from_secs(config.delay),
+            max_delay: Duration::from_secs(config.max_delay),
+            blocked: config.blocked,
+            state_path: config.state_path.clone(),
+            failures: Mutex::new(failures),
+        }
+    }
+
+    pub fn blocked(&self) -> Blocked {
+        self.blocked
+    }
+
+    /// Should an attempt be rejected without trying the credentials?
+    pub fn is_blocked(&self, ip: Option<IpAddr>, username: Option<&str>) -> bool {
+        let now = now();
+        // Unwrap: The lock is never poisoned (no panics while it is held).
+        let failures = self.failures.lock().unwrap();
+
+        let ip_failures =
+            Failures::count(ip.and_then(|ip| failures.ips.get(&ip)), now, self.window);
+        let user_failures = Failures::count(
+            username.and_then(|username| failures.users.get(&username.to_lowercase())),
+            now,
+            self.window,
+        );
+
+        let blocked =
+            ip_failures >= self.max_failures_per_ip || user_failures >= self.max_failures_per_user;
+        if blocked {
+            info!(
+                ip = ip.map(|ip| ip.to_string()),
+                username, ip_failures, user_failures, "Blocked authentication attempt"
+            );
+        }
+
+        blocked
+    }
+
+    /// Record a failed attempt and return how long to delay its answer.
+    pub fn failed(&self, ip: Option<IpAddr>, username: Option<&str>) -> Duration {
+        // Stable message for fail2ban's `failregex` (see README).
+        warn!(
+            "Authentication failure; rhost={} user={}",
+            ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            username.unwrap_or_default()
+        );
+
+        let now = now();
+        // Unwrap: The lock is never poisoned (no panics while it is held).
+        let mut failures = self.failures.lock().unwrap();
+        if failures.last_pruned + self.window <= now {
+            failures.prune(now, self.window);
+        }
+
+        let mut count = 0;
+        if let Some(ip) = ip {
+            let times = failures.ips.entry(ip).or_default();
+            times.push_back(now);
+            count = Failures::count(Some(times), now, self.window);
+        }
+        if let Some(username) = username {
+            let times = failures.users.entry(username.to_lowercase()).or_default();
+            times.push_back(now);
+            count = count.max(Failures::count(Some(times), now, self.window));
+        }
+        failures.dirty = true;
+
+        // The first failure is delayed by `delay`, every further one twice as long.
+        let doublings = u32::try_from(count.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.delay
+            .checked_mul(2u32.checked_pow(doublings).unwrap_or(u32::MAX))
+            .unwrap_or(Duration::MAX)
+            .min(self.max_delay)
+    }
+
+    /// Forget the failures of a username that authenticated successfully.
+    ///
+    /// Failures of the IP address are kept (it may guess passwords of other users).
+    pub fn succeeded(&self, username: &str) {
+        // Unwrap: The lock is never poisoned (no panics while it is held).
+        let mut failures = self.failures.lock().unwrap();
+        if failures.users.remove(&username.to_lowercase()).is_some() {
+            failures.dirty = true;
+        }
+    }
+
+    /// Write the failures to the state file (if any changed since the last save).
+    pub async fn save(&self) {
+        let Some(path) = &self.state_path else {
+            return;
+        };
+
+        let state = {
+            // Unwrap: The lock is never poisoned (no panics while it is held).
+            let mut failures = self.failures.lock().unwrap();
+            if !failures.dirty {
+                return;
+            }
+            failures.prune(now(), self.window);
+            failures.dirty = false;
+            // Unwrap: Serializing maps with string keys never fails.
+            serde_json::to_vec(&*failures).unwrap()
+        };
+
+        // The state contains usernames and IP addresses of clients.
+        if let Err(error) = util::write_atomically(path, &state, 0o600).await {
+            error!(?error, path, "Failed to save authentication failures");
+        }
+    }
+}
+
+/// Save the failures periodically (until the throttle is dropped).
+pub async fn save_periodically(throttle: Weak<Throttle>) {
+    let mut interval = tokio::time::interval(SAVE_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+
+        let Some(throttle) = throttle.upgrade() else {
+            return;
+        };
+        throttle.save().await;
+    }
+}
+
+/// Read the failures saved before a restart.
+///
+/// A missing or broken state file starts with no failures (the proxy must start anyway).
+fn load(path: &str) -> Failures {
+    let state = match std::fs::read(path) {
+        Ok(state) => state,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Failures::default(),
+        Err(error) => {
+            warn!(?error, path, "Failed to read authentication failures");
+            return Failures::default();
+        }
+    };
+
+    match serde_json::from_slice(&state) {
+        Ok(failures) => failures,
+        Err(error) => {
+            warn!(?error, path, "Failed to parse authentication failures");
+            Failures::default()
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let throttle = Throttle::new(&BruteForce {
+            window: 900,
+            max_failures_per_ip: 4,
+            max_failures_per_user: 3,
+            delay: 1,
+            max_delay: 3,
+            blocked: Blocked::Reject,
+            state_path: None,
+        });
+        let ip = Some("192.0.2.1".parse().unwrap());
+        let other_ip = Some("192.0.2.2".parse().unwrap());
+
+        assert!(!throttle.is_blocked(ip, Some("alice")));
+        assert_eq!(Duration::from_secs(1), throttle.failed(ip, Some("alice")));
+        assert_eq!(Duration::from_secs(2), throttle.failed(ip, Some("Alice")));
+        assert_eq!(
+            Duration::from_secs(3),
+            throttle.failed(other_ip, Some("alice"))
+        );
+        assert!(throttle.is_blocked(None, Some("ALICE")));
+        assert!(!throttle.is_blocked(ip, Some("bob")));
+
+        // Success forgets the username, but not the IP address.
+        throttle.succeeded("alice");
+        assert!(!throttle.is_blocked(other_ip, Some("alice")));
+        throttle.failed(ip, Some("bob"));
+        assert!(!throttle.is_blocked(ip, None));
+        throttle.failed(ip, Some("carol"));
+        assert!(throttle.is_blocked(ip, None));
+        assert!(!throttle.is_blocked(other_ip, None));
+
+        // Failures leave the window.
+        throttle.failures.lock().unwrap().prune(now() + 900, 900);
+        assert!(!throttle.is_blocked(ip, Some("alice")));
+    }
+}
@@ -0,0 +1,796 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock, Weak},
+    time::{Duration, SystemTime},
+};
+
+use aws_lc_rs::digest;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    signal::unix::Signal,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        client::{
+            danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+            Resumption, WebPkiServerVerifier,
+        },
+        crypto::{
+            aws_lc_rs::default_provider, verify_tls12_signature, verify_tls13_signature,
+            CryptoProvider,
+        },
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+        server::{
+            ClientHello, NoServerSessionStorage, ResolvesServerCert, ServerConnection,
+            WebPkiClientVerifier,
+        },
+        sign::CertifiedKey,
+        version::{TLS12, TLS13},
+        CertificateError, ClientConfig, CommonState, DigitallySignedStruct, ProtocolVersion,
+        RootCertStore, ServerConfig, SignatureScheme, SupportedProtocolVersion,
+    },
+    server, TlsAcceptor, TlsConnector,
+};
+use tracing::{error, info, warn};
+use webpki::EndEntityCert;
+use x509_parser::{certificate::X509Certificate, oid_registry, prelude::FromDer};
+
+use crate::{
+    config::{BindTls, ClientAuthMode, ConnectTls, Identity, Route, SniFallback, TlsVersion},
+    proxy::ProxyError,
+    util,
+};
+
+/// How often the identity files are checked for modifications.
+const IDENTITY_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// TLS acceptor that is shared by all connections of a service.
+///
+/// The acceptor is rebuilt when its identity changes on disk. Connections that are already
+/// established keep using the acceptor they were accepted with.
+pub struct ReloadableAcceptor {
+    identity: Identity,
+    options: BindTls,
+    routes: Vec<Route>,
+    sni_fallback: SniFallback,
+    current: RwLock<Acceptor>,
+}
+
+impl ReloadableAcceptor {
+    pub fn new(
+        identity: Identity,
+        options: BindTls,
+        routes: Vec<Route>,
+        sni_fallback: SniFallback,
+    ) -> Result<Self, ProxyError> {
+        let acceptor = build_acceptor(&identity, &options, &routes, sni_fallback)?;
+
+        Ok(Self {
+            identity,
+            options,
+            routes,
+            sni_fallback,
+            current: RwLock::new(acceptor),
+        })
+    }
+
+    /// Acceptor to be used for the next connection.
+    pub fn current(&self) -> Acceptor {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Rebuild the acceptor from disk.
+    ///
+    /// The current acceptor is kept if the identity can't be loaded.
+    pub fn reload(&self) -> Result<(), ProxyError> {
+        let acceptor = build_acceptor(
+            &self.identity,
+            &self.options,
+            &self.routes,
+            self.sni_fallback,
+        )?;
+        *self.current.write().unwrap() = acceptor;
+        Ok(())
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        let client_auth_paths = self
+            .options
+            .client_auth
+            .iter()
+            .map(|client_auth| client_auth.ca_bundle_path.as_str());
+
+        let route_paths = self.routes.iter().flat_map(|route| route.identity.paths());
+
+        self.identity
+            .paths()
+            .into_iter()
+            .chain(route_paths)
+            .chain(client_auth_paths)
+            .map(|path| {
+                std::fs::metadata(path)
+                    .and_then(|meta| meta.modified())
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+/// Reload the acceptor on SIGHUP or when one of its identity files was modified.
+///
+/// Stops once the acceptor was dropped.
+pub async fn watch_identity(acceptor: Weak<ReloadableAcceptor>, mut hangup: Signal) {
+    let mut last_modified = match acceptor.upgrade() {
+        Some(acceptor) => acceptor.modified(),
+        None => return,
+    };
+
+    let mut interval = tokio::time::interval(IDENTITY_POLL_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        let forced = tokio::select! {
+            _ = hangup.recv() => true,
+            _ = interval.tick() => false,
+        };
+
+        let Some(acceptor) = acceptor.upgrade() else {
+            return;
+        };
+
+        let modified = acceptor.modified();
+        if !forced && modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+
+        match acceptor.reload() {
+            Ok(()) => info!(forced, "Reloaded TLS identity"),
+            Err(error) => error!(
+                ?error,
+                "Failed to reload TLS identity, keeping previous one"
+            ),
+        }
+    }
+}
+
+/// TLS acceptor and the certificates it presents.
+#[derive(Clone)]
+pub struct Acceptor {
+    acceptor: TlsAcceptor,
+    resolver: Arc<SniResolver>,
+    /// Subjects of accepted client certificates (any, if empty).
+    subjects: Arc<[String]>,
+}
+
+impl Acceptor {
+    /// Perform the TLS handshake and apply the client certificate policy.
+    pub async fn accept<S>(&self, stream: S) -> std::io::Result<server::TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let stream = self.acceptor.accept(stream).await?;
+
+        let subject = peer_subject(stream.get_ref().1);
+        if !allows(&self.subjects, subject.as_deref()) {
+            let reason = match subject {
+                Some(subject) => format!("Client certificate subject \"{subject}\" is not allowed"),
+                None => "Client certificate required".to_owned(),
+            };
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                reason,
+            ));
+        }
+
+        Ok(stream)
+    }
+
+    /// Channel bindings of a connection accepted by this acceptor.
+    pub fn channel_bindings(&self, connection: &ServerConnection) -> ChannelBindings {
+        // RFC 9266 requires TLS 1.3 (or the extended master secret, which rustls doesn't enforce).
+        let exporter = match connection.protocol_version() {
+            Some(ProtocolVersion::TLSv1_3) => connection
+                .export_keying_material([0; 32], b"EXPORTER-Channel-Binding", None)
+                .ok()
+                .map(Vec::from),
+            _ => None,
+        };
+
+        let server_end_point = self
+            .resolver
+            .certified_key(connection.server_name())
+            .and_then(|certified_key| server_end_point(certified_key.end_entity_cert().ok()?));
+
+        ChannelBindings {
+            exporter,
+            server_end_point,
+        }
+    }
+}
+
+/// Channel binding data of a client's TLS connection.
+#[derive(Clone, Debug, Default)]
+pub struct ChannelBindings {
+    /// "tls-exporter" (RFC 9266), `None` before TLS 1.3.
+    pub exporter: Option<Vec<u8>>,
+    /// "tls-server-end-point" (RFC 5929), the hash of the proxy's certificate.
+    pub server_end_point: Option<Vec<u8>>,
+}
+
+/// Hash of a certificate as specified for "tls-server-end-point".
+///
+/// The hash function is the one of the certificate's signature algorithm, but at least SHA-256.
+fn server_end_point(certificate: &CertificateDer) -> Option<Vec<u8>> {
+    let (_, parsed) = X509Certificate::from_der(certificate).ok()?;
+    let algorithm = &parsed.signature_algorithm.algorithm;
+
+    let hash = if *algorithm == oid_registry::OID_PKCS1_SHA384WITHRSA
+        || *algorithm == oid_registry::OID_SIG_ECDSA_WITH_SHA384
+    {
+        &digest::SHA384
+    } else if *algorithm == oid_registry::OID_PKCS1_SHA512WITHRSA
+        || *algorithm == oid_registry::OID_SIG_ECDSA_WITH_SHA512
+    {
+        &digest::SHA512
+    } else {
+        &digest::SHA256
+    };
+
+    Some(digest::digest(hash, certificate).as_ref().to_vec())
+}
+
+fn build_acceptor(
+    identity: &Identity,
+    options: &BindTls,
+    routes: &[Route],
+    sni_fallback: SniFallback,
+) -> Result<Acceptor, ProxyError> {
+    let provider = crypto_provider(&options.cipher_suites)?;
+    let versions = protocol_versions(options.min_version, options.max_version)?;
+
+    let builder =
+        ServerConfig::builder_with_provider(provider.clone()).with_protocol_versions(&versions)?;
+    let builder = match &options.client_auth {
+        Some(client_auth) => {
+            let root_store = load_root_store(&client_auth.ca_bundle_path)?;
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(root_store), provider.clone());
+            let verifier = match client_auth.mode {
+                ClientAuthMode::Required => verifier.build()?,
+                ClientAuthMode::Optional => verifier.allow_unauthenticated().build()?,
+            };
+
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let default = Arc::new(load_certified_key(identity, &provider)?);
+    let mut by_server_name = HashMap::new();
+    for route in routes {
+        let certified_key = Arc::new(load_certified_key(&route.identity, &provider)?);
+        for server_name in &route.server_names {
+            by_server_name.insert(server_name.to_ascii_lowercase(), certified_key.clone());
+        }
+    }
+    let resolver = Arc::new(SniResolver {
+        by_server_name,
+        fallback: match sni_fallback {
+            SniFallback::Default => Some(default),
+            SniFallback::Reject => None,
+        },
+    });
+
+    let mut config = builder.with_cert_resolver(resolver.clone());
+
+    config.alpn_protocols = alpn_protocols(options.alpn_protocols.as_deref());
+    if !options.session_resumption {
+        config.session_storage = Arc::new(NoServerSessionStorage {});
+        config.send_tls13_tickets = 0;
+    }
+
+    let subjects = options
+        .client_auth
+        .iter()
+        .flat_map(|client_auth| client_auth.subjects.iter().cloned())
+        .collect();
+
+    Ok(Acceptor {
+        acceptor: TlsAcceptor::from(Arc::new(config)),
+        resolver,
+        subjects,
+    })
+}
+
+fn load_identity(
+    identity: &Identity,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), ProxyError> {
+    match identity {
+        Identity::CertificateChainAndLeafKey {
+            certificate_chain_path,
+            leaf_key_path,
+        } => {
+            let certificate_chain = util::load_certificate_chain_pem(certificate_chain_path)?;
+            let leaf_key = util::load_leaf_key_pem(leaf_key_path)?;
+
+            Ok((certificate_chain, leaf_key))
+        }
+        Identity::CombinedPem { path } => Ok(util::load_combined_pem(path)?),
+        Identity::Pkcs12 { path, password } => {
+            let password = match password {
+                Some(password) => util::load_secret(password)?,
+                None => String::new(),
+            };
+
+            Ok(util::load_pkcs12(path, &password)?)
+        }
+    }
+}
+
+fn load_certified_key(
+    identity: &Identity,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, ProxyError> {
+    let (certificate_chain, leaf_key) = load_identity(identity)?;
+
+    // Note: We provide the full chain here.
+    Ok(CertifiedKey::from_der(
+        certificate_chain,
+        leaf_key,
+        provider,
+    )?)
+}
+
+/// Selects the certificate by the server name (SNI) a client requested.
+#[derive(Debug)]
+struct SniResolver {
+    by_server_name: HashMap<String, Arc<CertifiedKey>>,
+    /// Certificate for unknown (or missing) server names, `None` to abort the handshake.
+    fallback: Option<Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    fn certified_key(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        server_name
+            .and_then(|server_name| {
+                self.by_server_name
+                    .get(&server_name.to_ascii_lowercase())
+                    .cloned()
+            })
+            .or_else(|| self.fallback.clone())
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.certified_key(client_hello.server_name())
+    }
+}
+
+/// Whether `subjects` allow a client with a certificate of this subject (or without certificate).
+///
+/// Without `subjects`, whether a certificate is required is up to the verifier.
+fn allows(subjects: &[String], subject: Option<&str>) -> bool {
+    match subject {
+        _ if subjects.is_empty() => true,
+        Some(subject) => subjects.iter().any(|allowed| allowed == subject),
+        None => false,
+    }
+}
+
+/// Subject of the (verified) certificate presented by the peer, e.g., "CN=alice, O=Example".
+pub fn peer_subject(connection: &CommonState) -> Option<String> {
+    let certificate = connection.peer_certificates()?.first()?;
+    let (_, certificate) = X509Certificate::from_der(certificate).ok()?;
+
+    Some(certificate.subject().to_string())
+}
+
+/// Establishes TLS connections to the server of a service.
+#[derive(Clone)]
+pub struct ServerConnector {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl ServerConnector {
+    pub fn new(host: &str, options: &ConnectTls) -> Result<Self, ProxyError> {
+        let server_name = options.server_name.as_deref().unwrap_or(host);
+        let server_name = ServerName::try_from(server_name.to_owned())?;
+
+        let provider = crypto_provider(&options.cipher_suites)?;
+        let versions = protocol_versions(options.min_version, options.max_version)?;
+
+        let verifier = if options.danger_accept_invalid_certs {
+            warn!(
+                ?server_name,
+                "Server certificate verification is DISABLED (`danger_accept_invalid_certs`)"
+            );
+            None
+        } else {
+            let root_store = match &options.ca_bundle_path {
+                Some(path) => load_root_store(path)?,
+                None => load_native_root_store()?,
+            };
+
+            let verifier =
+                WebPkiServerVerifier::builder_with_provider(Arc::new(root_store), provider.clone())
+                    .build()?;
+
+            Some(verifier)
+        };
+
+        let pins: Vec<_> = options
+            .pinned_certificate_sha256
+            .iter()
+            .map(|fingerprint| Pin::Certificate(fingerprint.0))
+            .chain(
+                options
+                    .pinned_spki_sha256
+                    .iter()
+                    .map(|fingerprint| Pin::Spki(fingerprint.0)),
+            )
+            .collect();
+
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&versions)?;
+        let builder = match verifier {
+            Some(verifier) if pins.is_empty() => builder.with_webpki_verifier(verifier),
+            verifier => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinningVerifier {
+                    verifier,
+                    pins,
+                    provider,
+                })),
+        };
+        let mut config = match &options.client_identity {
+            Some(identity) => {
+                let (certificate_chain, leaf_key) = load_identity(identity)?;
+                builder.with_client_auth_cert(certificate_chain, leaf_key)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        config.alpn_protocols = alpn_protocols(options.alpn_protocols.as_deref());
+        if !options.session_resumption {
+            config.resumption = Resumption::disabled();
+        }
+
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    pub async fn connect<S>(&self, stream: S) -> std::io::Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await?;
+        log_negotiated("p2s", stream.get_ref().1);
+
+        Ok(stream)
+    }
+}
+
+/// Default crypto provider, restricted to the given cipher suites (if any).
+fn crypto_provider(cipher_suites: &[String]) -> Result<Arc<CryptoProvider>, ProxyError> {
+    let mut provider = default_provider();
+
+    if !cipher_suites.is_empty() {
+        provider.cipher_suites = cipher_suites
+            .iter()
+            .map(|name| {
+                provider
+                    .cipher_suites
+                    .iter()
+                    .find(|suite| format!("{:?}", suite.suite()) == *name)
+                    .copied()
+                    .ok_or_else(|| ProxyError::UnknownCipherSuite(name.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+    }
+
+    Ok(Arc::new(provider))
+}
+
+fn protocol_versions(
+    min_version: Option<TlsVersion>,
+    max_version: Option<TlsVersion>,
+) -> Result<Vec<&'static SupportedProtocolVersion>, ProxyError> {
+    let min_version = min_version.unwrap_or(TlsVersion::Tls12);
+    let max_version = max_version.unwrap_or(TlsVersion::Tls13);
+
+    let versions: Vec<_> = [(TlsVersion::Tls12, &TLS12), (TlsVersion::Tls13, &TLS13)]
+        .into_iter()
+        .filter(|(version, _)| (min_version..=max_version).contains(version))
+        .map(|(_, version)| version)
+        .collect();
+
+    if versions.is_empty() {
+        return Err(ProxyError::NoTlsVersion);
+    }
+
+    Ok(versions)
+}
+
+fn alpn_protocols(protocols: Option<&[String]>) -> Vec<Vec<u8>> {
+    match protocols {
+        Some(protocols) => protocols
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect(),
+        // See <https://www.iana.org/assignments/tls-extensiontype-values/tls-extensiontype-values.xhtml#alpn-protocol-ids>
+        None => vec![b"imap".to_vec()],
+    }
+}
+
+/// Log the parameters negotiated during a TLS handshake.
+pub fn log_negotiated(role: &'static str, connection: &CommonState) {
+    info!(
+        role,
+        version = ?connection.protocol_version(),
+        cipher_suite = ?connection.negotiated_cipher_suite().map(|suite| suite.suite()),
+        alpn = ?connection.alpn_protocol().map(String::from_utf8_lossy),
+        handshake = ?connection.handshake_kind(),
+        "Negotiated TLS"
+    );
+}
+
+fn load_root_store(path: &str) -> Result<RootCertStore, ProxyError> {
+    let mut root_store = RootCertStore::empty();
+
+    for certificate in util::load_certificate_chain_pem(path)? {
+        root_store.add(certificate)?;
+    }
+
+    Ok(root_store)
+}
+
+fn load_native_root_store() -> Result<RootCertStore, ProxyError> {
+    let result = rustls_native_certs::load_native_certs();
+
+    for error in &result.errors {
+        warn!(%error, "Failed to load native root certificate(s)");
+    }
+
+    let mut root_store = RootCertStore::empty();
+    let (added, ignored) = root_store.add_parsable_certificates(result.certs);
+    if ignored > 0 {
+        warn!(
+            added,
+            ignored, "Ignored unparsable native root certificate(s)"
+        );
+    }
+
+    Ok(root_store)
+}
+
+#[derive(Debug)]
+enum Pin {
+    /// SHA-256 fingerprint of the certificate.
+    Certificate([u8; 32]),
+    /// SHA-256 fingerprint of the certificate's SubjectPublicKeyInfo.
+    Spki([u8; 32]),
+}
+
+impl Pin {
+    fn matches(&self, end_entity: &CertificateDer) -> bool {
+        match self {
+            Self::Certificate(fingerprint) => sha256(end_entity) == *fingerprint,
+            Self::Spki(fingerprint) => match EndEntityCert::try_from(end_entity) {
+                Ok(certificate) => sha256(&certificate.subject_public_key_info()) == *fingerprint,
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let digest = digest::digest(&digest::SHA256, data);
+    // Unwrap: A SHA-256 digest is 32 bytes long.
+    digest.as_ref().try_into().unwrap()
+}
+
+/// Verifies the server certificate against pins (and optionally the trust anchors).
+#[derive(Debug)]
+struct PinningVerifier {
+    /// Verifier for trust anchors, `None` to accept invalid certificates.
+    verifier: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<Pin>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        match &self.verifier {
+            Some(verifier) => {
+                verifier.verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    ocsp_response,
+                    now,
+                )?;
+            }
+            None => {
+                warn!(
+                    ?server_name,
+                    "Accepting server certificate without verification"
+                );
+            }
+        }
+
+        if !self.pins.is_empty() && !self.pins.iter().any(|pin| pin.matches(end_entity)) {
+            return Err(CertificateError::ApplicationVerificationFailure.into());
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::config::ClientAuth;
+
+    /// Path of a file in the `testdata` directory (see `testdata/generate.sh`).
+    pub(crate) fn testdata(name: &str) -> String {
+        format!("{}/testdata/{name}", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    /// Acceptor presenting `localhost-1` that accepts clients with or without certificate.
+    pub(crate) fn acceptor() -> Acceptor {
+        let identity = Identity::CertificateChainAndLeafKey {
+            certificate_chain_path: testdata("localhost-1.pem"),
+            leaf_key_path: testdata("localhost-1-key.pem"),
+        };
+        let options = BindTls {
+            client_auth: Some(ClientAuth {
+                ca_bundle_path: testdata("ca.pem"),
+                mode: ClientAuthMode::Optional,
+                subjects: vec![],
+            }),
+            ..BindTls::default()
+        };
+
+        ReloadableAcceptor::new(identity, options, vec![], SniFallback::Default)
+            .unwrap()
+            .current()
+    }
+
+    /// Connector that trusts the test CA.
+    pub(crate) fn connector() -> ServerConnector {
+        let options = ConnectTls {
+            ca_bundle_path: Some(testdata("ca.pem")),
+            ..ConnectTls::default()
+        };
+
+        ServerConnector::new("localhost", &options).unwrap()
+    }
+
+    /// Certificate presented by `acceptor` in a handshake over the loopback interface.
+    async fn presented(acceptor: &Acceptor) -> CertificateDer<'static> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let stream = connector().connect(stream).await.unwrap();
+            stream.get_ref().1.peer_certificates().unwrap()[0].clone()
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        acceptor.accept(stream).await.unwrap();
+        client.await.unwrap()
+    }
+
+    fn leaf(name: &str) -> CertificateDer<'static> {
+        util::load_certificate_chain_pem(testdata(name))
+            .unwrap()
+            .remove(0)
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("imap-proxy-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let certificate_chain_path = dir.join("cert.pem");
+        let leaf_key_path = dir.join("key.pem");
+        let install = |name: &str| {
+            std::fs::copy(testdata(&format!("{name}.pem")), &certificate_chain_path).unwrap();
+            std::fs::copy(testdata(&format!("{name}-key.pem")), &leaf_key_path).unwrap();
+        };
+
+        install("localhost-1");
+        let identity = Identity::CertificateChainAndLeafKey {
+            certificate_chain_path: certificate_chain_path.to_str().unwrap().into(),
+            leaf_key_path: leaf_key_path.to_str().unwrap().into(),
+        };
+        let acceptor =
+            ReloadableAcceptor::new(identity, BindTls::default(), vec![], SniFallback::Default)
+                .unwrap();
+        let before = acceptor.current();
+        assert_eq!(leaf("localhost-1.pem"), presented(&before).await);
+
+        // A broken identity keeps the current acceptor.
+        std::fs::write(&leaf_key_path, "garbage").unwrap();
+        assert!(acceptor.reload().is_err());
+        assert_eq!(
+            leaf("localhost-1.pem"),
+            presented(&acceptor.current()).await
+        );
+
+        install("localhost-2");
+        acceptor.reload().unwrap();
+        assert_eq!(
+            leaf("localhost-2.pem"),
+            presented(&acceptor.current()).await
+        );
+        // Connections that were accepted before keep their acceptor.
+        assert_eq!(leaf("localhost-1.pem"), presented(&before).await);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_allows() {
+        assert!(allows(&[], Some("CN=mallory")));
+        assert!(allows(&[], None));
+
+        let subjects = ["CN=alice".to_owned(), "CN=bob, O=Example".to_owned()];
+        assert!(allows(&subjects, Some("CN=alice")));
+        assert!(allows(&subjects, Some("CN=bob, O=Example")));
+        assert!(!allows(&subjects, Some("CN=bob")));
+        assert!(!allows(&subjects, Some("CN=mallory")));
+        assert!(!allows(&subjects, None));
+    }
+}
@@ -0,0 +1,105 @@
+//! Unix domain sockets.
+
+use std::{
+    fs::Permissions,
+    io,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+};
+
+use nix::unistd::{Group, User};
+use thiserror::Error;
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{info, warn};
+
+#[derive(Debug, Error)]
+pub enum UnixError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Lookup(#[from] nix::Error),
+    #[error("Socket \"{0}\" is in use")]
+    InUse(String),
+    #[error("File \"{0}\" exists and is not a socket")]
+    NotASocket(String),
+    #[error("Unknown user \"{0}\"")]
+    UnknownUser(String),
+    #[error("Unknown group \"{0}\"")]
+    UnknownGroup(String),
+}
+
+/// Bind to `path`, replacing a stale socket file, and set permissions and owner.
+pub async fn bind(
+    path: &str,
+    mode: Option<u32>,
+    owner: Option<&str>,
+) -> Result<UnixListener, UnixError> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            // A socket nobody listens on is a leftover, e.g., after a crash.
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(UnixError::InUse(path.to_owned()));
+            }
+            info!(path, "Removing stale socket");
+            std::fs::remove_file(path)?;
+        }
+        Ok(_) => return Err(UnixError::NotASocket(path.to_owned())),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error.into()),
+    }
+
+    let listener = UnixListener::bind(path)?;
+
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+
+    if let Some(owner) = owner {
+        let (uid, gid) = lookup_owner(owner)?;
+        std::os::unix::fs::chown(path, uid, gid)?;
+    }
+
+    Ok(listener)
+}
+
+/// Remove the socket file of a listener that is closed.
+pub fn unbind(path: &str) {
+    match std::fs::remove_file(path) {
+        Ok(()) => info!(path, "Removed socket"),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => warn!(path, %error, "Failed to remove socket"),
+    }
+}
+
+/// Parse "user", "user:group", or ":group" (names or numeric IDs).
+fn lookup_owner(owner: &str) -> Result<(Option<u32>, Option<u32>), UnixError> {
+    let (user, group) = match owner.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (owner, None),
+    };
+
+    let uid = match user {
+        "" => None,
+        user => match user.parse() {
+            Ok(uid) => Some(uid),
+            Err(_) => {
+                let user = User::from_name(user)?
+                    .ok_or_else(|| UnixError::UnknownUser(user.to_owned()))?;
+                Some(user.uid.as_raw())
+            }
+        },
+    };
+
+    let gid = match group {
+        None | Some("") => None,
+        Some(group) => match group.parse() {
+            Ok(gid) => Some(gid),
+            Err(_) => {
+                let group = Group::from_name(group)?
+                    .ok_or_else(|| UnixError::UnknownGroup(group.to_owned()))?;
+                Some(group.gid.as_raw())
+            }
+        },
+    };
+
+    Ok((uid, gid))
+}
@@ -1,21 +1,109 @@
-use std::{fs::File, io::BufReader, path::Path};
+use std::{
+    fs::File,
+    io::BufReader,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
+use imap_codec::{decode::Decoder, ResponseCodec};
 use imap_next::imap_types::{
     auth::AuthMechanism,
     core::Vec1,
     response::{
         Bye, Capability, Code, CommandContinuationRequest, CommandContinuationRequestBasic, Data,
-        Greeting, Status, StatusBody, Tagged,
+        Greeting, Response, Status, StatusBody, Tagged,
     },
+    ToStatic,
 };
+use p12_keystore::KeyStore;
+use rustls_pemfile::Item;
 use thiserror::Error;
-use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio::io::AsyncWriteExt;
+use tokio_rustls::rustls::pki_types::{
+    CertificateDer, PrivateKeyDer, PrivatePkcs1KeyDer, PrivatePkcs8KeyDer, PrivateSec1KeyDer,
+};
 use tracing::warn;
 
+use crate::config::{self, Secret};
+
+/// Capabilities without a variant in imap-types whose syntax imap-codec parses.
+///
+/// NAMESPACE requires the `ext_namespace` feature. The others only add flags.
+const OTHER_SUPPORTED_CAPABILITIES: [&str; 3] = ["NAMESPACE", "CHILDREN", "SPECIAL-USE"];
+
+#[derive(Debug, Error)]
+pub enum CapabilityError {
+    #[error("Invalid capability \"{0}\"")]
+    Invalid(String),
+    #[error("Capability \"{0}\" can't be enabled because its syntax isn't supported")]
+    Unsupported(String),
+}
+
+/// Capabilities that are forwarded to (and announced to) clients.
+#[derive(Default)]
+pub struct CapabilityFilter {
+    allow: Vec<Capability<'static>>,
+    deny: Vec<Capability<'static>>,
+    inject: Vec<Capability<'static>>,
+}
+
+impl CapabilityFilter {
+    pub fn new(config: &config::Capabilities) -> Result<Self, CapabilityError> {
+        let parse_all = |capabilities: &[String]| -> Result<Vec<_>, CapabilityError> {
+            capabilities
+                .iter()
+                .map(|capability| parse_capability(capability))
+                .collect()
+        };
+
+        let allow = parse_all(&config.allow)?;
+        let deny = parse_all(&config.deny)?;
+        let inject = parse_all(&config.inject)?;
+
+        // Enabled capabilities must not introduce syntax the proxy can't parse.
+        for capability in allow.iter().chain(&inject) {
+            if !is_capability_supported(capability) {
+                return Err(CapabilityError::Unsupported(capability.to_string()));
+            }
+        }
+
+        Ok(Self {
+            allow,
+            deny,
+            inject,
+        })
+    }
+
+    /// The capability must not be announced to clients.
+    pub fn is_denied(&self, capability: &Capability) -> bool {
+        contains(&self.deny, capability)
+    }
+
+    // Remove unsupported capabilities in a capability list and add the injected ones.
+    fn filter<'a>(&self, capabilities: Vec1<Capability<'a>>) -> Vec1<Capability<'a>> {
+        let mut filtered: Vec<_> = capabilities
+            .into_iter()
+            .filter(|capability| {
+                (is_capability_forwarded(capability) || contains(&self.allow, capability))
+                    && !contains(&self.deny, capability)
+            })
+            .collect();
+
+        for capability in &self.inject {
+            if !contains(&filtered, capability) {
+                filtered.push(capability.clone());
+            }
+        }
+
+        Vec1::try_from(filtered).unwrap_or(Vec1::from(Capability::Imap4Rev1))
+    }
+}
+
 /// Remove unsupported capabilities in a greetings `Code::Capability`.
-pub fn filter_capabilities_in_greeting(greeting: &mut Greeting) {
+pub fn filter_capabilities_in_greeting(greeting: &mut Greeting, filter: &CapabilityFilter) {
     if let Some(Code::Capability(capabilities)) = &mut greeting.code {
-        let filtered = filter_capabilities(capabilities.clone());
+        let filtered = filter.filter(capabilities.clone());
 
         if *capabilities != filtered {
             warn!(
@@ -29,9 +117,9 @@ pub fn filter_capabilities_in_greeting(greeting: &mut Greeting) {
 }
 
 /// Remove unsupported capabilities in a `Data::Capability`.
-pub fn filter_capabilities_in_data(data: &mut Data) {
+pub fn filter_capabilities_in_data(data: &mut Data, filter: &CapabilityFilter) {
     if let Data::Capability(capabilities) = data {
-        let filtered = filter_capabilities(capabilities.clone());
+        let filtered = filter.filter(capabilities.clone());
 
         if *capabilities != filtered {
             warn!(
@@ -45,7 +133,7 @@ pub fn filter_capabilities_in_data(data: &mut Data) {
 }
 
 /// Remove unsupported capabilities in a status' `Code::Capability`.
-pub fn filter_capabilities_in_status(status: &mut Status) {
+pub fn filter_capabilities_in_status(status: &mut Status, filter: &CapabilityFilter) {
     if let Status::Tagged(Tagged {
         body:
             StatusBody {
@@ -63,7 +151,7 @@ pub fn filter_capabilities_in_status(status: &mut Status) {
         ..
     }) = status
     {
-        let filtered = filter_capabilities(capabilities.clone());
+        let filtered = filter.filter(capabilities.clone());
 
         if *capabilities != filtered {
             warn!(
@@ -77,10 +165,13 @@ pub fn filter_capabilities_in_status(status: &mut Status) {
 }
 
 /// Remove unsupported capabilities in command continuation request response.
-pub fn filter_capabilities_in_continuation(continuation: &mut CommandContinuationRequest) {
+pub fn filter_capabilities_in_continuation(
+    continuation: &mut CommandContinuationRequest,
+    filter: &CapabilityFilter,
+) {
     if let CommandContinuationRequest::Basic(basic) = continuation {
         if let Some(Code::Capability(capabilities)) = basic.code() {
-            let capabilities = filter_capabilities(capabilities.clone());
+            let capabilities = filter.filter(capabilities.clone());
 
             *basic = CommandContinuationRequestBasic::new(
                 Some(Code::Capability(capabilities)),
@@ -91,25 +182,111 @@ pub fn filter_capabilities_in_continuation(continuation: &mut CommandContinuatio
     }
 }
 
-// Remove unsupported capabilities in a capability list.
-fn filter_capabilities(capabilities: Vec1<Capability>) -> Vec1<Capability> {
-    let filtered: Vec<_> = capabilities
+/// Advertise STARTTLS in a greetings `Code::Capability`.
+pub fn advertise_starttls_in_greeting(greeting: &mut Greeting, login_disabled: bool) {
+    if let Some(Code::Capability(capabilities)) = &mut greeting.code {
+        *capabilities = advertise_starttls(capabilities.clone(), login_disabled);
+    }
+}
+
+/// Advertise STARTTLS in a `Data::Capability`.
+pub fn advertise_starttls_in_data(data: &mut Data, login_disabled: bool) {
+    if let Data::Capability(capabilities) = data {
+        *capabilities = advertise_starttls(capabilities.clone(), login_disabled);
+    }
+}
+
+// Add STARTTLS (and LOGINDISABLED) to a capability list.
+//
+// Authentication mechanisms are removed when login is disabled.
+fn advertise_starttls(capabilities: Vec1<Capability>, login_disabled: bool) -> Vec1<Capability> {
+    let mut capabilities: Vec<_> = capabilities
         .into_iter()
-        .filter(|capability| match capability {
-            Capability::Imap4Rev1 => true,
-            Capability::Auth(auth_mechanism) if is_auth_mechanism_proxyable(auth_mechanism) => true,
-            Capability::SaslIr => true,
-            Capability::Quota | Capability::QuotaRes(_) | Capability::QuotaSet => true,
-            Capability::Move => true,
-            Capability::LiteralPlus | Capability::LiteralMinus => true,
-            Capability::Unselect => true,
-            Capability::Id => true,
-            Capability::Idle => true,
-            _ => false,
-        })
+        .filter(|capability| !(login_disabled && matches!(capability, Capability::Auth(_))))
         .collect();
 
-    Vec1::try_from(filtered).unwrap_or(Vec1::from(Capability::Imap4Rev1))
+    capabilities.push(Capability::StartTls);
+    if login_disabled {
+        capabilities.push(Capability::LoginDisabled);
+    }
+
+    Vec1::try_from(capabilities).unwrap()
+}
+
+// Capabilities that are forwarded by default.
+fn is_capability_forwarded(capability: &Capability) -> bool {
+    match capability {
+        Capability::Imap4Rev1 => true,
+        Capability::Auth(auth_mechanism) if is_auth_mechanism_proxyable(auth_mechanism) => true,
+        Capability::SaslIr => true,
+        Capability::Quota | Capability::QuotaRes(_) | Capability::QuotaSet => true,
+        Capability::Move => true,
+        Capability::LiteralPlus | Capability::LiteralMinus => true,
+        Capability::Unselect => true,
+        Capability::Id => true,
+        Capability::Idle => true,
+        _ => false,
+    }
+}
+
+// Capabilities whose syntax imap-codec parses (and that the proxy doesn't manage itself).
+fn is_capability_supported(capability: &Capability) -> bool {
+    match capability {
+        Capability::Imap4Rev1 => true,
+        // AUTHENTICATE has the same syntax for every mechanism
+        Capability::Auth(_) => true,
+        Capability::SaslIr => true,
+        Capability::Idle => true,
+        Capability::Enable => true,
+        Capability::Quota | Capability::QuotaRes(_) | Capability::QuotaSet => true,
+        Capability::LiteralPlus | Capability::LiteralMinus => true,
+        Capability::Move => true,
+        Capability::Id => true,
+        Capability::Unselect => true,
+        Capability::Sort(_) | Capability::Thread(_) => true,
+        Capability::Binary => true,
+        Capability::UidPlus => true,
+        Capability::Namespace => true,
+        Capability::Other(_) => {
+            let capability = capability.to_string();
+
+            OTHER_SUPPORTED_CAPABILITIES
+                .iter()
+                .any(|name| capability.eq_ignore_ascii_case(name))
+        }
+        // Managed by the proxy
+        Capability::StartTls | Capability::LoginDisabled => false,
+        // Changes the whole connection
+        Capability::Compress { .. } => false,
+        // Everything else (e.g., extensions whose imap-codec feature isn't enabled)
+        _ => false,
+    }
+}
+
+// Parse a capability as announced by a server.
+fn parse_capability(capability: &str) -> Result<Capability<'static>, CapabilityError> {
+    let invalid = || CapabilityError::Invalid(capability.to_owned());
+
+    let line = format!("* CAPABILITY {capability}\r\n");
+    let (_, response) = ResponseCodec::default()
+        .decode(line.as_bytes())
+        .map_err(|_| invalid())?;
+
+    match response {
+        Response::Data(Data::Capability(capabilities)) if capabilities.as_ref().len() == 1 => {
+            Ok(capabilities.as_ref()[0].to_static())
+        }
+        _ => Err(invalid()),
+    }
+}
+
+// Capabilities are compared case-insensitively.
+fn contains(capabilities: &[Capability], capability: &Capability) -> bool {
+    let capability = capability.to_string();
+
+    capabilities
+        .iter()
+        .any(|candidate| candidate.to_string().eq_ignore_ascii_case(&capability))
 }
 
 fn is_auth_mechanism_proxyable(auth_mechanism: &AuthMechanism) -> bool {
@@ -137,6 +314,20 @@ pub enum IdentityError {
     },
     #[error("Expected 1 key in \"{path}\", found {found}")]
     UnexpectedKeyCount { path: String, found: usize },
+    #[error("Error processing PKCS#12 bundle \"{path}\"")]
+    Pkcs12 {
+        #[source]
+        source: p12_keystore::error::Error,
+        path: String,
+    },
+    #[error("Expected key and certificate chain in PKCS#12 bundle \"{path}\"")]
+    MissingPkcs12KeyChain { path: String },
+    #[error("Error reading secret from environment variable \"{name}\"")]
+    Env {
+        #[source]
+        source: std::env::VarError,
+        name: String,
+    },
 }
 
 pub fn load_certificate_chain_pem<'a, P: AsRef<Path>>(
@@ -161,6 +352,22 @@ pub fn load_certificate_chain_pem<'a, P: AsRef<Path>>(
 }
 
 pub fn load_leaf_key_pem<'a, P: AsRef<Path>>(path: P) -> Result<PrivateKeyDer<'a>, IdentityError> {
+    let (_, key) = load_pem(path)?;
+
+    Ok(key)
+}
+
+/// Load certificate chain and leaf key from a single PEM file.
+pub fn load_combined_pem<'a, P: AsRef<Path>>(
+    path: P,
+) -> Result<(Vec<CertificateDer<'a>>, PrivateKeyDer<'a>), IdentityError> {
+    load_pem(path)
+}
+
+// Load all certificates and exactly one key (PKCS#1, PKCS#8, or SEC1) from a PEM file.
+fn load_pem<'a, P: AsRef<Path>>(
+    path: P,
+) -> Result<(Vec<CertificateDer<'a>>, PrivateKeyDer<'a>), IdentityError> {
     let display_path = path.as_ref().display().to_string();
 
     let mut reader = BufReader::new(File::open(&path).map_err(|source| IdentityError::Io {
@@ -168,23 +375,237 @@ pub fn load_leaf_key_pem<'a, P: AsRef<Path>>(path: P) -> Result<PrivateKeyDer<'a
         path: display_path.clone(),
     })?);
 
+    let mut certificates = vec![];
     let mut keys = vec![];
 
-    for key in rustls_pemfile::pkcs8_private_keys(&mut reader) {
-        let key = key.map_err(|source| IdentityError::Io {
+    for item in rustls_pemfile::read_all(&mut reader) {
+        let item = item.map_err(|source| IdentityError::Io {
             source,
             path: display_path.clone(),
         })?;
-        keys.push(PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
-            key.secret_pkcs8_der().to_vec(),
-        )));
+
+        match item {
+            Item::X509Certificate(certificate) => {
+                certificates.push(CertificateDer::from(certificate.to_vec()))
+            }
+            Item::Pkcs1Key(key) => keys.push(PrivateKeyDer::Pkcs1(PrivatePkcs1KeyDer::from(
+                key.secret_pkcs1_der().to_vec(),
+            ))),
+            Item::Pkcs8Key(key) => keys.push(PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+                key.secret_pkcs8_der().to_vec(),
+            ))),
+            Item::Sec1Key(key) => keys.push(PrivateKeyDer::Sec1(PrivateSec1KeyDer::from(
+                key.secret_sec1_der().to_vec(),
+            ))),
+            _ => {}
+        }
     }
 
     match keys.len() {
-        1 => Ok(keys.remove(0)),
+        1 => Ok((certificates, keys.remove(0))),
         found => Err(IdentityError::UnexpectedKeyCount {
             path: display_path,
             found,
         }),
     }
 }
+
+/// Load certificate chain and leaf key from a PKCS#12 (PFX) bundle.
+pub fn load_pkcs12<'a, P: AsRef<Path>>(
+    path: P,
+    password: &str,
+) -> Result<(Vec<CertificateDer<'a>>, PrivateKeyDer<'a>), IdentityError> {
+    let display_path = path.as_ref().display().to_string();
+
+    let data = std::fs::read(&path).map_err(|source| IdentityError::Io {
+        source,
+        path: display_path.clone(),
+    })?;
+
+    let keystore =
+        KeyStore::from_pkcs12(&data, password).map_err(|source| IdentityError::Pkcs12 {
+            source,
+            path: display_path.clone(),
+        })?;
+
+    let Some((_, key_chain)) = keystore.private_key_chain() else {
+        return Err(IdentityError::MissingPkcs12KeyChain { path: display_path });
+    };
+
+    let certificates = key_chain
+        .chain()
+        .iter()
+        .map(|certificate| CertificateDer::from(certificate.as_der().to_vec()))
+        .collect();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_chain.key().to_vec()));
+
+    Ok((certificates, key))
+}
+
+/// Read a secret, e.g., a password.
+pub fn load_secret(secret: &Secret) -> Result<String, IdentityError> {
+    match secret {
+        Secret::File { path } => {
+            let secret = std::fs::read_to_string(path).map_err(|source| IdentityError::Io {
+                source,
+                path: path.clone(),
+            })?;
+
+            Ok(secret.trim_end_matches(['\r', '\n']).to_owned())
+        }
+        Secret::Env { name } => std::env::var(name).map_err(|source| IdentityError::Env {
+            source,
+            name: name.clone(),
+        }),
+    }
+}
+
+/// Replace the secret in a file (keeping its permissions), e.g., a rotated refresh token.
+pub async fn save_secret(path: &str, secret: &str) -> Result<(), IdentityError> {
+    let io = |source| IdentityError::Io {
+        source,
+        path: path.to_owned(),
+    };
+
+    let mode = tokio::fs::metadata(path)
+        .await
+        .map_err(io)?
+        .permissions()
+        .mode()
+        & 0o7777;
+    write_atomically(path, format!("{secret}\n").as_bytes(), mode)
+        .await
+        .map_err(io)
+}
+
+/// Replace a file, so a crash never leaves it truncated.
+///
+/// The temporary file is created with `mode` (it's never readable by others in between) and has
+/// a unique name (concurrent writers never write into the same temporary file).
+pub async fn write_atomically(path: &str, contents: &[u8], mode: u32) -> std::io::Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let temporary = format!(
+        "{path}.{}.{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+
+    let result = async {
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&temporary)
+            .await?;
+        // The umask may have removed permissions
+        file.set_permissions(std::fs::Permissions::from_mode(mode))
+            .await?;
+        file.write_all(contents).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&temporary, path).await
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temporary).await;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(capabilities: &[&str]) -> Vec1<Capability<'static>> {
+        let capabilities: Vec<_> = capabilities
+            .iter()
+            .map(|capability| parse_capability(capability).unwrap())
+            .collect();
+
+        Vec1::try_from(capabilities).unwrap()
+    }
+
+    #[test]
+    fn test_capability_filter() {
+        let server = capabilities(&["IMAP4rev1", "UIDPLUS", "children", "MOVE", "AUTH=PLAIN"]);
+
+        let filter = CapabilityFilter::default();
+        assert_eq!(
+            capabilities(&["IMAP4rev1", "MOVE", "AUTH=PLAIN"]),
+            filter.filter(server.clone())
+        );
+
+        let config = config::Capabilities {
+            allow: vec!["UIDPLUS".into(), "CHILDREN".into()],
+            deny: vec!["MOVE".into()],
+            inject: vec!["ID".into(), "AUTH=PLAIN".into()],
+        };
+        let filter = CapabilityFilter::new(&config).unwrap();
+        assert_eq!(
+            capabilities(&["IMAP4rev1", "UIDPLUS", "children", "AUTH=PLAIN", "ID"]),
+            filter.filter(server)
+        );
+    }
+
+    #[test]
+    fn test_capability_filter_validation() {
+        let tests = [
+            ("NAMESPACE", true),
+            ("SPECIAL-USE", true),
+            ("AUTH=XOAUTH2", true),
+            ("BINARY", true),
+            ("CONDSTORE", false),
+            ("UTF8=ACCEPT", false),
+            ("XUNKNOWN", false),
+            ("COMPRESS=DEFLATE", false),
+            ("STARTTLS", false),
+            ("LIST-STATUS", false),
+            ("not a capability", false),
+        ];
+
+        for (capability, valid) in tests {
+            let config = config::Capabilities {
+                allow: vec![capability.into()],
+                ..Default::default()
+            };
+            assert_eq!(
+                valid,
+                CapabilityFilter::new(&config).is_ok(),
+                "{capability}"
+            );
+        }
+
+        // Unsupported capabilities can be denied.
+        let config = config::Capabilities {
+            deny: vec!["CONDSTORE".into()],
+            ..Default::default()
+        };
+        assert!(CapabilityFilter::new(&config).is_ok());
+    }
+
+    #[test]
+    fn test_capability_filter_config() {
+        let file = r#"
+            name = "Unsupported capability"
+
+            [bind]
+            encryption = "Insecure"
+            host = "127.0.0.1"
+
+            [connect]
+            encryption = "Tls"
+            host = "imap.example.org"
+
+            [capabilities]
+            inject = ["COMPRESS=DEFLATE"]
+        "#;
+
+        let service: config::Service = toml::from_str(file).unwrap();
+        assert!(matches!(
+            CapabilityFilter::new(&service.capabilities),
+            Err(CapabilityError::Unsupported(capability)) if capability == "COMPRESS=DEFLATE"
+        ));
+    }
+}
@@ -0,0 +1,416 @@
+//! Connections through SOCKS5 (RFC 1928) and HTTP CONNECT proxies.
+
+use std::{
+    fmt::{Display, Formatter},
+    net::{IpAddr, SocketAddr},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{lookup_host, TcpStream},
+};
+
+use crate::{
+    config::Via,
+    util::{self, IdentityError},
+};
+
+/// Maximum length of the HTTP proxy's response header.
+const HTTP_MAX_RESPONSE_LENGTH: usize = 8192;
+
+#[derive(Debug, Error)]
+pub enum ViaError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Failed to resolve \"{0}\"")]
+    Resolve(String),
+    #[error("Name \"{0}\" is too long for SOCKS5")]
+    NameTooLong(String),
+    #[error("Unexpected SOCKS5 reply")]
+    Socks5Protocol,
+    #[error("SOCKS5 proxy requires an unsupported authentication method")]
+    Socks5NoAcceptableMethod,
+    #[error("SOCKS5 proxy rejected username or password")]
+    Socks5AuthenticationFailed,
+    #[error("SOCKS5 proxy failed to connect: {0}")]
+    Socks5ConnectFailed(&'static str),
+    #[error("Unexpected HTTP proxy response")]
+    HttpProtocol,
+    #[error("HTTP proxy failed to connect: \"{0}\"")]
+    HttpConnectFailed(String),
+}
+
+/// Proxy that server connections are established through.
+#[derive(Clone)]
+pub struct Hop {
+    via: Via,
+    credentials: Option<(String, String)>,
+}
+
+impl Hop {
+    pub fn new(via: &Via) -> Result<Self, IdentityError> {
+        let (Via::Socks5 {
+            username, password, ..
+        }
+        | Via::HttpConnect {
+            username, password, ..
+        }) = via;
+
+        let credentials = match username {
+            Some(username) => {
+                let password = match password {
+                    Some(password) => util::load_secret(password)?,
+                    None => String::new(),
+                };
+
+                Some((username.clone(), password))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            via: via.clone(),
+            credentials,
+        })
+    }
+
+    /// Connect to `host:port` through the proxy.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, ViaError> {
+        match &self.via {
+            Via::Socks5 {
+                host: via_host,
+                port: via_port,
+                remote_dns,
+                ..
+            } => {
+                let mut stream = TcpStream::connect((via_host.as_str(), *via_port)).await?;
+                self.socks5(&mut stream, host, port, *remote_dns).await?;
+
+                Ok(stream)
+            }
+            Via::HttpConnect {
+                host: via_host,
+                port: via_port,
+                ..
+            } => {
+                let mut stream = TcpStream::connect((via_host.as_str(), *via_port)).await?;
+                self.http_connect(&mut stream, host, port).await?;
+
+                Ok(stream)
+            }
+        }
+    }
+
+    async fn socks5<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        host: &str,
+        port: u16,
+        remote_dns: bool,
+    ) -> Result<(), ViaError> {
+        // Offer "no authentication" and, with credentials, "username/password".
+        let methods: &[u8] = match self.credentials {
+            Some(_) => &[0x00, 0x02],
+            None => &[0x00],
+        };
+        let mut greeting = vec![0x05, methods.len() as u8];
+        greeting.extend_from_slice(methods);
+        stream.write_all(&greeting).await?;
+
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+        match (reply, &self.credentials) {
+            ([0x05, 0x00], _) => {}
+            ([0x05, 0x02], Some((username, password))) => {
+                // See RFC 1929.
+                let (Ok(username_length), Ok(password_length)) =
+                    (u8::try_from(username.len()), u8::try_from(password.len()))
+                else {
+                    return Err(ViaError::Socks5AuthenticationFailed);
+                };
+
+                let mut request = vec![0x01, username_length];
+                request.extend_from_slice(username.as_bytes());
+                request.push(password_length);
+                request.extend_from_slice(password.as_bytes());
+                stream.write_all(&request).await?;
+
+                let mut reply = [0; 2];
+                stream.read_exact(&mut reply).await?;
+                if reply[1] != 0x00 {
+                    return Err(ViaError::Socks5AuthenticationFailed);
+                }
+            }
+            ([0x05, 0xff], _) => return Err(ViaError::Socks5NoAcceptableMethod),
+            _ => return Err(ViaError::Socks5Protocol),
+        }
+
+        // CONNECT
+        let mut request = vec![0x05, 0x01, 0x00];
+        match host.parse::<IpAddr>() {
+            Ok(ip) => encode_ip(&mut request, ip),
+            Err(_) if remote_dns => {
+                let length =
+                    u8::try_from(host.len()).map_err(|_| ViaError::NameTooLong(host.to_owned()))?;
+                request.push(0x03);
+                request.push(length);
+                request.extend_from_slice(host.as_bytes());
+            }
+            Err(_) => {
+                let addr = lookup_host((host, port))
+                    .await?
+                    .next()
+                    .ok_or_else(|| ViaError::Resolve(host.to_owned()))?;
+                encode_ip(&mut request, addr.ip());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != 0x05 {
+            return Err(ViaError::Socks5Protocol);
+        }
+        if reply[1] != 0x00 {
+            return Err(ViaError::Socks5ConnectFailed(socks5_reply_text(reply[1])));
+        }
+
+        // Skip the bound address (and port).
+        let length = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => usize::from(stream.read_u8().await?),
+            _ => return Err(ViaError::Socks5Protocol),
+        };
+        let mut bound = vec![0; length + 2];
+        stream.read_exact(&mut bound).await?;
+
+        Ok(())
+    }
+
+    async fn http_connect<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        host: &str,
+        port: u16,
+    ) -> Result<(), ViaError> {
+        let authority = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => SocketAddr::from((ip, port)).to_string(),
+            _ => format!("{host}:{port}"),
+        };
+
+        let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+        if let Some((username, password)) = &self.credentials {
+            let token = BASE64.encode(format!("{username}:{password}"));
+            request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read the response header, but nothing beyond it.
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() == HTTP_MAX_RESPONSE_LENGTH {
+                return Err(ViaError::HttpProtocol);
+            }
+            response.push(stream.read_u8().await?);
+        }
+
+        let response = String::from_utf8_lossy(&response);
+        // Unwrap: There is at least one line.
+        let status_line = response.lines().next().unwrap();
+        let mut fields = status_line.splitn(3, ' ');
+        match (fields.next(), fields.next()) {
+            (Some(version), Some(code)) if version.starts_with("HTTP/1.") => {
+                if code.starts_with('2') {
+                    Ok(())
+                } else {
+                    Err(ViaError::HttpConnectFailed(status_line.to_owned()))
+                }
+            }
+            _ => Err(ViaError::HttpProtocol),
+        }
+    }
+}
+
+impl Display for Hop {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        self.via.fmt(f)
+    }
+}
+
+fn encode_ip(request: &mut Vec<u8>, ip: IpAddr) {
+    match ip {
+        IpAddr::V4(ip) => {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+        }
+    }
+}
+
+fn socks5_reply_text(reply: u8) -> &'static str {
+    match reply {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    fn socks5(credentials: Option<(&str, &str)>) -> Hop {
+        Hop {
+            via: Via::Socks5 {
+                host: "127.0.0.1".into(),
+                port: 9050,
+                username: None,
+                password: None,
+                remote_dns: true,
+            },
+            credentials: credentials.map(|(u, p)| (u.into(), p.into())),
+        }
+    }
+
+    fn http_connect(credentials: Option<(&str, &str)>) -> Hop {
+        Hop {
+            via: Via::HttpConnect {
+                host: "127.0.0.1".into(),
+                port: 3128,
+                username: None,
+                password: None,
+            },
+            credentials: credentials.map(|(u, p)| (u.into(), p.into())),
+        }
+    }
+
+    /// Stand-in proxy that expects each request and sends the reply.
+    fn stand_in(steps: Vec<(&'static [u8], &'static [u8])>) -> DuplexStream {
+        let (client, mut server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            for (request, reply) in steps {
+                let mut got = vec![0; request.len()];
+                server.read_exact(&mut got).await.unwrap();
+                assert_eq!(
+                    String::from_utf8_lossy(request),
+                    String::from_utf8_lossy(&got)
+                );
+                server.write_all(reply).await.unwrap();
+            }
+            // Anything beyond the handshake belongs to the server.
+            server.write_all(b"* OK").await.unwrap();
+        });
+
+        client
+    }
+
+    #[tokio::test]
+    async fn test_socks5() {
+        let mut stream = stand_in(vec![
+            (b"\x05\x01\x00", b"\x05\x00"),
+            (
+                b"\x05\x01\x00\x03\x0bexample.org\x00\x8f",
+                b"\x05\x00\x00\x03\x04host\x00\x00",
+            ),
+        ]);
+        let hop = socks5(None);
+        hop.socks5(&mut stream, "example.org", 143, true)
+            .await
+            .unwrap();
+        let mut rest = [0; 4];
+        stream.read_exact(&mut rest).await.unwrap();
+        assert_eq!(b"* OK", &rest);
+
+        let mut stream = stand_in(vec![
+            (b"\x05\x02\x00\x02", b"\x05\x02"),
+            (b"\x01\x05alice\x06secret", b"\x01\x00"),
+            (
+                b"\x05\x01\x00\x04\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\x03\xe1",
+                b"\x05\x00\x00\x01\0\0\0\0\0\0",
+            ),
+        ]);
+        let hop = socks5(Some(("alice", "secret")));
+        hop.socks5(&mut stream, "::1", 993, false).await.unwrap();
+
+        let mut stream = stand_in(vec![
+            (b"\x05\x02\x00\x02", b"\x05\x02"),
+            (b"\x01\x05alice\x05wrong", b"\x01\x01"),
+        ]);
+        let hop = socks5(Some(("alice", "wrong")));
+        let error = hop.socks5(&mut stream, "example.org", 143, true).await;
+        assert!(matches!(error, Err(ViaError::Socks5AuthenticationFailed)));
+
+        let mut stream = stand_in(vec![(b"\x05\x01\x00", b"\x05\xff")]);
+        let error = socks5(None)
+            .socks5(&mut stream, "example.org", 143, true)
+            .await;
+        assert!(matches!(error, Err(ViaError::Socks5NoAcceptableMethod)));
+
+        let mut stream = stand_in(vec![
+            (b"\x05\x01\x00", b"\x05\x00"),
+            (
+                b"\x05\x01\x00\x01\x7f\0\0\x01\x00\x8f",
+                b"\x05\x05\x00\x01\0\0\0\0\0\0",
+            ),
+        ]);
+        let error = socks5(None)
+            .socks5(&mut stream, "127.0.0.1", 143, true)
+            .await;
+        assert!(
+            matches!(error, Err(ViaError::Socks5ConnectFailed(text)) if text == "connection refused")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let mut stream = stand_in(vec![(
+            b"CONNECT example.org:143 HTTP/1.1\r\nHost: example.org:143\r\n\r\n",
+            b"HTTP/1.1 200 Connection established\r\n\r\n",
+        )]);
+        let hop = http_connect(None);
+        hop.http_connect(&mut stream, "example.org", 143)
+            .await
+            .unwrap();
+        let mut rest = [0; 4];
+        stream.read_exact(&mut rest).await.unwrap();
+        assert_eq!(b"* OK", &rest);
+
+        let mut stream = stand_in(vec![(
+            b"CONNECT [::1]:993 HTTP/1.1\r\nHost: [::1]:993\r\n\
+              Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n",
+            b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n",
+        )]);
+        let hop = http_connect(Some(("alice", "secret")));
+        let error = hop.http_connect(&mut stream, "::1", 993).await;
+        assert!(matches!(
+            error,
+            Err(ViaError::HttpConnectFailed(line))
+                if line == "HTTP/1.1 407 Proxy Authentication Required"
+        ));
+
+        let mut stream = stand_in(vec![(
+            b"CONNECT example.org:143 HTTP/1.1\r\nHost: example.org:143\r\n\r\n",
+            b"SSH-2.0-OpenSSH\r\n\r\n",
+        )]);
+        let error = http_connect(None)
+            .http_connect(&mut stream, "example.org", 143)
+            .await;
+        assert!(matches!(error, Err(ViaError::HttpProtocol)));
+    }
+}
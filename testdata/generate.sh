@@ -0,0 +1,34 @@
+#!/bin/sh
+# Regenerate the certificates used by the tests (valid for 100 years).
+set -eu
+cd "$(dirname "$0")"
+
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes -days 36500 \
+    -subj "/CN=imap-proxy test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" \
+    -addext "keyUsage=critical,keyCertSign" \
+    -keyout "$tmp/ca-key.pem" -out ca.pem
+
+leaf() {
+    name=$1
+    subject=$2
+    extensions=$3
+
+    openssl req -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+        -subj "$subject" -keyout "$name-key.pem" -out "$tmp/$name.csr"
+    printf '%s\n' "$extensions" > "$tmp/$name.ext"
+    openssl x509 -req -in "$tmp/$name.csr" -CA ca.pem -CAkey "$tmp/ca-key.pem" \
+        -CAcreateserial -CAserial "$tmp/ca.srl" -days 36500 -extfile "$tmp/$name.ext" \
+        -out "$name.pem"
+}
+
+server="basicConstraints=CA:FALSE
+extendedKeyUsage=serverAuth
+subjectAltName=DNS:localhost"
+leaf localhost-1 "/CN=localhost" "$server"
+leaf localhost-2 "/CN=localhost" "$server"
+leaf alice "/CN=alice" "basicConstraints=CA:FALSE
+extendedKeyUsage=clientAuth"